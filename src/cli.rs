@@ -1,35 +1,460 @@
-use std::{net::{Ipv4Addr, Ipv6Addr}, path::PathBuf, str::FromStr};
+use std::{net::{IpAddr, SocketAddr}, path::PathBuf, str::FromStr, time::Duration};
 
-use clap::Parser;
+use chrono::{DateTime, Local, NaiveDate, NaiveTime, TimeZone};
+use clap::{builder::RangedU64ValueParser, error::ErrorKind, ArgAction, Command, CommandFactory, Parser, ValueEnum};
+use network_monitor::{
+    export::Collector,
+    hook::{Hook, Scope},
+    probe::{HttpCheck, Marking, DEFAULT_PAYLOAD_SIZE, DEFAULT_PING_TIMEOUT, DEFAULT_TIMEOUT},
+    report::Period,
+    webhook::{Template, Webhook},
+    DnsProtocol,
+    Family,
+    Nameservers,
+    Probe
+};
 use once_cell::sync::Lazy;
-use trust_dns_resolver::config::{LookupIpStrategy, ResolverConfig, ResolverOpts};
 
-pub static ARGS: Lazy<Args> = Lazy::new(Args::parse);
+pub static ARGS: Lazy<Args> = Lazy::new(Args::load);
 
 #[derive(Debug, Clone, Parser)]
-#[command(version, author)]
+#[command(version, author, args_override_self = true)]
 pub struct Args {
+    /// file to read options from, with one "option = value" per line using the long names of
+    /// the options, like "interval = 10", and "target = HOSTNAME" for each hostname
+    ///
+    /// options given on the command line take precedence, lines starting with "#" are ignored
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
     /// interval between ping attempts in seconds
-    #[arg(short, long, default_value="15")]
+    #[arg(short, long, default_value="15", value_parser=clap::value_parser!(u64).range(1..))]
     pub interval: u64,
 
     /// output directory for logs
-    #[arg(short = 'o', long, value_parser=parse_log_file_dir)]
+    #[arg(short = 'o', long, global = true, value_parser=parse_log_file_dir)]
     pub out_dir: Option<PathBuf>,
 
+    /// format of the logs, both on stderr and in --out-dir
+    #[arg(long, value_enum, default_value="text")]
+    pub log_format: LogFormatArg,
+
+    /// longest time between re-resolving the hostnames in seconds, they're also re-resolved once
+    /// their DNS records expire
+    ///
+    /// only the families a hostname had addresses in at startup are monitored, so one that gains
+    /// an A or AAAA record later is only monitored over that family after a restart
+    ///
+    /// 0 disables re-resolution, pinging the addresses found at startup forever
+    #[arg(long, default_value="300")]
+    pub resolve_interval: u64,
+
+    /// DNS resolver used for looking up the hostnames
+    #[arg(long, value_enum, default_value="cloudflare")]
+    pub resolver: ResolverArg,
+
+    /// nameserver used for looking up the hostnames instead of --resolver, can be given multiple
+    /// times
+    ///
+    /// must be an IP address, optionally with a port, like "192.168.0.1" or "[::1]:5353"
+    #[arg(long = "nameserver", value_name = "ADDRESS", value_parser=parse_nameserver)]
+    pub nameservers: Vec<(IpAddr, Option<u16>)>,
+
+    /// protocol used for talking to the nameservers
+    #[arg(long, value_enum, default_value="plain")]
+    pub dns_protocol: DnsProtocolArg,
+
+    /// name the nameservers' certificates are checked against, required for using --nameserver
+    /// with encrypted DNS
+    #[arg(long, value_name = "NAME")]
+    pub dns_tls_name: Option<String>,
+
+    /// name resolved every interval to check that DNS itself works, can be given multiple times
+    ///
+    /// lookups go through the nameservers chosen with --resolver or --nameserver without any
+    /// caching, and DNS is considered down once none of the names resolve
+    #[arg(long = "dns-probe", value_name = "NAME", value_parser=clap::builder::NonEmptyStringValueParser::new())]
+    pub dns_probes: Vec<String>,
+
+    /// how often round trip times are summarized in seconds, logging their minimum, average,
+    /// maximum, median, 95th and 99th percentiles and standard deviation per hostname
+    ///
+    /// 0 disables the summaries
+    #[arg(long, default_value="300")]
+    pub stats_window: u64,
+
+    /// how many echo requests are sent to each hostname every interval, measuring packet loss,
+    /// jitter and reordering when more than 1
+    #[arg(long, default_value="1", value_parser=clap::value_parser!(u16).range(1..))]
+    pub burst: u16,
+
+    /// time between the echo requests of a burst in milliseconds
+    #[arg(long, value_name = "MILLISECONDS", default_value="20")]
+    pub burst_gap: u64,
+
+    /// packet loss percentage over the latest probes above which a hostname is considered
+    /// degraded, best used with --burst
+    #[arg(long, value_name = "PERCENT", value_parser=parse_loss)]
+    pub degraded_loss: Option<f64>,
+
+    /// 95th percentile round trip time over the latest probes in milliseconds above which a
+    /// hostname is considered degraded
+    #[arg(long, value_name = "MILLISECONDS")]
+    pub degraded_p95: Option<u64>,
+
+    /// how many of the latest probes are used for --degraded-loss and --degraded-p95
+    #[arg(long, value_name = "PROBES", default_value="20", value_parser=RangedU64ValueParser::<usize>::new().range(1..))]
+    pub degraded_window: usize,
+
+    /// how many probes in a row must exceed a threshold for a hostname to become degraded
+    #[arg(long, default_value="3", value_parser=clap::value_parser!(u32).range(1..))]
+    pub degraded_enter: u32,
+
+    /// how many probes in a row must be within the thresholds for a hostname to stop being
+    /// degraded
+    #[arg(long, default_value="3", value_parser=clap::value_parser!(u32).range(1..))]
+    pub degraded_leave: u32,
+
+    /// how often the path MTU to each hostname is measured in seconds, by sending don't-fragment
+    /// echo requests of every --pmtu-sizes, logging when it shrinks or when large packets start
+    /// being dropped while smaller ones still get through
+    ///
+    /// 0 disables the measurements, only supported on Linux
+    #[arg(long, value_name = "SECONDS", default_value="0")]
+    pub pmtu_interval: u64,
+
+    /// sizes of the whole IP packets tried when measuring the path MTU, separated by commas
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_delimiter = ',',
+        default_value = "1280,1400,1420,1460,1480,1492,1500",
+        value_parser = RangedU64ValueParser::<usize>::new().range(68..=65535)
+    )]
+    pub pmtu_sizes: Vec<usize>,
+
+    /// trace the route to hostnames when they go down and again once they come back, logging
+    /// every hop along the way and the last one that responded, needs root or CAP_NET_RAW
+    #[arg(long)]
+    pub traceroute: bool,
+
+    /// ping the default gateway and the first hop of the ISP along with the hostnames, telling
+    /// whether outages are in the local network, at the gateway, the ISP or the hostnames
+    /// themselves, only supported on Linux
+    #[arg(long)]
+    pub localize: bool,
+
+    /// address to serve Prometheus metrics on, like "0.0.0.0:9870", with probe results, round
+    /// trip time histograms, whether each hostname, family, the network and DNS are up, and
+    /// their outage counts and downtime at "/metrics"
+    #[arg(long, value_name = "ADDRESS")]
+    pub metrics_listen: Option<SocketAddr>,
+
+    /// where to push probe results, burst statistics and outages to, can be given multiple
+    /// times: "http(s)://" URLs of InfluxDB's write API, with "#token=TOKEN" for 2.x,
+    /// "udp://host:port" for InfluxDB's UDP listener or "graphite://host:port" for Graphite's
+    /// plaintext protocol, samples are kept and sent later while the collector can't be reached
+    #[arg(long = "export", value_name = "URL", value_parser=parse_collector)]
+    pub export: Vec<Collector>,
+
+    /// webhook to post a notification to on each outage, recovery and degradation, can be given
+    /// multiple times: "#format=FORMAT" picks the body for services that expect a specific one,
+    /// "json" by default posts every field of the event, "slack", "discord" and "teams" post the
+    /// message for their incoming webhooks and "ntfy" posts it as plain text, or
+    /// "#template=PATH" reads the body from a file, replacing "{{event}}", "{{message}}",
+    /// "{{time}}", "{{timestamp}}", "{{family}}", "{{target}}", "{{cause}}", "{{downtime}}",
+    /// "{{downtime_seconds}}", "{{reason}}" and "{{json}}" with the event's values,
+    /// notifications are kept and sent later while the webhook can't be reached
+    #[arg(long = "webhook", value_name = "URL", value_parser=parse_webhook)]
+    pub webhooks: Vec<Webhook>,
+
+    /// command to run once an outage has lasted for --hook-min-downtime, as "SCOPE=COMMAND"
+    /// where SCOPE is "network", "ipv4", "ipv6" or a hostname, like
+    /// "ipv6=/usr/local/bin/restart-modem", can be given multiple times, hostnames being down from
+    /// when they go down over either family until they're back over both
+    ///
+    /// commands are run with "sh -c" and get the outage in NETWORK_MONITOR_EVENT ("down" or
+    /// "up"), NETWORK_MONITOR_SCOPE, NETWORK_MONITOR_FAMILY, NETWORK_MONITOR_TARGET,
+    /// NETWORK_MONITOR_STARTED_AT, NETWORK_MONITOR_DURATION in seconds and
+    /// NETWORK_MONITOR_CAUSE, the ones that don't apply are left unset
+    #[arg(long = "on-down", value_name = "SCOPE=COMMAND", value_parser=parse_hook)]
+    pub on_down: Vec<Hook>,
+
+    /// command to run once an outage that lasted for --hook-min-downtime ends, the same way as
+    /// --on-down
+    #[arg(long = "on-up", value_name = "SCOPE=COMMAND", value_parser=parse_hook)]
+    pub on_up: Vec<Hook>,
+
+    /// how long --on-down and --on-up commands may run for in seconds before they're killed
+    #[arg(long, value_name = "SECONDS", default_value="60", value_parser=clap::value_parser!(u64).range(1..))]
+    pub hook_timeout: u64,
+
+    /// how long outages must last in seconds for --on-down and --on-up commands to be run
+    #[arg(long, value_name = "SECONDS", default_value="0")]
+    pub hook_min_downtime: u64,
+
+    /// least time between two runs of the same --on-down or --on-up command in seconds, so
+    /// restarting something doesn't end up in a loop
+    #[arg(long, value_name = "SECONDS", default_value="0")]
+    pub hook_cooldown: u64,
+
     /// how many errors in a row must occur for a network outage to be logged
-    #[arg(long, default_value="2")]
+    #[arg(long, default_value="2", value_parser=clap::value_parser!(u32).range(1..))]
     pub hysteresis: u32,
 
     /// verbosity
     #[arg(short, action = clap::ArgAction::Count)]
     pub verbosity: u8,
 
-    /// hostname used for pinging
+    /// only monitor IPv4
+    #[arg(short = '4', long, conflicts_with = "ipv6_only")]
+    pub ipv4_only: bool,
+
+    /// only monitor IPv6
+    #[arg(short = '6', long)]
+    pub ipv6_only: bool,
+
+    /// how many hostnames must be unreachable over IPv4 or IPv6 for it to be considered down
+    ///
+    /// outages of fewer hostnames are logged as issues with the hostnames themselves, defaults
+    /// to all of them
+    #[arg(short, long, value_parser=RangedU64ValueParser::<usize>::new().range(1..))]
+    pub quorum: Option<usize>,
+
+    /// hostnames used for pinging
+    ///
+    /// must be either a valid URL, like "https://youtube.com", a domain name, like "youtube.com"
+    /// or an IP address, like "1.1.1.1"
+    ///
+    /// "tcp://" URLs, like "tcp://example.com:443", are monitored with TCP handshakes to the given
+    /// port instead, and "http://" or "https://" URLs with GET requests, which must succeed with
+    /// a status below 400, so they're no longer pinged, give the bare domain name to ping it
+    ///
+    /// options go after a "#", like "https://example.com/health#status=200&contains=ok" or
+    /// "1.1.1.1#size=1400&ttl=64":
+    ///
+    /// - "timeout" in seconds, 2 for pings and 5 for everything else by default
+    ///
+    /// - "ttl" and "dscp" to set the TTL or hop limit and the DSCP marking of the probes
+    ///
+    /// - "size" for the payload size of pings in bytes, 256 by default
+    ///
+    /// - "status" and "contains" for the exact status and a text the body must contain for
+    ///   HTTP targets
+    #[arg(default_value="google.com", value_name="HOSTNAME", value_parser=parse_target)]
+    pub hostnames: Vec<TargetArg>,
+
+    #[command(subcommand)]
+    pub command: Option<CommandArg>
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+pub enum CommandArg {
+    /// print the uptime, outages and latency of each family saved in the history of --out-dir
+    /// instead of monitoring
+    ///
+    /// time the monitor wasn't running for counts as uptime
+    Report(ReportArgs),
+    /// save the outages logged by versions from before the history to the history of
+    /// --out-dir instead of monitoring, including the compressed logs kept after rotating
     ///
-    /// must be either a valid URL, like "https://youtube.com" or a domain name, like "youtube.com"
-    #[arg(default_value="google.com", value_parser=parse_address)]
-    pub hostname: (Ipv4Addr, Ipv6Addr)
+    /// only logs from before the first thing already in the history are imported, so nothing
+    /// is saved twice
+    Import(ImportArgs),
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct ImportArgs {
+    /// log files or directories containing them, defaults to --out-dir
+    #[arg(value_name = "PATH")]
+    pub paths: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct ReportArgs {
+    /// start of the report, like "2024-01-31" for midnight in local time or
+    /// "2024-01-31T12:00:00+01:00", defaults to 30 days before --until
+    #[arg(long, value_name = "TIME", value_parser=parse_time)]
+    pub since: Option<DateTime<Local>>,
+
+    /// end of the report, in the same format as --since, defaults to now
+    #[arg(long, value_name = "TIME", value_parser=parse_time)]
+    pub until: Option<DateTime<Local>>,
+
+    /// split the report into calendar days, weeks starting on monday or months
+    #[arg(long, value_enum)]
+    pub by: Option<PeriodArg>,
+}
+
+impl ReportArgs {
+    /// the time the report covers, with the defaults filled in
+    pub fn range(&self) -> (DateTime<Local>, DateTime<Local>) {
+        let until = self.until.unwrap_or_else(Local::now);
+        (self.since.unwrap_or(until - chrono::Days::new(30)), until)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PeriodArg {
+    Day,
+    Week,
+    Month,
+}
+
+impl From<PeriodArg> for Period {
+    fn from(value: PeriodArg) -> Self {
+        match value {
+            PeriodArg::Day => Period::Day,
+            PeriodArg::Week => Period::Week,
+            PeriodArg::Month => Period::Month,
+        }
+    }
+}
+
+impl Args {
+    /// parses the command line along with the config file, if there is one
+    fn load() -> Self {
+        let args = Args::parse();
+        let Some(path) = &args.config else {
+            return args.validated();
+        };
+
+        let mut command = Args::command();
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => command.error(ErrorKind::Io, format!("could not read {}: {e}", path.display())).exit(),
+        };
+        let (options, mut targets) = match config_args(&contents, &command) {
+            Ok(args) => args,
+            Err(e) => command.error(ErrorKind::InvalidValue, format!("{}: {e}", path.display())).exit(),
+        };
+
+        // later options override earlier ones, so the command line goes last,
+        // and the hostnames aren't needed when running a subcommand
+        if args.command.is_some() {
+            targets.clear();
+        }
+        let mut cli = std::env::args_os();
+        let bin = cli.next().unwrap_or_default();
+        let args = std::iter::once(bin)
+            .chain(options.into_iter().map(Into::into))
+            .chain(cli)
+            .chain(targets.into_iter().map(Into::into));
+        Args::parse_from(args).validated()
+    }
+
+    /// exits if the options don't make sense together
+    fn validated(self) -> Self {
+        if self.command.is_some() && self.out_dir.is_none() {
+            Args::command().error(ErrorKind::MissingRequiredArgument, "--out-dir the history is saved in is required").exit();
+        }
+        if let Some(CommandArg::Report(report)) = &self.command {
+            let (since, until) = report.range();
+            if since >= until {
+                Args::command().error(ErrorKind::ValueValidation, "--since must be before --until").exit();
+            }
+        }
+        self
+    }
+}
+
+/// turns the lines of a config file into command line options and hostnames
+fn config_args(contents: &str, command: &Command) -> Result<(Vec<String>, Vec<String>), String> {
+    let mut options = Vec::new();
+    let mut targets = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line_no = i + 1;
+        let (key, value) = line.split_once('=')
+            .ok_or_else(|| format!("line {line_no} should look like \"option = value\""))?;
+        let (key, value) = (key.trim(), value.trim());
+        let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+
+        if key == "target" {
+            targets.push(value.to_string());
+            continue;
+        }
+        let arg = command.get_arguments()
+            .filter(|arg| !arg.is_positional() && arg.get_id() != "config")
+            .find(|arg| arg.get_long() == Some(key) || arg.get_id() == key.replace('-', "_").as_str())
+            .ok_or_else(|| format!("unknown option \"{key}\" on line {line_no}"))?;
+        let flag = match (arg.get_long(), arg.get_short()) {
+            (Some(long), _) => format!("--{long}"),
+            (None, Some(short)) => format!("-{short}"),
+            (None, None) => unreachable!("options have a name"),
+        };
+
+        match arg.get_action() {
+            ArgAction::SetTrue => match value {
+                "true" => options.push(flag),
+                "false" => {},
+                _ => return Err(format!("\"{key}\" on line {line_no} must be true or false")),
+            },
+            ArgAction::Count => {
+                let count: usize = value.parse()
+                    .map_err(|_| format!("\"{key}\" on line {line_no} must be a number"))?;
+                options.extend(std::iter::repeat_n(flag, count));
+            },
+            _ => {
+                options.push(flag);
+                options.push(value.to_string());
+            },
+        }
+    }
+    Ok((options, targets))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormatArg {
+    /// "[timestamp target LEVEL] message" lines
+    Text,
+    /// JSON Lines, with an object per line holding the RFC 3339 UTC "timestamp", "level",
+    /// "target" and "message", along with the "event" type and its fields, like "host",
+    /// "family", "rtt_ms" or "error", for events
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ResolverArg {
+    /// the system's configuration, like /etc/resolv.conf
+    System,
+    Google,
+    Quad9,
+    Cloudflare,
+}
+
+impl From<ResolverArg> for Nameservers {
+    fn from(value: ResolverArg) -> Self {
+        match value {
+            ResolverArg::System => Nameservers::System,
+            ResolverArg::Google => Nameservers::Google,
+            ResolverArg::Quad9 => Nameservers::Quad9,
+            ResolverArg::Cloudflare => Nameservers::Cloudflare,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DnsProtocolArg {
+    /// plain DNS over UDP and TCP
+    Plain,
+    /// DNS over TLS
+    Tls,
+    /// DNS over HTTPS
+    Https,
+}
+
+impl From<DnsProtocolArg> for DnsProtocol {
+    fn from(value: DnsProtocolArg) -> Self {
+        match value {
+            DnsProtocolArg::Plain => DnsProtocol::Plain,
+            DnsProtocolArg::Tls => DnsProtocol::Tls,
+            DnsProtocolArg::Https => DnsProtocol::Https,
+        }
+    }
 }
 
 /// errors if not a directory or if path doesn't exist
@@ -47,70 +472,413 @@ fn parse_log_file_dir(val: &str) -> Result<PathBuf, &'static str> {
     }
 }
 
-/// looks up the A and AAAA records of the given domain, errors if either aren't present
-fn parse_address(val: &str) -> Result<(Ipv4Addr, Ipv6Addr), &'static str> {
-    let url = url::Url::from_str(val);
+/// a hostname along with how it should be probed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetArg {
+    pub host: String,
+    pub probe: Probe,
+    pub marking: Marking,
+}
+
+/// largest payload that fits in an IPv4 echo request
+const MAX_PAYLOAD_SIZE: usize = 65_507;
+
+/// parses a URL, domain name or IP address, optionally followed by options
+/// after a "#"
+///
+/// "tcp://host:port" URLs are probed with TCP handshakes and "http(s)://" URLs
+/// with GET requests, everything else is pinged
+fn parse_target(val: &str) -> Result<TargetArg, &'static str> {
+    let (host, url, options) = match url::Url::from_str(val) {
+        Ok(mut url) => {
+            let host = match url.host().ok_or("The provided URL does not have a domain")? {
+                url::Host::Domain(d) => d.to_string(),
+                url::Host::Ipv4(v4) => v4.to_string(),
+                url::Host::Ipv6(v6) => v6.to_string(),
+            };
+            let options = url.fragment().unwrap_or_default().to_string();
+            url.set_fragment(None);
+            (host, Some(url), options)
+        },
+        Err(_) => {
+            let (host, options) = val.split_once('#').unwrap_or((val, ""));
+            (host.to_string(), None, options.to_string())
+        },
+    };
+    let scheme = url.as_ref().map(|u| u.scheme()).unwrap_or_default();
+    let is_http = matches!(scheme, "http" | "https");
+    let is_icmp = !is_http && scheme != "tcp";
 
-    let resolver = trust_dns_resolver::Resolver::new(
-        ResolverConfig::cloudflare(),
-        {
-            let mut opts = ResolverOpts::default();
-            opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
-            opts
+    let mut timeout = None;
+    let mut size = DEFAULT_PAYLOAD_SIZE;
+    let mut marking = Marking::default();
+    let mut status = None;
+    let mut body_contains = None;
+    for (key, value) in url::form_urlencoded::parse(options.as_bytes()) {
+        match key.as_ref() {
+            "timeout" => {
+                timeout = Some(
+                    value.parse::<f64>().ok()
+                        .and_then(|t| Duration::try_from_secs_f64(t).ok())
+                        .filter(|t| !t.is_zero())
+                        .ok_or("The timeout must be a positive number of seconds")?
+                );
+            },
+            "ttl" => {
+                marking.ttl = Some(
+                    value.parse::<u32>().ok()
+                        .filter(|t| (1..=255).contains(t))
+                        .ok_or("The TTL must be between 1 and 255")?
+                );
+            },
+            "dscp" => {
+                marking.dscp = Some(
+                    value.parse::<u8>().ok()
+                        .filter(|d| *d < 64)
+                        .ok_or("The DSCP must be between 0 and 63")?
+                );
+            },
+            "size" if is_icmp => {
+                size = value.parse::<usize>().ok()
+                    .filter(|s| *s <= MAX_PAYLOAD_SIZE)
+                    .ok_or("The payload size must be at most 65507 bytes")?;
+            },
+            "status" if is_http => {
+                status = Some(
+                    value.parse::<u16>().ok()
+                        .filter(|s| (100..600).contains(s))
+                        .ok_or("The status must be a valid HTTP status code")?
+                );
+            },
+            "contains" if is_http => body_contains = Some(value.into_owned()),
+            _ => return Err("Unknown target option, see --help for the ones supported by each kind of target"),
         }
-    ).unwrap();
+    }
 
-    if let Ok(url) = url {
-        let hostname = match url.host().ok_or("The provided URL does not have a domain")? {
-            url::Host::Domain(d) => d,
-            _ => return Err("The provided URL must have a domain, not an IP address")
-        };
+    let probe = match url {
+        Some(url) if scheme == "tcp" => Probe::Tcp {
+            port: url.port().ok_or("TCP targets must have a port, like \"tcp://example.com:443\"")?,
+            timeout: timeout.unwrap_or(DEFAULT_TIMEOUT),
+        },
+        Some(url) if is_http => Probe::Http(HttpCheck {
+            url,
+            status,
+            body_contains,
+            timeout: timeout.unwrap_or(DEFAULT_TIMEOUT),
+        }),
+        _ => Probe::Icmp { timeout: timeout.unwrap_or(DEFAULT_PING_TIMEOUT), size },
+    };
 
-        let lookup = resolver.lookup_ip(hostname);
+    Ok(TargetArg { host, probe, marking })
+}
 
-        match lookup {
-            Ok(o) => {
-                let v4: Option<Ipv4Addr> = o.as_lookup().record_iter().find_map(|r| Some(r.data()?.as_a()?.0));
-                let v6: Option<Ipv6Addr> = o.as_lookup().record_iter().find_map(|r| Some(r.data()?.as_aaaa()?.0));
+/// a packet loss percentage that leaves room for being healthy, so below 100
+fn parse_loss(val: &str) -> Result<f64, &'static str> {
+    val.parse::<f64>().ok()
+        .filter(|loss| (0.0..100.0).contains(loss))
+        .ok_or("The packet loss must be a percentage of at least 0 and below 100")
+}
 
-                match (v4, v6) {
-                    (None, None) => Err("The provided domain is invalid"),
-                    (None, Some(_)) => Err("The provided domain does not support IPv4"),
-                    (Some(_), None) => Err("The provided domain does not support IPv6"),
-                    (Some(v4), Some(v6)) => Ok((v4, v6)),
-                }
+/// a date, meaning midnight in local time, or an RFC 3339 timestamp
+fn parse_time(val: &str) -> Result<DateTime<Local>, &'static str> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(val) {
+        return Ok(time.with_timezone(&Local));
+    }
+    let date = NaiveDate::parse_from_str(val, "%Y-%m-%d")
+        .map_err(|_| "Times must be dates like \"2024-01-31\" or RFC 3339 timestamps like \"2024-01-31T12:00:00Z\"")?;
+    Local.from_local_datetime(&date.and_time(NaiveTime::MIN)).earliest()
+        .ok_or("Midnight of that date doesn't exist in the local time zone")
+}
+
+/// an InfluxDB write URL, "udp://host:port" or "graphite://host:port"
+fn parse_collector(val: &str) -> Result<Collector, &'static str> {
+    let mut url = url::Url::parse(val)
+        .map_err(|_| "Collectors must be URLs like \"http://localhost:8086/write?db=network\" or \"graphite://localhost:2003\"")?;
+    let addr = |url: &url::Url| match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => Ok(format!("{host}:{port}")),
+        _ => Err("UDP and Graphite collectors need a host and a port"),
+    };
+    match url.scheme() {
+        "http" | "https" => {
+            let token = match url.fragment() {
+                Some(fragment) => {
+                    let token = fragment.strip_prefix("token=").ok_or("The only option for InfluxDB is \"#token=TOKEN\"")?;
+                    Some(token.to_string())
+                },
+                None => None,
+            };
+            url.set_fragment(None);
+            Ok(Collector::InfluxHttp { url, token })
+        },
+        "udp" => Ok(Collector::InfluxUdp(addr(&url)?)),
+        "graphite" | "tcp" => Ok(Collector::Graphite(addr(&url)?)),
+        _ => Err("Collectors must be http(s)://, udp:// or graphite:// URLs"),
+    }
+}
+
+/// an http(s):// URL, optionally followed by "#format=FORMAT" or "#template=PATH"
+fn parse_webhook(val: &str) -> Result<Webhook, &'static str> {
+    let mut url = url::Url::parse(val).map_err(|_| "Webhooks must be http(s):// URLs")?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("Webhooks must be http(s):// URLs");
+    }
+    let options = url.fragment().unwrap_or_default().to_string();
+    url.set_fragment(None);
+
+    let mut template = Template::default();
+    for (key, value) in url::form_urlencoded::parse(options.as_bytes()) {
+        match key.as_ref() {
+            "format" => {
+                template = Template::preset(&value)
+                    .ok_or("Unknown webhook format, must be one of json, slack, discord, teams or ntfy")?;
             },
-            Err(_e) => Err("There was an error while trying to resolve the hostname"),
-        }
-    } else {
-        let lookup = resolver.lookup_ip(val);
-
-        match lookup {
-            Ok(o) => {
-                let v4: Option<Ipv4Addr> = o.as_lookup().record_iter().find_map(|r| Some(r.data()?.as_a()?.0));
-                let v6: Option<Ipv6Addr> = o.as_lookup().record_iter().find_map(|r| Some(r.data()?.as_aaaa()?.0));
-
-                match (v4, v6) {
-                    (None, None) => Err("The provided domain is invalid"),
-                    (None, Some(_)) => Err("The provided domain does not support IPv4"),
-                    (Some(_), None) => Err("The provided domain does not support IPv6"),
-                    (Some(v4), Some(v6)) => Ok((v4, v6)),
-                }
+            "template" => {
+                let contents = std::fs::read_to_string(value.as_ref()).map_err(|_| "Could not read the webhook template")?;
+                template = Template::parse(&contents)?;
             },
-            Err(_e) => Err("There was an error while trying to resolve the hostname"),
+            _ => return Err("Unknown webhook option, must be \"format\" or \"template\""),
         }
     }
+    Ok(Webhook { url, template })
+}
+
+/// "SCOPE=COMMAND", where SCOPE is "network", "ipv4", "ipv6" or a hostname
+fn parse_hook(val: &str) -> Result<Hook, &'static str> {
+    let (scope, command) = val.split_once('=')
+        .ok_or("Hooks must look like \"SCOPE=COMMAND\", like \"ipv6=/usr/local/bin/restart-modem\"")?;
+    let scope = match scope.trim().to_ascii_lowercase().as_str() {
+        "" => return Err("The scope of a hook must be \"network\", \"ipv4\", \"ipv6\" or a hostname"),
+        "network" => Scope::Network,
+        "ipv4" => Scope::Family(Family::V4),
+        "ipv6" => Scope::Family(Family::V6),
+        _ => Scope::Target(scope.trim().to_string()),
+    };
+    if command.trim().is_empty() {
+        return Err("The command of a hook can't be empty");
+    }
+    Ok(Hook { scope, command: command.to_string() })
+}
+
+/// an IP address with an optional port, like "1.1.1.1", "1.1.1.1:53" or "[::1]:53"
+fn parse_nameserver(val: &str) -> Result<(IpAddr, Option<u16>), &'static str> {
+    if let Ok(addr) = val.parse::<SocketAddr>() {
+        return Ok((addr.ip(), Some(addr.port())));
+    }
+    match val.parse::<IpAddr>() {
+        Ok(addr) => Ok((addr, None)),
+        Err(_) => Err("Nameservers must be IP addresses, optionally with a port"),
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use clap::CommandFactory;
+    use std::time::Duration;
+
+    use chrono::{Local, TimeZone, Utc};
+    use clap::{CommandFactory, Parser};
+    use network_monitor::{
+        export::Collector,
+        hook::{Hook, Scope},
+        probe::{HttpCheck, Marking, DEFAULT_PING_TIMEOUT, DEFAULT_TIMEOUT},
+        webhook::Template,
+        Family,
+        Probe
+    };
 
-    use super::Args;
+    use super::{config_args, parse_collector, parse_hook, parse_nameserver, parse_webhook, parse_target, parse_time, Args, CommandArg, PeriodArg, TargetArg};
 
 
     #[test]
     fn verify_cli() {
         Args::command().debug_assert()
     }
+
+    #[test]
+    fn hostnames() {
+        let host = |val| parse_target(val).unwrap().host;
+        assert_eq!(host("youtube.com"), "youtube.com");
+        assert_eq!(host("https://youtube.com/watch"), "youtube.com");
+        assert_eq!(host("127.0.0.1"), "127.0.0.1");
+        assert_eq!(host("::1"), "::1");
+        assert_eq!(host("http://[::1]:8080/"), "::1");
+    }
+
+    #[test]
+    fn tcp_targets() {
+        assert_eq!(
+            parse_target("tcp://example.com:443").unwrap(),
+            TargetArg {
+                host: "example.com".to_string(),
+                probe: Probe::Tcp { port: 443, timeout: DEFAULT_TIMEOUT },
+                marking: Marking::default(),
+            }
+        );
+        assert_eq!(
+            parse_target("tcp://[::1]:22#timeout=1.5").unwrap().probe,
+            Probe::Tcp { port: 22, timeout: Duration::from_millis(1500) }
+        );
+        assert!(parse_target("tcp://example.com").is_err());
+        assert!(parse_target("tcp://example.com:443#timeout=0").is_err());
+        assert!(parse_target("tcp://example.com:443#foo=bar").is_err());
+        assert!(parse_target("tcp://example.com:443#status=200").is_err());
+        assert!(parse_target("tcp://example.com:443#size=1400").is_err());
+    }
+
+    #[test]
+    fn icmp_targets() {
+        assert_eq!(parse_target("example.com").unwrap().probe, Probe::default());
+        assert_eq!(
+            parse_target("1.1.1.1#size=1400&ttl=64&dscp=46&timeout=10").unwrap(),
+            TargetArg {
+                host: "1.1.1.1".to_string(),
+                probe: Probe::Icmp { timeout: Duration::from_secs(10), size: 1400 },
+                marking: Marking { ttl: Some(64), dscp: Some(46) },
+            }
+        );
+        assert_eq!(
+            parse_target("::1#size=0").unwrap().probe,
+            Probe::Icmp { timeout: DEFAULT_PING_TIMEOUT, size: 0 }
+        );
+        assert!(parse_target("example.com#ttl=0").is_err());
+        assert!(parse_target("example.com#dscp=64").is_err());
+        assert!(parse_target("example.com#size=70000").is_err());
+        assert!(parse_target("example.com#status=200").is_err());
+    }
+
+    #[test]
+    fn http_targets() {
+        assert_eq!(
+            parse_target("https://example.com/health?full=1#status=204&contains=all%20good&timeout=2").unwrap(),
+            TargetArg {
+                host: "example.com".to_string(),
+                probe: Probe::Http(HttpCheck {
+                    url: "https://example.com/health?full=1".parse().unwrap(),
+                    status: Some(204),
+                    body_contains: Some("all good".to_string()),
+                    timeout: Duration::from_secs(2),
+                }),
+                marking: Marking::default(),
+            }
+        );
+        assert!(matches!(
+            parse_target("http://[::1]:8080/").unwrap().probe,
+            Probe::Http(HttpCheck { status: None, body_contains: None, .. })
+        ));
+        assert!(parse_target("https://example.com#status=42").is_err());
+    }
+
+    #[test]
+    fn config_file() {
+        let config = r#"
+            # comments and blank lines are skipped
+            interval = 5
+            ipv4-only = true
+            verbosity = 2
+            nameserver = 1.1.1.1
+            nameserver = "8.8.8.8"
+            target = 1.1.1.1#ttl=64
+            target = https://example.com/#status=200
+        "#;
+        let (options, targets) = config_args(config, &Args::command()).unwrap();
+        assert_eq!(
+            options,
+            [
+                "--interval", "5", "--ipv4-only", "-v", "-v",
+                "--nameserver", "1.1.1.1", "--nameserver", "8.8.8.8",
+            ]
+        );
+        assert_eq!(targets, ["1.1.1.1#ttl=64", "https://example.com/#status=200"]);
+
+        // the command line goes after the config file, overriding it
+        let args = Args::try_parse_from(
+            ["network_monitor"].into_iter()
+                .chain(options.iter().map(String::as_str))
+                .chain(["-i", "30"])
+                .chain(targets.iter().map(String::as_str))
+        ).unwrap();
+        assert_eq!(args.interval, 30);
+        assert_eq!(args.verbosity, 2);
+        assert_eq!(args.hostnames.len(), 2);
+
+        assert!(config_args("interval 5", &Args::command()).is_err());
+        assert!(config_args("frobnicate = 1", &Args::command()).is_err());
+        assert!(config_args("ipv4-only = maybe", &Args::command()).is_err());
+        assert!(config_args("config = other.conf", &Args::command()).is_err());
+    }
+
+    #[test]
+    fn report_command() {
+        let args = Args::try_parse_from(["network_monitor", "-i", "5", "report", "-o", "/", "--since", "2024-01-31", "--by", "week"]).unwrap();
+        assert_eq!(args.out_dir, Some("/".into()));
+        let Some(CommandArg::Report(report)) = args.command else {
+            panic!("expected a report");
+        };
+        assert_eq!(report.since, Some(Local.with_ymd_and_hms(2024, 1, 31, 0, 0, 0).unwrap()));
+        assert_eq!(report.by, Some(PeriodArg::Week));
+
+        assert_eq!(
+            parse_time("2024-01-31T12:00:00Z").unwrap(),
+            Utc.with_ymd_and_hms(2024, 1, 31, 12, 0, 0).unwrap()
+        );
+        assert!(parse_time("yesterday").is_err());
+    }
+
+    #[test]
+    fn rejects_out_of_range_options() {
+        for args in [
+            ["--hysteresis", "0"],
+            ["-q", "0"],
+            ["-i", "0"],
+            ["--degraded-loss", "100"],
+            ["--degraded-window", "0"],
+            ["--pmtu-sizes", "1500,40"],
+            ["--dns-probe", ""],
+        ] {
+            assert!(Args::try_parse_from(["network_monitor"].into_iter().chain(args)).is_err(), "{args:?}");
+        }
+        assert!(Args::try_parse_from(["network_monitor", "--degraded-loss", "5.5", "--pmtu-sizes", "68,1500"]).is_ok());
+    }
+
+    #[test]
+    fn nameservers() {
+        assert_eq!(parse_nameserver("1.1.1.1").unwrap(), ("1.1.1.1".parse().unwrap(), None));
+        assert_eq!(parse_nameserver("[::1]:5353").unwrap(), ("::1".parse().unwrap(), Some(5353)));
+        assert!(parse_nameserver("dns.google").is_err());
+    }
+
+    #[test]
+    fn collectors() {
+        assert_eq!(parse_collector("https://influx.lan/api/v2/write?org=home&bucket=net#token=abc").unwrap(), Collector::InfluxHttp {
+            url: "https://influx.lan/api/v2/write?org=home&bucket=net".parse().unwrap(),
+            token: Some("abc".to_string()),
+        });
+        assert_eq!(parse_collector("udp://[::1]:8089").unwrap(), Collector::InfluxUdp("[::1]:8089".to_string()));
+        assert_eq!(parse_collector("graphite://graphite.lan:2003").unwrap(), Collector::Graphite("graphite.lan:2003".to_string()));
+        assert!(parse_collector("graphite://graphite.lan").is_err());
+        assert!(parse_collector("http://influx.lan/write#db=net").is_err());
+    }
+
+    #[test]
+    fn webhooks() {
+        let webhook = parse_webhook("https://hooks.slack.com/services/T0/B0/x#format=slack").unwrap();
+        assert_eq!(webhook.url.as_str(), "https://hooks.slack.com/services/T0/B0/x");
+        assert_eq!(webhook.template, Template::preset("slack").unwrap());
+        assert_eq!(parse_webhook("http://localhost:8080/hook").unwrap().template, Template::default());
+        assert!(parse_webhook("https://ntfy.sh/alerts#format=email").is_err());
+        assert!(parse_webhook("ftp://example.com/hook").is_err());
+    }
+
+    #[test]
+    fn hooks() {
+        assert_eq!(parse_hook("IPv6=restart-modem --hard").unwrap(), Hook {
+            scope: Scope::Family(Family::V6),
+            command: "restart-modem --hard".to_string(),
+        });
+        assert_eq!(parse_hook("example.com=notify a=b").unwrap(), Hook {
+            scope: Scope::Target("example.com".to_string()),
+            command: "notify a=b".to_string(),
+        });
+        assert!(parse_hook("restart-modem").is_err());
+        assert!(parse_hook("network= ").is_err());
+    }
 }
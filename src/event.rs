@@ -0,0 +1,403 @@
+use std::{fmt::Display, net::IpAddr, time::Duration};
+
+use chrono::{DateTime, Utc};
+
+use crate::{probe::{BurstStats, DnsFailure, HttpTimings, ProbeKind, Route}, stats::RttStats};
+
+/// address family of a monitored path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    pub fn of(addr: &IpAddr) -> Self {
+        match addr {
+            IpAddr::V4(_) => Self::V4,
+            IpAddr::V6(_) => Self::V6,
+        }
+    }
+}
+
+impl Display for Family {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Family::V4 => write!(f, "IPv4"),
+            Family::V6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+/// why the route to a target was traced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceReason {
+    /// the target just went down
+    Outage,
+    /// the target just came back, for comparing with the route during the
+    /// outage
+    Recovery,
+}
+
+/// where an outage is, from closest to furthest away
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum OutageCause {
+    /// there's no default route, or its interface is down
+    Lan,
+    /// the default gateway doesn't answer
+    Gateway,
+    /// the gateway answers but the ISP's first hop doesn't, or it isn't known
+    /// and every target is down
+    Isp,
+    /// everything up to the ISP answers, so it's the targets or beyond
+    Remote,
+}
+
+impl Display for OutageCause {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutageCause::Lan => write!(f, "local network"),
+            OutageCause::Gateway => write!(f, "gateway"),
+            OutageCause::Isp => write!(f, "ISP"),
+            OutageCause::Remote => write!(f, "remote target"),
+        }
+    }
+}
+
+/// a [`NetworkEvent`] along with when it happened
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub timestamp: DateTime<Utc>,
+    pub kind: NetworkEvent,
+}
+
+impl Event {
+    /// timestamps `kind` with the current time
+    pub fn now(kind: NetworkEvent) -> Self {
+        Self { timestamp: Utc::now(), kind }
+    }
+}
+
+/// everything that a running [`Monitor`](crate::Monitor) reports
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetworkEvent {
+    ProbeSucceeded {
+        target: String,
+        family: Family,
+        addr: IpAddr,
+        probe: ProbeKind,
+        /// how long the probe took, like the round trip time of a ping
+        rtt: Duration,
+        /// how long each step of the request took, only set by HTTP probes
+        http: Option<HttpTimings>,
+    },
+    ProbeFailed {
+        target: String,
+        family: Family,
+        addr: IpAddr,
+        probe: ProbeKind,
+        error: String,
+        /// how many probes in a row have failed, including this one
+        failures: u32,
+    },
+    /// loss and jitter of a burst of pings, sent after the probe's own result
+    BurstCompleted {
+        target: String,
+        family: Family,
+        addr: IpAddr,
+        stats: BurstStats,
+    },
+    /// a single target stopped responding while enough others still do, so
+    /// it's probably an issue with the target itself
+    TargetDown {
+        target: String,
+        family: Family,
+        /// where the outage is, if outages are localized
+        cause: Option<OutageCause>,
+    },
+    TargetUp {
+        target: String,
+        family: Family,
+        /// how long the target was unreachable for
+        downtime: Duration,
+        /// where the outage was when it began
+        cause: Option<OutageCause>,
+    },
+    /// a target still responds over this family, but with too much packet
+    /// loss or latency
+    TargetDegraded {
+        target: String,
+        family: Family,
+        /// which threshold was exceeded
+        reason: String,
+    },
+    /// a degraded target is healthy again
+    TargetRecovered {
+        target: String,
+        family: Family,
+        /// how long the target was degraded for
+        duration: Duration,
+    },
+    /// enough targets stopped responding over this family for it to be
+    /// considered down
+    FamilyDown {
+        family: Family,
+        /// where the outage is, if outages are localized
+        cause: Option<OutageCause>,
+    },
+    FamilyUp {
+        family: Family,
+        /// how long the family was down for
+        downtime: Duration,
+        /// where the outage was when it began
+        cause: Option<OutageCause>,
+    },
+    NetworkDown {
+        /// where the outage is, the one closest to this host if the families
+        /// went down for different reasons
+        cause: Option<OutageCause>,
+    },
+    NetworkUp {
+        /// how long the network was down for
+        downtime: Duration,
+        /// where the outage was when it began
+        cause: Option<OutageCause>,
+    },
+    /// a target's hostname now resolves to a different address, which will be
+    /// used from now on
+    AddressChanged {
+        target: String,
+        family: Family,
+        old: IpAddr,
+        new: IpAddr,
+    },
+    /// re-resolving a target's hostname failed, its last known addresses are
+    /// still used
+    ResolutionFailed {
+        target: String,
+        error: String,
+    },
+    DnsProbeSucceeded {
+        name: String,
+        /// how long resolving the name took
+        latency: Duration,
+    },
+    DnsProbeFailed {
+        name: String,
+        reason: DnsFailure,
+        /// how many lookups of this name in a row have failed, including this
+        /// one
+        failures: u32,
+    },
+    /// every name checked by DNS probes stopped resolving
+    DnsDown,
+    DnsUp {
+        /// how long DNS was down for
+        downtime: Duration,
+    },
+    /// round trip times of a target over a family during the last window,
+    /// only sent if at least one probe succeeded
+    LatencySummary {
+        target: String,
+        family: Family,
+        probe: ProbeKind,
+        /// how long the window was
+        window: Duration,
+        stats: RttStats,
+    },
+    /// the largest packets that reach a target over this family changed size,
+    /// or were measured for the first time
+    PathMtuChanged {
+        target: String,
+        family: Family,
+        /// `None` for the first measurement
+        previous: Option<usize>,
+        mtu: usize,
+    },
+    /// the largest packets that reach any target over this family changed
+    /// size, which is what the local link and ISP allow
+    FamilyMtuChanged {
+        family: Family,
+        /// `None` for the first measurement
+        previous: Option<usize>,
+        mtu: usize,
+    },
+    /// large packets to a target stopped getting through while smaller ones
+    /// still do, usually a black hole where ICMP "packet too big" errors are
+    /// dropped somewhere along the path
+    LargePacketsDropped {
+        target: String,
+        family: Family,
+        /// largest packet that still gets through
+        mtu: usize,
+        /// smallest packet that doesn't
+        size: usize,
+    },
+    /// the route to a target, traced when it went down or came back, sent
+    /// after the outage or recovery it belongs to once the traceroute is done
+    RouteTraced {
+        target: String,
+        family: Family,
+        reason: TraceReason,
+        route: Route,
+    },
+}
+
+impl NetworkEvent {
+    /// whether this is a change in the state of the network, as opposed to a
+    /// single probe result
+    pub fn is_transition(&self) -> bool {
+        matches!(
+            self,
+            Self::TargetDown { .. } | Self::TargetUp { .. }
+                | Self::TargetDegraded { .. } | Self::TargetRecovered { .. }
+                | Self::FamilyDown { .. } | Self::FamilyUp { .. }
+                | Self::NetworkDown { .. } | Self::NetworkUp { .. }
+                | Self::DnsDown | Self::DnsUp { .. }
+        )
+    }
+
+    /// the kind of event in snake case, like "family_down"
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::ProbeSucceeded { .. } => "probe_succeeded",
+            Self::ProbeFailed { .. } => "probe_failed",
+            Self::BurstCompleted { .. } => "burst_completed",
+            Self::TargetDown { .. } => "target_down",
+            Self::TargetUp { .. } => "target_up",
+            Self::TargetDegraded { .. } => "target_degraded",
+            Self::TargetRecovered { .. } => "target_recovered",
+            Self::FamilyDown { .. } => "family_down",
+            Self::FamilyUp { .. } => "family_up",
+            Self::NetworkDown { .. } => "network_down",
+            Self::NetworkUp { .. } => "network_up",
+            Self::AddressChanged { .. } => "address_changed",
+            Self::ResolutionFailed { .. } => "resolution_failed",
+            Self::DnsProbeSucceeded { .. } => "dns_probe_succeeded",
+            Self::DnsProbeFailed { .. } => "dns_probe_failed",
+            Self::DnsDown => "dns_down",
+            Self::DnsUp { .. } => "dns_up",
+            Self::LatencySummary { .. } => "latency_summary",
+            Self::PathMtuChanged { .. } => "path_mtu_changed",
+            Self::FamilyMtuChanged { .. } => "family_mtu_changed",
+            Self::LargePacketsDropped { .. } => "large_packets_dropped",
+            Self::RouteTraced { .. } => "route_traced",
+        }
+    }
+}
+
+impl Display for NetworkEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ProbeSucceeded { addr, probe, rtt, http, .. } => {
+                write!(f, "{probe} to {addr} successful in {:.2}ms", rtt.as_secs_f64() * 1000.0)?;
+                if let Some(http) = http {
+                    write!(f, " ({http})")?;
+                }
+                Ok(())
+            },
+            Self::ProbeFailed { addr, probe, failures, .. } => write!(f, "{probe} to {addr} failed {failures} times"),
+            Self::BurstCompleted { addr, stats, .. } => {
+                write!(
+                    f,
+                    "{}/{} echo replies from {addr}, {:.1}% loss, {:.2}ms jitter, {} reordered",
+                    stats.received,
+                    stats.sent,
+                    stats.loss(),
+                    stats.jitter.as_secs_f64() * 1000.0,
+                    stats.reordered
+                )?;
+                match stats.duplicates {
+                    Some(duplicates) => write!(f, ", {duplicates} duplicates"),
+                    None => Ok(()),
+                }
+            },
+            Self::TargetDown { target, family, cause } => {
+                write!(f, "{target} is unreachable over {family}")?;
+                write_cause(f, cause)
+            },
+            Self::TargetUp { target, family, downtime, cause } => {
+                write!(
+                    f,
+                    "{target} is reachable over {family} again, and was unreachable for {}",
+                    format_duration(*downtime)
+                )?;
+                write_cause(f, cause)
+            },
+            Self::TargetDegraded { target, family, reason } => {
+                write!(f, "{target} is degraded over {family}: {reason}")
+            },
+            Self::TargetRecovered { target, family, duration } => write!(
+                f,
+                "{target} is healthy over {family} again, and was degraded for {}",
+                format_duration(*duration)
+            ),
+            Self::FamilyDown { family, cause } => {
+                write!(f, "{family} is down!")?;
+                write_cause(f, cause)
+            },
+            Self::FamilyUp { family, downtime, cause } => {
+                write!(f, "{family} is back online, and was down for {}", format_duration(*downtime))?;
+                write_cause(f, cause)
+            },
+            Self::NetworkDown { cause } => {
+                write!(f, "network is down!")?;
+                write_cause(f, cause)
+            },
+            Self::NetworkUp { downtime, cause } => {
+                write!(f, "network is back online, and was down for {}", format_duration(*downtime))?;
+                write_cause(f, cause)
+            },
+            Self::AddressChanged { target, family, old, new } => {
+                write!(f, "{family} address of {target} changed from {old} to {new}")
+            },
+            Self::ResolutionFailed { target, error } => {
+                write!(f, "could not resolve {target}, keeping its last known addresses: {error}")
+            },
+            Self::DnsProbeSucceeded { name, latency } => {
+                write!(f, "resolved {name} in {:.2}ms", latency.as_secs_f64() * 1000.0)
+            },
+            Self::DnsProbeFailed { name, reason, failures } => {
+                write!(f, "resolving {name} failed {failures} times: {reason}")
+            },
+            Self::DnsDown => write!(f, "DNS is down!"),
+            Self::DnsUp { downtime } => {
+                write!(f, "DNS is back online, and was down for {}", format_duration(*downtime))
+            },
+            Self::LatencySummary { target, family, probe, window, stats } => write!(
+                f,
+                "{probe} latency to {target} over {family} in the last {}: {stats}",
+                format_duration(*window)
+            ),
+            Self::PathMtuChanged { target, family, previous, mtu } => match previous {
+                Some(previous) => write!(f, "path MTU to {target} over {family} changed from {previous} to {mtu} bytes"),
+                None => write!(f, "path MTU to {target} over {family} is {mtu} bytes"),
+            },
+            Self::FamilyMtuChanged { family, previous, mtu } => match previous {
+                Some(previous) => write!(f, "{family} path MTU changed from {previous} to {mtu} bytes"),
+                None => write!(f, "{family} path MTU is {mtu} bytes"),
+            },
+            Self::LargePacketsDropped { target, family, mtu, size } => write!(
+                f,
+                "{size} byte packets to {target} over {family} are being dropped while {mtu} byte ones get through"
+            ),
+            Self::RouteTraced { target, family, reason, route } => match reason {
+                TraceReason::Outage => write!(f, "route to {target} over {family} during the outage: {route}"),
+                TraceReason::Recovery => write!(f, "route to {target} over {family} after recovering: {route}"),
+            },
+        }
+    }
+}
+
+fn write_cause(f: &mut std::fmt::Formatter<'_>, cause: &Option<OutageCause>) -> std::fmt::Result {
+    match cause {
+        Some(cause) => write!(f, " (outage at the {cause})"),
+        None => Ok(()),
+    }
+}
+
+/// formats a duration as HH:MM:SS
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{:02}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+}
@@ -0,0 +1,456 @@
+//! pushes probe results and outages to InfluxDB or Graphite, keeping them
+//! while the collector can't be reached
+
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    time::{Duration, Instant}
+};
+
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpStream, UdpSocket},
+    select,
+    sync::mpsc,
+    task::JoinHandle
+};
+use url::Url;
+
+use crate::{
+    event::{Event, Family, NetworkEvent},
+    probe::{post, ProbeKind},
+    sink::Sink
+};
+
+/// how often buffered samples are sent
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// most lines sent at once
+const BATCH_SIZE: usize = 5000;
+
+/// most lines kept while the collector can't be reached, the oldest ones are
+/// dropped past this, which is days' worth at the default interval
+const MAX_BUFFERED: usize = 500_000;
+
+/// largest UDP datagram sent, which fits in any MTU once the headers are added
+const MAX_DATAGRAM_SIZE: usize = 1200;
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// where samples are pushed to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Collector {
+    /// InfluxDB's HTTP write API, like "http://localhost:8086/write?db=network"
+    /// for 1.x or "http://localhost:8086/api/v2/write?org=home&bucket=network"
+    /// for 2.x
+    InfluxHttp {
+        url: Url,
+        /// sent as "Authorization: Token ..." for 2.x
+        token: Option<String>,
+    },
+    /// InfluxDB's UDP listener at "host:port", which doesn't confirm that
+    /// anything arrived, so only errors reported by the socket are retried
+    InfluxUdp(String),
+    /// Graphite's plaintext protocol over TCP at "host:port"
+    Graphite(String),
+}
+
+impl Display for Collector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Collector::InfluxHttp { url, .. } => write!(f, "InfluxDB at {url}"),
+            Collector::InfluxUdp(addr) => write!(f, "InfluxDB at udp://{addr}"),
+            Collector::Graphite(addr) => write!(f, "Graphite at {addr}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Float(f64),
+    Integer(i64),
+}
+
+/// a measurement at some point in time
+#[derive(Debug, Clone, PartialEq)]
+struct Sample {
+    measurement: &'static str,
+    tags: Vec<(&'static str, String)>,
+    fields: Vec<(&'static str, Field)>,
+    time: DateTime<Utc>,
+}
+
+impl Sample {
+    fn new(measurement: &'static str, time: DateTime<Utc>) -> Self {
+        Self { measurement, tags: Vec::new(), fields: Vec::new(), time }
+    }
+
+    fn tag(mut self, key: &'static str, value: impl Display) -> Self {
+        self.tags.push((key, value.to_string()));
+        self
+    }
+
+    fn family(self, family: Family) -> Self {
+        self.tag("family", match family {
+            Family::V4 => "ipv4",
+            Family::V6 => "ipv6",
+        })
+    }
+
+    fn float(mut self, key: &'static str, value: f64) -> Self {
+        self.fields.push((key, Field::Float(value)));
+        self
+    }
+
+    fn integer(mut self, key: &'static str, value: i64) -> Self {
+        self.fields.push((key, Field::Integer(value)));
+        self
+    }
+
+    /// as a single line of InfluxDB's line protocol, with a nanosecond
+    /// timestamp
+    fn to_line_protocol(&self) -> String {
+        let escape = |value: &str, special: &[char]| {
+            let mut escaped = String::with_capacity(value.len());
+            for c in value.chars() {
+                if special.contains(&c) {
+                    escaped.push('\\');
+                }
+                escaped.push(c);
+            }
+            escaped
+        };
+        let mut line = escape(self.measurement, &[',', ' ']);
+        for (key, value) in &self.tags {
+            // empty tag values aren't allowed
+            if !value.is_empty() {
+                line.push_str(&format!(",{key}={}", escape(value, &[',', '=', ' '])));
+            }
+        }
+        for (i, (key, value)) in self.fields.iter().enumerate() {
+            line.push(if i == 0 { ' ' } else { ',' });
+            match value {
+                Field::Float(value) => line.push_str(&format!("{key}={value}")),
+                Field::Integer(value) => line.push_str(&format!("{key}={value}i")),
+            }
+        }
+        line.push_str(&format!(" {}", self.time.timestamp_nanos_opt().unwrap_or_default()));
+        line
+    }
+
+    /// as Graphite plaintext lines, one per field, named after the
+    /// measurement and the tag values, like
+    /// "network_monitor.probe.example_com.ipv4.icmp.rtt_ms 12.3 1700000000"
+    fn to_graphite(&self) -> Vec<String> {
+        let sanitize = |value: &str| -> String {
+            value.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect()
+        };
+        let mut path = format!("network_monitor.{}", self.measurement);
+        for (_, value) in &self.tags {
+            path.push('.');
+            path.push_str(&sanitize(value));
+        }
+        self.fields.iter().map(|(key, value)| {
+            let value = match value {
+                Field::Float(value) => value.to_string(),
+                Field::Integer(value) => value.to_string(),
+            };
+            format!("{path}.{key} {value} {}", self.time.timestamp())
+        }).collect()
+    }
+}
+
+/// what's pushed for an event, if anything
+fn samples(event: &Event) -> Vec<Sample> {
+    let time = event.timestamp;
+    let ms = |duration: &Duration| duration.as_secs_f64() * 1000.0;
+    let probe = |target: &str, family: Family, probe: ProbeKind| Sample::new("probe", time)
+        .tag("target", target)
+        .family(family)
+        .tag("probe", match probe {
+            ProbeKind::Icmp => "icmp",
+            ProbeKind::Tcp => "tcp",
+            ProbeKind::Http => "http",
+        });
+    let status = |scope: &'static str| Sample::new("status", time).tag("scope", scope);
+    let sample = match &event.kind {
+        NetworkEvent::ProbeSucceeded { target, family, probe: kind, rtt, .. } => {
+            probe(target, *family, *kind).float("rtt_ms", ms(rtt)).integer("success", 1)
+        },
+        NetworkEvent::ProbeFailed { target, family, probe: kind, .. } => {
+            probe(target, *family, *kind).integer("success", 0)
+        },
+        NetworkEvent::BurstCompleted { target, family, stats, .. } => Sample::new("burst", time)
+            .tag("target", target)
+            .family(*family)
+            .float("loss_percent", stats.loss())
+            .float("jitter_ms", ms(&stats.jitter))
+            .integer("sent", stats.sent.into())
+            .integer("received", stats.received.into()),
+        NetworkEvent::TargetDown { target, family, .. } => {
+            status("target").tag("target", target).family(*family).integer("up", 0)
+        },
+        NetworkEvent::TargetUp { target, family, downtime, .. } => status("target")
+            .tag("target", target)
+            .family(*family)
+            .integer("up", 1)
+            .float("downtime_s", downtime.as_secs_f64()),
+        NetworkEvent::FamilyDown { family, .. } => status("family").family(*family).integer("up", 0),
+        NetworkEvent::FamilyUp { family, downtime, .. } => {
+            status("family").family(*family).integer("up", 1).float("downtime_s", downtime.as_secs_f64())
+        },
+        NetworkEvent::NetworkDown { .. } => status("network").integer("up", 0),
+        NetworkEvent::NetworkUp { downtime, .. } => {
+            status("network").integer("up", 1).float("downtime_s", downtime.as_secs_f64())
+        },
+        NetworkEvent::DnsDown => status("dns").integer("up", 0),
+        NetworkEvent::DnsUp { downtime } => status("dns").integer("up", 1).float("downtime_s", downtime.as_secs_f64()),
+        _ => return Vec::new(),
+    };
+    vec![sample]
+}
+
+/// why a batch couldn't be sent
+enum SendError {
+    /// the collector may accept it later
+    Retry(String),
+    /// the collector won't ever accept it, like when it's malformed
+    Rejected(String),
+}
+
+impl Collector {
+    fn encode(&self, sample: &Sample) -> Vec<String> {
+        match self {
+            Collector::InfluxHttp { .. } | Collector::InfluxUdp(_) => vec![sample.to_line_protocol()],
+            Collector::Graphite(_) => sample.to_graphite(),
+        }
+    }
+
+    async fn send(&self, lines: &[String]) -> Result<(), SendError> {
+        let mut body = lines.join("\n");
+        body.push('\n');
+        let send = async {
+            match self {
+                Collector::InfluxHttp { url, token } => {
+                    let authorization = token.as_ref().map(|token| format!("Token {token}"));
+                    let mut headers = vec![("Content-Type", "text/plain; charset=utf-8")];
+                    if let Some(authorization) = &authorization {
+                        headers.push(("Authorization", authorization));
+                    }
+                    match post(url, &headers, body.as_bytes(), SEND_TIMEOUT).await {
+                        Ok(status) if status < 300 => Ok(()),
+                        // too many requests
+                        Ok(429) => Err(SendError::Retry("got status 429".to_string())),
+                        Ok(status) if status < 500 => Err(SendError::Rejected(format!("got status {status}"))),
+                        Ok(status) => Err(SendError::Retry(format!("got status {status}"))),
+                        Err(e) => Err(SendError::Retry(e)),
+                    }
+                },
+                Collector::InfluxUdp(addr) => {
+                    let resolved = tokio::net::lookup_host(addr.as_str()).await.ok().and_then(|mut addrs| addrs.next())
+                        .ok_or_else(|| SendError::Retry(format!("could not resolve {addr}")))?;
+                    let local = match resolved.is_ipv4() {
+                        true => "0.0.0.0:0",
+                        false => "[::]:0",
+                    };
+                    let socket = UdpSocket::bind(local).await
+                        .map_err(|e| SendError::Retry(format!("could not open socket: {e}")))?;
+                    socket.connect(resolved).await.map_err(|e| SendError::Retry(e.to_string()))?;
+                    let mut datagram = String::new();
+                    for line in lines {
+                        if !datagram.is_empty() && datagram.len() + line.len() + 1 > MAX_DATAGRAM_SIZE {
+                            socket.send(datagram.as_bytes()).await.map_err(|e| SendError::Retry(e.to_string()))?;
+                            datagram.clear();
+                        }
+                        datagram.push_str(line);
+                        datagram.push('\n');
+                    }
+                    socket.send(datagram.as_bytes()).await.map_err(|e| SendError::Retry(e.to_string()))?;
+                    Ok(())
+                },
+                Collector::Graphite(addr) => {
+                    let mut stream = TcpStream::connect(addr).await
+                        .map_err(|e| SendError::Retry(format!("could not connect: {e}")))?;
+                    stream.write_all(body.as_bytes()).await.map_err(|e| SendError::Retry(e.to_string()))?;
+                    stream.shutdown().await.map_err(|e| SendError::Retry(e.to_string()))
+                },
+            }
+        };
+        match tokio::time::timeout(SEND_TIMEOUT, send).await {
+            Ok(res) => res,
+            Err(_) => Err(SendError::Retry(format!("timed out after {SEND_TIMEOUT:?}"))),
+        }
+    }
+}
+
+/// pushes samples of events to a collector in batches, as a [`Sink`]
+///
+/// samples are kept in memory while the collector can't be reached and sent
+/// once it's back, retrying with exponential backoff
+#[derive(Debug, Clone)]
+pub struct Exporter {
+    samples: mpsc::UnboundedSender<Sample>,
+}
+
+impl Exporter {
+    /// starts pushing to `collector`, must be called from within a tokio
+    /// runtime
+    ///
+    /// the returned task finishes once the exporter was dropped and what was
+    /// kept got one last chance at being sent, so it should be awaited before
+    /// exiting
+    pub fn start(collector: Collector) -> (Self, JoinHandle<()>) {
+        let (samples, rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(run(collector, rx));
+        (Self { samples }, task)
+    }
+}
+
+impl Sink for Exporter {
+    fn handle(&mut self, event: &Event) {
+        for sample in samples(event) {
+            // the exporter's task only stops once this is dropped
+            let _ = self.samples.send(sample);
+        }
+    }
+}
+
+async fn run(collector: Collector, mut samples: mpsc::UnboundedReceiver<Sample>) {
+    let mut buffer = VecDeque::new();
+    let mut dropped = 0;
+    let mut backoff = MIN_BACKOFF;
+    let mut retry_at = Instant::now();
+    let mut failing = false;
+    let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+    flush.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        let closed = select! {
+            sample = samples.recv() => match sample {
+                Some(sample) => {
+                    buffer.extend(collector.encode(&sample));
+                    if buffer.len() > MAX_BUFFERED {
+                        dropped += buffer.len() - MAX_BUFFERED;
+                        buffer.drain(..buffer.len() - MAX_BUFFERED);
+                    }
+                    // batches are only sent when they're full or every
+                    // flush interval
+                    if buffer.len() < BATCH_SIZE {
+                        continue;
+                    }
+                    false
+                },
+                None => true,
+            },
+            _ = flush.tick() => false,
+        };
+
+        if Instant::now() >= retry_at || closed {
+            while !buffer.is_empty() {
+                let batch: Vec<String> = buffer.iter().take(BATCH_SIZE).cloned().collect();
+                match collector.send(&batch).await {
+                    Ok(()) => {
+                        buffer.drain(..batch.len());
+                        if failing {
+                            info!("{collector} can be reached again, sending the samples kept meanwhile");
+                            failing = false;
+                        }
+                        backoff = MIN_BACKOFF;
+                    },
+                    Err(SendError::Rejected(e)) => {
+                        warn!("{collector} rejected {} samples, dropping them: {e}", batch.len());
+                        buffer.drain(..batch.len());
+                    },
+                    Err(SendError::Retry(e)) => {
+                        match failing {
+                            true => debug!("could not push to {collector}, retrying in {backoff:?}: {e}"),
+                            false => warn!("could not push to {collector}, keeping samples until it can be reached: {e}"),
+                        }
+                        failing = true;
+                        retry_at = Instant::now() + backoff;
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                        break;
+                    },
+                }
+            }
+        }
+        if dropped > 0 {
+            warn!("dropped {dropped} of the oldest samples for {collector}, as too many were kept");
+            dropped = 0;
+        }
+        if closed {
+            if !buffer.is_empty() {
+                warn!("could not push {} samples to {collector} before stopping", buffer.len());
+            }
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    use super::{samples, Collector};
+    use crate::{event::{Event, Family, NetworkEvent}, probe::ProbeKind};
+
+    fn probe_succeeded() -> Event {
+        Event {
+            timestamp: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+            kind: NetworkEvent::ProbeSucceeded {
+                target: "example.com".to_string(),
+                family: Family::V6,
+                addr: "2001:db8::1".parse().unwrap(),
+                probe: ProbeKind::Icmp,
+                rtt: Duration::from_micros(12_500),
+                http: None,
+            },
+        }
+    }
+
+    #[test]
+    fn encodes_samples() {
+        let sample = &samples(&probe_succeeded())[0];
+        assert_eq!(
+            sample.to_line_protocol(),
+            "probe,target=example.com,family=ipv6,probe=icmp rtt_ms=12.5,success=1i 1700000000000000000"
+        );
+        assert_eq!(sample.to_graphite(), [
+            "network_monitor.probe.example_com.ipv6.icmp.rtt_ms 12.5 1700000000",
+            "network_monitor.probe.example_com.ipv6.icmp.success 1 1700000000",
+        ]);
+
+        let down = Event {
+            timestamp: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+            kind: NetworkEvent::TargetDown { target: "a b,c".to_string(), family: Family::V4, cause: None },
+        };
+        assert_eq!(
+            samples(&down)[0].to_line_protocol(),
+            "status,scope=target,target=a\\ b\\,c,family=ipv4 up=0i 1700000000000000000"
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_to_graphite() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let collector = Collector::Graphite(listener.local_addr().unwrap().to_string());
+        let lines = collector.encode(&samples(&probe_succeeded())[0]);
+
+        let (sent, received) = tokio::join!(collector.send(&lines), async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut received = String::new();
+            stream.read_to_string(&mut received).await.unwrap();
+            received
+        });
+        assert!(sent.is_ok());
+        assert_eq!(received, lines.join("\n") + "\n");
+    }
+}
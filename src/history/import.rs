@@ -0,0 +1,393 @@
+//! rebuilds outages from the text logs written before the history existed
+
+use std::{
+    collections::{hash_map, HashMap},
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    time::Duration
+};
+
+use chrono::{DateTime, Utc};
+use flate2::read::MultiGzDecoder;
+
+use super::{Error, History, OutageScope};
+use crate::event::{Family, OutageCause};
+
+/// what importing logs did
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Imported {
+    /// outages saved to the history
+    pub outages: usize,
+    /// recoveries that were skipped since neither when the outage began nor
+    /// how long it lasted was logged
+    pub skipped: usize,
+}
+
+/// the log files in `dir`, including the compressed ones kept after rotating
+pub fn log_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if path.is_file() && (name.ends_with(".log") || name.ends_with(".log.gz")) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// a log line about an outage starting or ending
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Message {
+    /// the monitor was started again, so whatever was down before it stopped
+    /// is unknown
+    Started,
+    Down {
+        scope: OutageScope,
+        cause: Option<OutageCause>,
+    },
+    Up {
+        scope: OutageScope,
+        /// how long it was down for, if logged
+        downtime: Option<Duration>,
+        cause: Option<OutageCause>,
+    },
+}
+
+/// a parsed log line, along with whether it's from before events were logged
+/// by the library, when "network is down!" also meant both families were
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    time: DateTime<Utc>,
+    legacy: bool,
+    message: Message,
+}
+
+/// parses a line written by `formatter_file`, like
+/// "[2024-01-31 12:00:00.123456 +01:00 network_monitor ERROR] IPv6 is down!"
+fn parse_line(line: &str) -> Option<Entry> {
+    let (header, message) = line.strip_prefix('[')?.split_once("] ")?;
+    let [date, time, offset, target, _level] = header.split(' ').collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let time = DateTime::parse_from_str(&format!("{date} {time} {offset}"), "%Y-%m-%d %H:%M:%S%.f %:z").ok()?;
+    Some(Entry {
+        time: time.to_utc(),
+        legacy: target == "network_monitor",
+        message: parse_message(message)?,
+    })
+}
+
+fn parse_message(message: &str) -> Option<Message> {
+    if message == "logging started" {
+        return Some(Message::Started);
+    }
+    let (message, cause) = match message.strip_suffix(')').and_then(|m| m.rsplit_once(" (outage at the ")) {
+        Some((message, cause)) => (message, Some(parse_cause_display(cause)?)),
+        None => (message, None),
+    };
+    let family = |name: &str| match name {
+        "IPv4" => Some(Family::V4),
+        "IPv6" => Some(Family::V6),
+        _ => None,
+    };
+
+    if let Some(subject) = message.strip_suffix(" is down!") {
+        let scope = match subject {
+            "network" => OutageScope::Network,
+            "DNS" => OutageScope::Dns,
+            family_name => OutageScope::Family(family(family_name)?),
+        };
+        return Some(Message::Down { scope, cause });
+    }
+    if let Some((subject, rest)) = message.split_once(" is back online") {
+        let scope = match subject {
+            "network" => OutageScope::Network,
+            "DNS" => OutageScope::Dns,
+            family_name => OutageScope::Family(family(family_name)?),
+        };
+        let downtime = match rest.trim_end_matches(';') {
+            "" => None,
+            rest => Some(parse_duration(rest.strip_prefix(", and was down for ")?)?),
+        };
+        return Some(Message::Up { scope, downtime, cause });
+    }
+    if let Some((target, family_name)) = message.rsplit_once(" is unreachable over ") {
+        let scope = OutageScope::Target { target: target.to_string(), family: family(family_name)? };
+        return Some(Message::Down { scope, cause });
+    }
+    if let Some((target, rest)) = message.rsplit_once(" is reachable over ") {
+        let (family_name, downtime) = rest.split_once(" again, and was unreachable for ")?;
+        let scope = OutageScope::Target { target: target.to_string(), family: family(family_name)? };
+        return Some(Message::Up { scope, downtime: Some(parse_duration(downtime)?), cause });
+    }
+    None
+}
+
+/// the opposite of [`OutageCause`]'s `Display`
+fn parse_cause_display(cause: &str) -> Option<OutageCause> {
+    match cause {
+        "local network" => Some(OutageCause::Lan),
+        "gateway" => Some(OutageCause::Gateway),
+        "ISP" => Some(OutageCause::Isp),
+        "remote target" => Some(OutageCause::Remote),
+        _ => None,
+    }
+}
+
+/// the opposite of [`format_duration`](crate::event::format_duration)
+fn parse_duration(duration: &str) -> Option<Duration> {
+    let [hours, minutes, seconds] = duration.split(':').collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let secs = hours.parse::<u64>().ok()? * 3600 + minutes.parse::<u64>().ok()? * 60 + seconds.parse::<u64>().ok()?;
+    Some(Duration::from_secs(secs))
+}
+
+/// the outage entries of a log file, which is decompressed if it ends with
+/// ".gz"
+fn read_entries(path: &Path) -> std::io::Result<Vec<Entry>> {
+    let file = File::open(path)?;
+    let reader: Box<dyn Read> = match path.extension().is_some_and(|ext| ext == "gz") {
+        true => Box::new(MultiGzDecoder::new(file)),
+        false => Box::new(file),
+    };
+    let mut reader = BufReader::new(reader);
+    let mut entries = Vec::new();
+    let mut line = Vec::new();
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        if let Some(entry) = parse_line(String::from_utf8_lossy(&line).trim_end()) {
+            entries.push(entry);
+        }
+        line.clear();
+    }
+    Ok(entries)
+}
+
+impl History {
+    /// saves the outages logged in `files` by earlier versions, only going up
+    /// to the first thing already in the history so that logs written since
+    /// then, or importing the same logs twice, don't save anything twice
+    pub fn import_logs(&mut self, files: &[PathBuf]) -> Result<Imported, Error> {
+        let mut entries = Vec::new();
+        for path in files {
+            let read = read_entries(path).map_err(|e| Error(format!("could not read {}: {e}", path.display())))?;
+            entries.extend(read);
+        }
+        entries.sort_by_key(|entry| entry.time);
+
+        let first_saved = self.db.query(
+            "SELECT min(time) FROM (SELECT min(started) AS time FROM outages UNION ALL SELECT min(time) FROM latency)",
+            &[]
+        )?[0][0].as_opt_i64()?;
+        if let Some(first_saved) = first_saved {
+            entries.retain(|entry| entry.time.timestamp_millis() < first_saved);
+        }
+
+        self.db.execute_batch("BEGIN")?;
+        let imported = self.replay(&entries).and_then(|imported| {
+            self.db.execute_batch("COMMIT")?;
+            Ok(imported)
+        });
+        if imported.is_err() {
+            let _ = self.db.execute_batch("ROLLBACK");
+        }
+        imported
+    }
+
+    fn replay(&mut self, entries: &[Entry]) -> Result<Imported, Error> {
+        let mut imported = Imported::default();
+        let mut ongoing: HashMap<OutageScope, DateTime<Utc>> = HashMap::new();
+        let mut last = None;
+        for entry in entries {
+            let time = entry.time;
+            match &entry.message {
+                Message::Started => {
+                    if let Some(last) = last {
+                        for (scope, _) in ongoing.drain() {
+                            self.interrupt(&scope, last)?;
+                        }
+                    }
+                },
+                Message::Down { scope, cause } => {
+                    // before the library, "network is down!" was all that was
+                    // logged when the second family went down
+                    let mut scopes = vec![scope.clone()];
+                    if entry.legacy && *scope == OutageScope::Network {
+                        scopes.extend([Family::V4, Family::V6].map(OutageScope::Family));
+                    }
+                    for scope in scopes {
+                        if let hash_map::Entry::Vacant(vacant) = ongoing.entry(scope) {
+                            self.start(vacant.key().clone(), time, *cause)?;
+                            vacant.insert(time);
+                            imported.outages += 1;
+                        }
+                    }
+                },
+                Message::Up { scope, downtime, cause } => {
+                    // before the library, the network was back as soon as
+                    // either family was without that being logged, and
+                    // "network is back online" was all that was logged when
+                    // both came back at once
+                    let mut scopes = vec![(scope.clone(), *downtime)];
+                    if entry.legacy && matches!(scope, OutageScope::Family(_)) {
+                        scopes.push((OutageScope::Network, None));
+                    }
+                    if entry.legacy && *scope == OutageScope::Network {
+                        scopes.extend([Family::V4, Family::V6].map(|family| (OutageScope::Family(family), None)));
+                    }
+                    for (each, downtime) in scopes {
+                        let start = ongoing.remove(&each);
+                        let downtime = match (downtime, start) {
+                            (Some(downtime), _) => downtime,
+                            (None, Some(start)) => (time - start).to_std().unwrap_or_default(),
+                            (None, None) => {
+                                // only a missing recovery of what was named
+                                // is worth mentioning
+                                if each == *scope {
+                                    imported.skipped += 1;
+                                }
+                                continue;
+                            },
+                        };
+                        if start.is_none() {
+                            imported.outages += 1;
+                        }
+                        self.end(each, time, downtime, *cause)?;
+                    }
+                },
+            }
+            last = Some(time);
+        }
+
+        // the logs end before these did
+        if let Some(last) = last {
+            for (scope, _) in ongoing {
+                self.interrupt(&scope, last)?;
+            }
+        }
+        Ok(imported)
+    }
+
+    /// ends the ongoing outage of `scope` at `at`, as the monitor stopped
+    /// while it lasted
+    fn interrupt(&mut self, scope: &OutageScope, at: DateTime<Utc>) -> Result<(), Error> {
+        let (scope, family, target) = scope.columns();
+        let at = at.timestamp_millis();
+        self.db.execute(
+            "UPDATE outages SET interrupted = 1, ended = max(started, ?), duration_ms = max(started, ?) - started
+            WHERE scope = ? AND family IS ? AND target IS ? AND ended IS NULL",
+            &[at.into(), at.into(), scope.into(), family.into(), target.into()]
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, time::Duration};
+
+    use chrono::{TimeZone, Utc};
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::{parse_message, History, Imported, Message};
+    use crate::{event::{Family, OutageCause}, history::{OutageRecord, OutageScope}};
+
+    #[test]
+    fn parses_messages() {
+        assert_eq!(
+            parse_message("IPv6 is back online, and was down for 01:02:03 (outage at the ISP)"),
+            Some(Message::Up {
+                scope: OutageScope::Family(Family::V6),
+                downtime: Some(Duration::from_secs(3723)),
+                cause: Some(OutageCause::Isp),
+            })
+        );
+        assert_eq!(
+            parse_message("IPv6 is back online;"),
+            Some(Message::Up { scope: OutageScope::Family(Family::V6), downtime: None, cause: None })
+        );
+        assert_eq!(
+            parse_message("1.1.1.1 is unreachable over IPv4"),
+            Some(Message::Down {
+                scope: OutageScope::Target { target: "1.1.1.1".to_string(), family: Family::V4 },
+                cause: None,
+            })
+        );
+        assert_eq!(parse_message("DNS is down!"), Some(Message::Down { scope: OutageScope::Dns, cause: None }));
+        assert_eq!(parse_message("ping to 1.1.1.1 failed 2 times"), None);
+    }
+
+    #[test]
+    fn imports_old_logs() {
+        let dir = std::env::temp_dir().join(format!("network_monitor_import_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.sqlite3");
+        let _ = std::fs::remove_file(&path);
+
+        // the older log was rotated and compressed
+        let mut old = GzEncoder::new(Vec::new(), Compression::default());
+        old.write_all(b"\
+[2024-01-31 12:00:00.000000000 +01:00 network_monitor INFO] logging started
+[2024-01-31 12:00:10.000000000 +01:00 network_monitor ERROR] IPv6 is down!
+[2024-01-31 12:01:10.000000000 +01:00 network_monitor ERROR] network is down!
+").unwrap();
+        std::fs::write(dir.join("network_monitor_r00000.log.gz"), old.finish().unwrap()).unwrap();
+        std::fs::write(dir.join("network_monitor_rCURRENT.log"), "\
+[2024-01-31 12:02:10.000000000 +01:00 network_monitor INFO] network is back online, and was down for 00:01:00
+[2024-01-31 12:05:00.000000000 +01:00 network_monitor ERROR] IPv4 is down!
+[2024-01-31 12:06:00.000000000 +01:00 network_monitor INFO] logging started
+").unwrap();
+
+        let files = super::log_files(&dir).unwrap();
+        assert_eq!(files.len(), 2);
+        let mut history = History::open(&path).unwrap();
+        assert_eq!(history.import_logs(&files).unwrap(), Imported { outages: 4, skipped: 0 });
+        // everything is now older than the history
+        assert_eq!(history.import_logs(&files).unwrap(), Imported::default());
+
+        let at = |h, m, s| Utc.with_ymd_and_hms(2024, 1, 31, h, m, s).unwrap();
+        let outage = |scope, start, end, interrupted| OutageRecord { scope, start, end: Some(end), cause: None, interrupted };
+        let outages = history.outages(at(0, 0, 0), at(23, 0, 0)).unwrap();
+        assert_eq!(outages, vec![
+            outage(OutageScope::Family(Family::V6), at(11, 0, 10), at(11, 2, 10), false),
+            outage(OutageScope::Network, at(11, 1, 10), at(11, 2, 10), false),
+            outage(OutageScope::Family(Family::V4), at(11, 1, 10), at(11, 2, 10), false),
+            outage(OutageScope::Family(Family::V4), at(11, 5, 0), at(11, 5, 0), true),
+        ]);
+    }
+
+    #[test]
+    fn imports_logs_of_the_library() {
+        let dir = std::env::temp_dir().join(format!("network_monitor_import_sink_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.sqlite3");
+        let _ = std::fs::remove_file(&path);
+
+        // each family's recovery is logged before the network's
+        let log = dir.join("network_monitor_rCURRENT.log");
+        std::fs::write(&log, "\
+[2024-01-31 12:00:00.000000000 +01:00 network_monitor INFO] logging started
+[2024-01-31 12:00:10.000000000 +01:00 network_monitor::sink ERROR] IPv4 is down!
+[2024-01-31 12:00:20.000000000 +01:00 network_monitor::sink ERROR] IPv6 is down!
+[2024-01-31 12:00:20.000000000 +01:00 network_monitor::sink ERROR] network is down!
+[2024-01-31 12:01:20.000000000 +01:00 network_monitor::sink INFO] IPv4 is back online, and was down for 00:01:10
+[2024-01-31 12:01:20.000000000 +01:00 network_monitor::sink INFO] network is back online, and was down for 00:01:00
+[2024-01-31 12:02:20.000000000 +01:00 network_monitor::sink INFO] IPv6 is back online, and was down for 00:02:00
+").unwrap();
+
+        let mut history = History::open(&path).unwrap();
+        assert_eq!(history.import_logs(&[log]).unwrap(), Imported { outages: 3, skipped: 0 });
+
+        let at = |h, m, s| Utc.with_ymd_and_hms(2024, 1, 31, h, m, s).unwrap();
+        let outage = |scope, start, end| OutageRecord { scope, start, end: Some(end), cause: None, interrupted: false };
+        let outages = history.outages(at(0, 0, 0), at(23, 0, 0)).unwrap();
+        assert_eq!(outages, vec![
+            outage(OutageScope::Family(Family::V4), at(11, 0, 10), at(11, 1, 20)),
+            outage(OutageScope::Family(Family::V6), at(11, 0, 20), at(11, 2, 20)),
+            outage(OutageScope::Network, at(11, 0, 20), at(11, 1, 20)),
+        ]);
+    }
+}
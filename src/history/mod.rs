@@ -0,0 +1,499 @@
+//! outages and latency summaries saved to an SQLite database, so they
+//! survive restarts and can be queried later
+
+use std::{path::Path, sync::mpsc, time::Duration};
+
+use chrono::{DateTime, TimeZone, Utc};
+use log::warn;
+use tokio::task::JoinHandle;
+
+use crate::{
+    event::{Event, Family, NetworkEvent, OutageCause, TraceReason},
+    sink::Sink
+};
+
+mod import;
+mod sqlite;
+
+pub use import::{log_files, Imported};
+pub use sqlite::Error;
+use sqlite::{Connection, Value};
+
+/// file the history is kept in, inside the output directory
+pub const FILE_NAME: &str = "history.sqlite3";
+
+/// every version of the schema, the database's `user_version` is how many of
+/// them were applied, so new ones must only ever be appended
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE outages (
+        id INTEGER PRIMARY KEY,
+        scope TEXT NOT NULL,
+        family TEXT,
+        target TEXT,
+        started INTEGER NOT NULL,
+        ended INTEGER,
+        duration_ms INTEGER,
+        cause TEXT,
+        interrupted INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX outages_by_start ON outages (started);
+    CREATE TABLE latency (
+        id INTEGER PRIMARY KEY,
+        time INTEGER NOT NULL,
+        target TEXT NOT NULL,
+        family TEXT NOT NULL,
+        probe TEXT NOT NULL,
+        window_ms INTEGER NOT NULL,
+        samples INTEGER NOT NULL,
+        min_us INTEGER NOT NULL,
+        avg_us INTEGER NOT NULL,
+        max_us INTEGER NOT NULL,
+        median_us INTEGER NOT NULL,
+        p95_us INTEGER NOT NULL,
+        p99_us INTEGER NOT NULL,
+        stddev_us INTEGER NOT NULL
+    );
+    CREATE INDEX latency_by_time ON latency (time);
+    CREATE TABLE routes (
+        id INTEGER PRIMARY KEY,
+        outage INTEGER REFERENCES outages (id),
+        time INTEGER NOT NULL,
+        reason TEXT NOT NULL,
+        target TEXT NOT NULL,
+        family TEXT NOT NULL,
+        hops TEXT NOT NULL,
+        last_hop TEXT,
+        reached INTEGER NOT NULL
+    );",
+];
+
+/// what was down
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum OutageScope {
+    Target { target: String, family: Family },
+    Family(Family),
+    Network,
+    Dns,
+}
+
+impl OutageScope {
+    /// the scope's name, family and target as they're stored
+    fn columns(&self) -> (&'static str, Option<&'static str>, Option<&str>) {
+        match self {
+            OutageScope::Target { target, family } => ("target", Some(family_name(*family)), Some(target)),
+            OutageScope::Family(family) => ("family", Some(family_name(*family)), None),
+            OutageScope::Network => ("network", None, None),
+            OutageScope::Dns => ("dns", None, None),
+        }
+    }
+
+    fn from_columns(scope: &str, family: Option<&str>, target: Option<&str>) -> Result<Self, Error> {
+        let family = family.map(parse_family).transpose()?;
+        match (scope, family, target) {
+            ("target", Some(family), Some(target)) => Ok(OutageScope::Target { target: target.to_string(), family }),
+            ("family", Some(family), _) => Ok(OutageScope::Family(family)),
+            ("network", ..) => Ok(OutageScope::Network),
+            ("dns", ..) => Ok(OutageScope::Dns),
+            _ => Err(Error(format!("invalid outage scope {scope:?}"))),
+        }
+    }
+}
+
+/// an outage as it was saved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutageRecord {
+    pub scope: OutageScope,
+    pub start: DateTime<Utc>,
+    /// `None` while the outage is still ongoing
+    pub end: Option<DateTime<Utc>>,
+    pub cause: Option<OutageCause>,
+    /// the monitor stopped during the outage, so `end` is only the last time
+    /// it was known to be running
+    pub interrupted: bool,
+}
+
+impl OutageRecord {
+    pub fn duration(&self) -> Option<Duration> {
+        Some((self.end? - self.start).to_std().unwrap_or_default())
+    }
+}
+
+/// a latency summary as it was saved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyRecord {
+    pub time: DateTime<Utc>,
+    pub target: String,
+    pub family: Family,
+    pub samples: usize,
+    pub median: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+}
+
+/// history of outages and latency, saved from events through a [`Recorder`]
+pub struct History {
+    db: Connection,
+}
+
+impl History {
+    /// opens or creates the database at `path`, bringing its schema up to date
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let history = Self { db: Connection::open(path.as_ref())? };
+        history.db.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")?;
+        history.migrate()?;
+        history.close_interrupted()?;
+        Ok(history)
+    }
+
+    /// opens the existing database at `path` for reading, like while a
+    /// monitor is still saving to it
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let history = Self { db: Connection::open_read_only(path.as_ref())? };
+        let version = history.db.query("PRAGMA user_version", &[])?[0][0].as_i64()? as usize;
+        match version.cmp(&MIGRATIONS.len()) {
+            std::cmp::Ordering::Equal => Ok(history),
+            std::cmp::Ordering::Less => Err(Error("the history is outdated, run the monitor once to update it".to_string())),
+            std::cmp::Ordering::Greater => Err(Error("the history was saved by a newer version".to_string())),
+        }
+    }
+
+    fn migrate(&self) -> Result<(), Error> {
+        let version = self.db.query("PRAGMA user_version", &[])?[0][0].as_i64()? as usize;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            self.db.execute_batch(&format!("BEGIN; {migration}; PRAGMA user_version = {}; COMMIT;", i + 1))
+                .map_err(|e| {
+                    let _ = self.db.execute_batch("ROLLBACK");
+                    Error(format!("migrating the history to version {} failed: {e}", i + 1))
+                })?;
+        }
+        Ok(())
+    }
+
+    /// ends the outages that were still ongoing when the monitor last
+    /// stopped at the last thing it saved
+    fn close_interrupted(&self) -> Result<(), Error> {
+        self.db.execute(
+            "UPDATE outages SET interrupted = 1, ended = max(
+                started,
+                coalesce((SELECT max(time) FROM latency), 0),
+                coalesce((SELECT max(ended) FROM outages), 0),
+                coalesce((SELECT max(started) FROM outages), 0)
+            ) WHERE ended IS NULL",
+            &[]
+        )?;
+        self.db.execute("UPDATE outages SET duration_ms = ended - started WHERE duration_ms IS NULL AND ended IS NOT NULL", &[])?;
+        Ok(())
+    }
+
+    /// saves what `event` says about outages and latency, anything else is
+    /// ignored
+    pub fn record(&mut self, event: &Event) -> Result<(), Error> {
+        let time = event.timestamp;
+        match &event.kind {
+            NetworkEvent::TargetDown { target, family, cause } => {
+                self.start(OutageScope::Target { target: target.clone(), family: *family }, time, *cause)
+            },
+            NetworkEvent::TargetUp { target, family, downtime, cause } => {
+                self.end(OutageScope::Target { target: target.clone(), family: *family }, time, *downtime, *cause)
+            },
+            NetworkEvent::FamilyDown { family, cause } => self.start(OutageScope::Family(*family), time, *cause),
+            NetworkEvent::FamilyUp { family, downtime, cause } => {
+                self.end(OutageScope::Family(*family), time, *downtime, *cause)
+            },
+            NetworkEvent::NetworkDown { cause } => self.start(OutageScope::Network, time, *cause),
+            NetworkEvent::NetworkUp { downtime, cause } => self.end(OutageScope::Network, time, *downtime, *cause),
+            NetworkEvent::DnsDown => self.start(OutageScope::Dns, time, None),
+            NetworkEvent::DnsUp { downtime } => self.end(OutageScope::Dns, time, *downtime, None),
+            NetworkEvent::LatencySummary { target, family, probe, window, stats } => {
+                let us = |d: Duration| d.as_micros() as i64;
+                self.db.execute(
+                    "INSERT INTO latency (
+                        time, target, family, probe, window_ms, samples,
+                        min_us, avg_us, max_us, median_us, p95_us, p99_us, stddev_us
+                    ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                    &[
+                        time.timestamp_millis().into(),
+                        target.as_str().into(),
+                        family_name(*family).into(),
+                        probe.to_string().into(),
+                        (window.as_millis() as i64).into(),
+                        (stats.samples as i64).into(),
+                        us(stats.min).into(),
+                        us(stats.avg).into(),
+                        us(stats.max).into(),
+                        us(stats.median).into(),
+                        us(stats.p95).into(),
+                        us(stats.p99).into(),
+                        us(stats.stddev).into(),
+                    ]
+                )?;
+                Ok(())
+            },
+            NetworkEvent::RouteTraced { target, family, reason, route } => {
+                // the outage being traced is the latest one of the target
+                let scope = OutageScope::Target { target: target.clone(), family: *family };
+                let (scope, family_column, target_column) = scope.columns();
+                let outage = self.db.query(
+                    "SELECT id FROM outages WHERE scope = ? AND family IS ? AND target IS ? ORDER BY started DESC LIMIT 1",
+                    &[scope.into(), family_column.into(), target_column.into()]
+                )?;
+                let outage = outage.first().map(|row| row[0].as_i64()).transpose()?;
+                self.db.execute(
+                    "INSERT INTO routes (outage, time, reason, target, family, hops, last_hop, reached)
+                    VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+                    &[
+                        outage.into(),
+                        time.timestamp_millis().into(),
+                        match reason {
+                            TraceReason::Outage => "outage",
+                            TraceReason::Recovery => "recovery",
+                        }.into(),
+                        target.as_str().into(),
+                        family_name(*family).into(),
+                        route.to_string().into(),
+                        route.last_responsive().and_then(|hop| hop.addr).map(|addr| addr.to_string()).into(),
+                        i64::from(route.reached).into(),
+                    ]
+                )?;
+                Ok(())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn start(&mut self, scope: OutageScope, time: DateTime<Utc>, cause: Option<OutageCause>) -> Result<(), Error> {
+        let (scope, family, target) = scope.columns();
+        self.db.execute(
+            "INSERT INTO outages (scope, family, target, started, cause) VALUES (?, ?, ?, ?, ?)",
+            &[scope.into(), family.into(), target.into(), time.timestamp_millis().into(), cause.map(cause_name).into()]
+        )?;
+        Ok(())
+    }
+
+    /// ends the ongoing outage of `scope`, which started `downtime` before
+    /// `time`, saving it whole if it started before the history did
+    fn end(&mut self, scope: OutageScope, time: DateTime<Utc>, downtime: Duration, cause: Option<OutageCause>) -> Result<(), Error> {
+        let (scope, family, target) = scope.columns();
+        let end = time.timestamp_millis();
+        let duration = downtime.as_millis() as i64;
+        let updated = self.db.execute(
+            "UPDATE outages SET started = ?, ended = ?, duration_ms = ? WHERE id = (
+                SELECT id FROM outages
+                WHERE scope = ? AND family IS ? AND target IS ? AND ended IS NULL
+                ORDER BY started DESC LIMIT 1
+            )",
+            &[(end - duration).into(), end.into(), duration.into(), scope.into(), family.into(), target.into()]
+        )?;
+        if updated == 0 {
+            self.db.execute(
+                "INSERT INTO outages (scope, family, target, started, ended, duration_ms, cause) VALUES (?, ?, ?, ?, ?, ?, ?)",
+                &[
+                    scope.into(),
+                    family.into(),
+                    target.into(),
+                    (end - duration).into(),
+                    end.into(),
+                    duration.into(),
+                    cause.map(cause_name).into(),
+                ]
+            )?;
+        }
+        Ok(())
+    }
+
+    /// outages that overlap the time between `since` and `until`, oldest
+    /// first
+    pub fn outages(&self, since: DateTime<Utc>, until: DateTime<Utc>) -> Result<Vec<OutageRecord>, Error> {
+        let rows = self.db.query(
+            "SELECT scope, family, target, started, ended, cause, interrupted FROM outages
+            WHERE started < ? AND (ended IS NULL OR ended > ?) ORDER BY started, id",
+            &[until.timestamp_millis().into(), since.timestamp_millis().into()]
+        )?;
+        rows.iter().map(|row| {
+            Ok(OutageRecord {
+                scope: OutageScope::from_columns(row[0].as_str()?, row[1].as_opt_str()?, row[2].as_opt_str()?)?,
+                start: timestamp(row[3].as_i64()?)?,
+                end: row[4].as_opt_i64()?.map(timestamp).transpose()?,
+                cause: row[5].as_opt_str()?.map(parse_cause).transpose()?,
+                interrupted: row[6].as_i64()? != 0,
+            })
+        }).collect()
+    }
+
+    /// latency summaries saved between `since` and `until`, oldest first
+    pub fn latency(&self, since: DateTime<Utc>, until: DateTime<Utc>) -> Result<Vec<LatencyRecord>, Error> {
+        let rows = self.db.query(
+            "SELECT time, target, family, samples, median_us, p95_us, p99_us FROM latency
+            WHERE time >= ? AND time < ? ORDER BY time",
+            &[since.timestamp_millis().into(), until.timestamp_millis().into()]
+        )?;
+        let us = |value: &Value| value.as_i64().map(|us| Duration::from_micros(us.max(0) as u64));
+        rows.iter().map(|row| {
+            Ok(LatencyRecord {
+                time: timestamp(row[0].as_i64()?)?,
+                target: row[1].as_str()?.to_string(),
+                family: parse_family(row[2].as_str()?)?,
+                samples: row[3].as_i64()?.max(0) as usize,
+                median: us(&row[4])?,
+                p95: us(&row[5])?,
+                p99: us(&row[6])?,
+            })
+        }).collect()
+    }
+}
+
+/// saves events to a [`History`] from a blocking task, as a [`Sink`], so a
+/// locked database or a slow disk doesn't hold up the monitor
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    events: mpsc::Sender<Event>,
+}
+
+impl Recorder {
+    /// starts saving to `history`, must be called from within a tokio
+    /// runtime
+    ///
+    /// the returned task finishes once the recorder was dropped and every
+    /// event was saved, so it should be awaited before exiting
+    pub fn start(mut history: History) -> (Self, JoinHandle<()>) {
+        let (events, rx) = mpsc::channel::<Event>();
+        let task = tokio::task::spawn_blocking(move || {
+            for event in rx {
+                if let Err(e) = history.record(&event) {
+                    warn!("could not save to the history: {e}");
+                }
+            }
+        });
+        (Self { events }, task)
+    }
+}
+
+impl Sink for Recorder {
+    fn handle(&mut self, event: &Event) {
+        let saved = event.kind.is_transition()
+            || matches!(event.kind, NetworkEvent::LatencySummary { .. } | NetworkEvent::RouteTraced { .. });
+        if saved {
+            // the task only stops once this is dropped
+            let _ = self.events.send(event.clone());
+        }
+    }
+}
+
+fn timestamp(ms: i64) -> Result<DateTime<Utc>, Error> {
+    Utc.timestamp_millis_opt(ms).single().ok_or_else(|| Error(format!("invalid timestamp {ms}")))
+}
+
+fn family_name(family: Family) -> &'static str {
+    match family {
+        Family::V4 => "ipv4",
+        Family::V6 => "ipv6",
+    }
+}
+
+fn parse_family(name: &str) -> Result<Family, Error> {
+    match name {
+        "ipv4" => Ok(Family::V4),
+        "ipv6" => Ok(Family::V6),
+        _ => Err(Error(format!("invalid family {name:?}"))),
+    }
+}
+
+fn cause_name(cause: OutageCause) -> &'static str {
+    match cause {
+        OutageCause::Lan => "lan",
+        OutageCause::Gateway => "gateway",
+        OutageCause::Isp => "isp",
+        OutageCause::Remote => "remote",
+    }
+}
+
+fn parse_cause(name: &str) -> Result<OutageCause, Error> {
+    match name {
+        "lan" => Ok(OutageCause::Lan),
+        "gateway" => Ok(OutageCause::Gateway),
+        "isp" => Ok(OutageCause::Isp),
+        "remote" => Ok(OutageCause::Remote),
+        _ => Err(Error(format!("invalid outage cause {name:?}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+
+    use super::{History, OutageRecord, OutageScope, Recorder, MIGRATIONS};
+    use crate::{event::{Event, Family, NetworkEvent, OutageCause}, sink::Sink};
+
+    fn at(secs: i64, kind: NetworkEvent) -> Event {
+        Event { timestamp: Utc.timestamp_opt(secs, 0).unwrap(), kind }
+    }
+
+    #[test]
+    fn saves_outages() {
+        let dir = std::env::temp_dir().join(format!("network_monitor_history_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("saves_outages.sqlite3");
+        let _ = std::fs::remove_file(&path);
+
+        let mut history = History::open(&path).unwrap();
+        let cause = Some(OutageCause::Isp);
+        history.record(&at(1000, NetworkEvent::FamilyDown { family: Family::V6, cause })).unwrap();
+        history.record(&at(1100, NetworkEvent::FamilyUp { family: Family::V6, downtime: Duration::from_secs(120), cause })).unwrap();
+        history.record(&at(2000, NetworkEvent::DnsDown)).unwrap();
+        drop(history);
+
+        // reopening doesn't migrate again, and ends outages that were
+        // ongoing when the monitor stopped
+        let history = History::open(&path).unwrap();
+        let version = history.db.query("PRAGMA user_version", &[]).unwrap()[0][0].as_i64().unwrap();
+        assert_eq!(version as usize, MIGRATIONS.len());
+
+        let outages = history.outages(Utc.timestamp_opt(0, 0).unwrap(), Utc.timestamp_opt(5000, 0).unwrap()).unwrap();
+        assert_eq!(outages, vec![
+            OutageRecord {
+                scope: OutageScope::Family(Family::V6),
+                // the start is corrected to when the first probe failed
+                start: Utc.timestamp_opt(980, 0).unwrap(),
+                end: Some(Utc.timestamp_opt(1100, 0).unwrap()),
+                cause,
+                interrupted: false,
+            },
+            OutageRecord {
+                scope: OutageScope::Dns,
+                start: Utc.timestamp_opt(2000, 0).unwrap(),
+                end: Some(Utc.timestamp_opt(2000, 0).unwrap()),
+                cause: None,
+                interrupted: true,
+            },
+        ]);
+        assert_eq!(outages[0].duration(), Some(Duration::from_secs(120)));
+
+        drop(history);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn records_in_the_background() {
+        let dir = std::env::temp_dir().join(format!("network_monitor_recorder_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.sqlite3");
+        let _ = std::fs::remove_file(&path);
+
+        let (mut recorder, task) = Recorder::start(History::open(&path).unwrap());
+        recorder.handle(&at(1000, NetworkEvent::NetworkDown { cause: None }));
+        recorder.handle(&at(1060, NetworkEvent::NetworkUp { downtime: Duration::from_secs(60), cause: None }));
+        drop(recorder);
+        task.await.unwrap();
+
+        let history = History::open_read_only(&path).unwrap();
+        let outages = history.outages(Utc.timestamp_opt(0, 0).unwrap(), Utc.timestamp_opt(5000, 0).unwrap()).unwrap();
+        assert_eq!(outages.len(), 1);
+        assert_eq!(outages[0].scope, OutageScope::Network);
+        assert_eq!(outages[0].duration(), Some(Duration::from_secs(60)));
+
+        drop(history);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,161 @@
+//! the few parts of SQLite the history needs, with SQLite built in through
+//! rusqlite so nothing has to be installed for it
+
+use std::{fmt::Display, path::Path, time::Duration};
+
+use rusqlite::{
+    params_from_iter,
+    types::{ToSqlOutput, ValueRef},
+    OpenFlags,
+    ToSql
+};
+
+/// an error reported by SQLite, or a value that didn't have the expected type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error(pub(super) String);
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error(e.to_string())
+    }
+}
+
+/// a single SQLite value
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+}
+
+impl Value {
+    pub fn as_i64(&self) -> Result<i64, Error> {
+        match self {
+            Value::Integer(i) => Ok(*i),
+            other => Err(Error(format!("expected an integer, got {other:?}"))),
+        }
+    }
+
+    pub fn as_opt_i64(&self) -> Result<Option<i64>, Error> {
+        match self {
+            Value::Null => Ok(None),
+            other => other.as_i64().map(Some),
+        }
+    }
+
+    pub fn as_str(&self) -> Result<&str, Error> {
+        match self {
+            Value::Text(s) => Ok(s),
+            other => Err(Error(format!("expected text, got {other:?}"))),
+        }
+    }
+
+    pub fn as_opt_str(&self) -> Result<Option<&str>, Error> {
+        match self {
+            Value::Null => Ok(None),
+            other => other.as_str().map(Some),
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Integer(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Real(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Text(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Text(value)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+impl From<ValueRef<'_>> for Value {
+    fn from(value: ValueRef<'_>) -> Self {
+        match value {
+            ValueRef::Integer(i) => Value::Integer(i),
+            ValueRef::Real(r) => Value::Real(r),
+            ValueRef::Text(text) => Value::Text(String::from_utf8_lossy(text).into_owned()),
+            // blobs aren't used
+            ValueRef::Null | ValueRef::Blob(_) => Value::Null,
+        }
+    }
+}
+
+impl ToSql for Value {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::Borrowed(match self {
+            Value::Null => ValueRef::Null,
+            Value::Integer(i) => ValueRef::Integer(*i),
+            Value::Real(r) => ValueRef::Real(*r),
+            Value::Text(s) => ValueRef::Text(s.as_bytes()),
+        }))
+    }
+}
+
+/// an open database
+pub(crate) struct Connection(rusqlite::Connection);
+
+impl Connection {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        Self::open_with(path, OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE)
+    }
+
+    /// opens an existing database without being able to change it
+    pub fn open_read_only(path: &Path) -> Result<Self, Error> {
+        Self::open_with(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+    }
+
+    fn open_with(path: &Path, flags: OpenFlags) -> Result<Self, Error> {
+        let db = rusqlite::Connection::open_with_flags(path, flags)?;
+        db.busy_timeout(Duration::from_secs(5))?;
+        Ok(Self(db))
+    }
+
+    /// runs one or more statements without parameters
+    pub fn execute_batch(&self, sql: &str) -> Result<(), Error> {
+        Ok(self.0.execute_batch(sql)?)
+    }
+
+    /// runs a single statement, returning how many rows it changed
+    pub fn execute(&self, sql: &str, params: &[Value]) -> Result<usize, Error> {
+        Ok(self.0.execute(sql, params_from_iter(params))?)
+    }
+
+    /// runs a single statement, returning every row it produced
+    pub fn query(&self, sql: &str, params: &[Value]) -> Result<Vec<Vec<Value>>, Error> {
+        let mut statement = self.0.prepare(sql)?;
+        let columns = statement.column_count();
+        let rows = statement.query_map(params_from_iter(params), |row| {
+            (0..columns).map(|i| row.get_ref(i).map(Value::from)).collect()
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+}
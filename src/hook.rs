@@ -0,0 +1,400 @@
+//! runs external commands when the network, a family or a target goes down
+//! and comes back, like power cycling a modem
+
+use std::{collections::HashMap, fmt::Display, process::Stdio, time::Duration};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{debug, info, warn};
+use tokio::{process::Command, select, sync::mpsc, time::Instant};
+
+use crate::{
+    event::{format_duration, Event, Family, NetworkEvent, OutageCause},
+    sink::Sink
+};
+
+/// what a hook is run for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Scope {
+    Network,
+    Family(Family),
+    /// a target by its hostname, which is down from when it goes down over
+    /// either family until it's back over both
+    Target(String),
+}
+
+impl Scope {
+    fn matches(&self, outage: &Outage) -> bool {
+        match (self, outage) {
+            (Scope::Network, Outage::Network) => true,
+            (Scope::Family(family), Outage::Family(other)) => family == other,
+            (Scope::Target(target), Outage::Target(other)) => target == other,
+            _ => false,
+        }
+    }
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scope::Network => write!(f, "network"),
+            Scope::Family(family) => write!(f, "{family}"),
+            Scope::Target(target) => write!(f, "{target}"),
+        }
+    }
+}
+
+/// a shell command run for a scope
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hook {
+    pub scope: Scope,
+    pub command: String,
+}
+
+/// hooks along with when they're run
+#[derive(Debug, Clone)]
+pub struct HookSettings {
+    /// run once an outage has lasted for `min_downtime`
+    pub on_down: Vec<Hook>,
+    /// run once an outage that lasted for `min_downtime` ends
+    pub on_up: Vec<Hook>,
+    /// how long commands may run for before they're killed
+    pub timeout: Duration,
+    /// how long outages must last for hooks to be run, so short ones are
+    /// ignored
+    pub min_downtime: Duration,
+    /// least time between two runs of the same hook, so a command that
+    /// restarts something doesn't end up doing it in a loop
+    pub cooldown: Duration,
+}
+
+/// something that can go down, targets over both families at once
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Outage {
+    Network,
+    Family(Family),
+    Target(String),
+}
+
+/// an outage that's still going on
+struct Ongoing {
+    started: DateTime<Utc>,
+    /// the family that went down first, if it's not the whole network
+    family: Option<Family>,
+    cause: Option<OutageCause>,
+    /// families a target is still down over
+    down: Vec<Family>,
+    /// whether the on-down hooks were run
+    fired: bool,
+}
+
+/// what an event means for hooks, if anything
+enum Transition {
+    Down(Outage, Option<Family>, Option<OutageCause>),
+    Up(Outage, Option<Family>, Duration),
+}
+
+impl Transition {
+    fn of(kind: &NetworkEvent) -> Option<Self> {
+        Some(match kind {
+            NetworkEvent::NetworkDown { cause } => Transition::Down(Outage::Network, None, *cause),
+            NetworkEvent::NetworkUp { downtime, .. } => Transition::Up(Outage::Network, None, *downtime),
+            NetworkEvent::FamilyDown { family, cause } => {
+                Transition::Down(Outage::Family(*family), Some(*family), *cause)
+            },
+            NetworkEvent::FamilyUp { family, downtime, .. } => {
+                Transition::Up(Outage::Family(*family), Some(*family), *downtime)
+            },
+            NetworkEvent::TargetDown { target, family, cause } => {
+                Transition::Down(Outage::Target(target.clone()), Some(*family), *cause)
+            },
+            NetworkEvent::TargetUp { target, family, downtime, .. } => {
+                Transition::Up(Outage::Target(target.clone()), Some(*family), *downtime)
+            },
+            _ => return None,
+        })
+    }
+}
+
+/// a hook along with when it was last run
+struct Runner {
+    hook: Hook,
+    /// "on-down" or "on-up"
+    kind: &'static str,
+    last_run: Option<Instant>,
+}
+
+impl Runner {
+    /// runs the hook in the background, unless it's in its cooldown
+    fn run(&mut self, cooldown: Duration, timeout: Duration, env: Vec<(&'static str, String)>) {
+        let label = format!("{} hook for {}", self.kind, self.hook.scope);
+        if let Some(last_run) = self.last_run.filter(|last_run| last_run.elapsed() < cooldown) {
+            info!("skipping the {label}, as it ran {} ago", format_duration(last_run.elapsed()));
+            return;
+        }
+        self.last_run = Some(Instant::now());
+        info!("running the {label}");
+        tokio::spawn(execute(label, self.hook.command.clone(), env, timeout));
+    }
+}
+
+async fn execute(label: String, command: String, env: Vec<(&'static str, String)>, timeout: Duration) {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .envs(env)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    match tokio::time::timeout(timeout, output).await {
+        Ok(Ok(output)) if output.status.success() => debug!("the {label} finished"),
+        Ok(Ok(output)) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            match stderr.trim() {
+                "" => warn!("the {label} failed with {}", output.status),
+                stderr => warn!("the {label} failed with {}: {stderr}", output.status),
+            }
+        },
+        Ok(Err(e)) => warn!("could not run the {label}: {e}"),
+        Err(_) => warn!("the {label} was killed after running for {timeout:?}"),
+    }
+}
+
+/// runs commands when outages begin and end, as a [`Sink`]
+///
+/// commands are run with `sh -c`, with the outage described by environment
+/// variables: `NETWORK_MONITOR_EVENT` is "down" or "up",
+/// `NETWORK_MONITOR_SCOPE` is "network", "IPv4", "IPv6" or a hostname,
+/// `NETWORK_MONITOR_FAMILY` and `NETWORK_MONITOR_TARGET` are set when they
+/// apply, the family being the one a target went down over first,
+/// `NETWORK_MONITOR_STARTED_AT` is when the outage began as an RFC 3339
+/// timestamp, `NETWORK_MONITOR_DURATION` is how long it lasted in seconds so
+/// far, and `NETWORK_MONITOR_CAUSE` is where it is if outages are localized
+#[derive(Debug, Clone)]
+pub struct Hooks {
+    events: mpsc::UnboundedSender<Event>,
+}
+
+impl Hooks {
+    /// starts waiting for outages, must be called from within a tokio runtime
+    pub fn start(settings: HookSettings) -> Self {
+        let (events, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run(settings, rx));
+        Self { events }
+    }
+}
+
+impl Sink for Hooks {
+    fn handle(&mut self, event: &Event) {
+        if Transition::of(&event.kind).is_some() {
+            // the task only stops once this is dropped
+            let _ = self.events.send(event.clone());
+        }
+    }
+}
+
+/// the environment variables a hook is run with
+fn environment(
+    event: &'static str,
+    scope: &Scope,
+    outage: &Outage,
+    state: &Ongoing,
+    duration: Duration
+) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("NETWORK_MONITOR_EVENT", event.to_string()),
+        ("NETWORK_MONITOR_SCOPE", scope.to_string()),
+        ("NETWORK_MONITOR_STARTED_AT", state.started.to_rfc3339_opts(SecondsFormat::Secs, true)),
+        ("NETWORK_MONITOR_DURATION", duration.as_secs().to_string()),
+    ];
+    if let Some(family) = state.family {
+        env.push(("NETWORK_MONITOR_FAMILY", family.to_string()));
+    }
+    if let Outage::Target(target) = outage {
+        env.push(("NETWORK_MONITOR_TARGET", target.clone()));
+    }
+    if let Some(cause) = state.cause {
+        env.push(("NETWORK_MONITOR_CAUSE", cause.to_string()));
+    }
+    env
+}
+
+async fn run(settings: HookSettings, mut events: mpsc::UnboundedReceiver<Event>) {
+    let runners = |hooks: &[Hook], kind| -> Vec<Runner> {
+        hooks.iter().map(|hook| Runner { hook: hook.clone(), kind, last_run: None }).collect()
+    };
+    let mut on_down = runners(&settings.on_down, "on-down");
+    let mut on_up = runners(&settings.on_up, "on-up");
+    let mut ongoing: HashMap<Outage, Ongoing> = HashMap::new();
+
+    loop {
+        // when the next outage will have lasted long enough for its hooks
+        let next = ongoing.values()
+            .filter(|outage| !outage.fired)
+            .map(|outage| outage.started + settings.min_downtime)
+            .min()
+            .map(|at| Instant::now() + (at - Utc::now()).to_std().unwrap_or_default());
+
+        select! {
+            event = events.recv() => {
+                let Some(event) = event else {
+                    return;
+                };
+                match Transition::of(&event.kind) {
+                    Some(Transition::Down(outage, family, cause)) => {
+                        let state = ongoing.entry(outage).or_insert(Ongoing {
+                            started: event.timestamp,
+                            family,
+                            cause,
+                            down: Vec::new(),
+                            fired: false,
+                        });
+                        if let Some(family) = family.filter(|family| !state.down.contains(family)) {
+                            state.down.push(family);
+                        }
+                    },
+                    Some(Transition::Up(outage, family, downtime)) => {
+                        let Some(state) = ongoing.get_mut(&outage) else {
+                            continue;
+                        };
+                        // targets are only back once they are over every family
+                        state.down.retain(|down| Some(*down) != family);
+                        if !state.down.is_empty() {
+                            continue;
+                        }
+                        let Some(ended) = ongoing.remove(&outage) else {
+                            continue;
+                        };
+                        if !ended.fired {
+                            continue;
+                        }
+                        let downtime = downtime.max((event.timestamp - ended.started).to_std().unwrap_or_default());
+                        for runner in on_up.iter_mut().filter(|runner| runner.hook.scope.matches(&outage)) {
+                            let env = environment("up", &runner.hook.scope, &outage, &ended, downtime);
+                            runner.run(settings.cooldown, settings.timeout, env);
+                        }
+                    },
+                    None => {},
+                }
+            },
+            _ = tokio::time::sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {},
+        }
+
+        let now = Utc::now();
+        for (outage, state) in &mut ongoing {
+            let duration = (now - state.started).to_std().unwrap_or_default();
+            if state.fired || duration < settings.min_downtime {
+                continue;
+            }
+            state.fired = true;
+            for runner in on_down.iter_mut().filter(|runner| runner.hook.scope.matches(outage)) {
+                let env = environment("down", &runner.hook.scope, outage, state, duration);
+                runner.run(settings.cooldown, settings.timeout, env);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+
+    use super::{environment, Hook, HookSettings, Hooks, Ongoing, Outage, Scope};
+    use crate::{event::{Event, Family, NetworkEvent}, sink::Sink};
+
+    #[test]
+    fn describes_outages() {
+        let outage = Outage::Target("example.com".to_string());
+        let scope = Scope::Target("example.com".to_string());
+        assert!(scope.matches(&outage));
+        assert!(!Scope::Family(Family::V6).matches(&outage));
+        let state = Ongoing {
+            started: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+            family: Some(Family::V6),
+            cause: None,
+            down: Vec::new(),
+            fired: true,
+        };
+        assert_eq!(environment("up", &scope, &outage, &state, Duration::from_secs(301)), [
+            ("NETWORK_MONITOR_EVENT", "up".to_string()),
+            ("NETWORK_MONITOR_SCOPE", "example.com".to_string()),
+            ("NETWORK_MONITOR_STARTED_AT", "2023-11-14T22:13:20Z".to_string()),
+            ("NETWORK_MONITOR_DURATION", "301".to_string()),
+            ("NETWORK_MONITOR_FAMILY", "IPv6".to_string()),
+            ("NETWORK_MONITOR_TARGET", "example.com".to_string()),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn runs_after_min_downtime() {
+        let path = std::env::temp_dir().join(format!("network_monitor_hook_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut hooks = Hooks::start(HookSettings {
+            on_down: vec![Hook {
+                scope: Scope::Family(Family::V6),
+                command: format!("echo \"$NETWORK_MONITOR_EVENT $NETWORK_MONITOR_SCOPE\" >> {}", path.display()),
+            }],
+            on_up: vec![Hook {
+                scope: Scope::Family(Family::V6),
+                command: format!("echo \"$NETWORK_MONITOR_EVENT $NETWORK_MONITOR_DURATION\" >> {}", path.display()),
+            }],
+            timeout: Duration::from_secs(5),
+            min_downtime: Duration::from_millis(200),
+            cooldown: Duration::ZERO,
+        });
+
+        // too short for the hooks
+        hooks.handle(&Event::now(NetworkEvent::FamilyDown { family: Family::V6, cause: None }));
+        hooks.handle(&Event::now(NetworkEvent::FamilyUp { family: Family::V6, downtime: Duration::ZERO, cause: None }));
+        // the network going down isn't in the hooks' scope
+        hooks.handle(&Event::now(NetworkEvent::NetworkDown { cause: None }));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!path.exists());
+
+        hooks.handle(&Event::now(NetworkEvent::FamilyDown { family: Family::V6, cause: None }));
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        hooks.handle(&Event::now(NetworkEvent::FamilyUp { family: Family::V6, downtime: Duration::from_secs(1), cause: None }));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let ran = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(ran, "down IPv6\nup 1\n");
+    }
+
+    #[tokio::test]
+    async fn runs_once_per_target() {
+        let path = std::env::temp_dir().join(format!("network_monitor_hook_target_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let hook = Hook {
+            scope: Scope::Target("example.com".to_string()),
+            command: format!("echo \"$NETWORK_MONITOR_EVENT $NETWORK_MONITOR_FAMILY\" >> {}", path.display()),
+        };
+        let mut hooks = Hooks::start(HookSettings {
+            on_down: vec![hook.clone()],
+            on_up: vec![hook],
+            timeout: Duration::from_secs(5),
+            min_downtime: Duration::ZERO,
+            cooldown: Duration::ZERO,
+        });
+        let down = |family| Event::now(NetworkEvent::TargetDown { target: "example.com".to_string(), family, cause: None });
+        let up = |family| Event::now(NetworkEvent::TargetUp {
+            target: "example.com".to_string(),
+            family,
+            downtime: Duration::from_secs(1),
+            cause: None,
+        });
+
+        // the target is down until it's back over both families
+        hooks.handle(&down(Family::V4));
+        hooks.handle(&down(Family::V6));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        hooks.handle(&up(Family::V4));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "down IPv4\n");
+        hooks.handle(&up(Family::V6));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let ran = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(ran, "down IPv4\nup IPv4\n");
+    }
+}
@@ -0,0 +1,96 @@
+//! just enough JSON writing for logs and notifications
+
+use std::fmt::Write;
+
+/// `value` as a JSON string, quotes included
+pub fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            },
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// `value` as a JSON number, `null` if it's infinite or NaN, which JSON
+/// can't represent
+pub fn number(value: f64) -> String {
+    match value.is_finite() {
+        true => value.to_string(),
+        false => "null".to_string(),
+    }
+}
+
+/// a JSON object built one field at a time, fields aren't checked for
+/// duplicates
+#[derive(Debug, Clone, Default)]
+pub struct Object {
+    fields: Vec<(String, String)>,
+}
+
+impl Object {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// adds a field whose value is already encoded as JSON
+    pub fn raw(&mut self, key: &str, json: impl Into<String>) -> &mut Self {
+        self.fields.push((key.to_string(), json.into()));
+        self
+    }
+
+    pub fn string(&mut self, key: &str, value: &str) -> &mut Self {
+        self.raw(key, quote(value))
+    }
+
+    pub fn number(&mut self, key: &str, value: f64) -> &mut Self {
+        self.raw(key, number(value))
+    }
+
+    pub fn bool(&mut self, key: &str, value: bool) -> &mut Self {
+        self.raw(key, value.to_string())
+    }
+
+    /// the object on a single line
+    pub fn finish(&self) -> String {
+        let mut json = String::from("{");
+        for (i, (key, value)) in self.fields.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str(&quote(key));
+            json.push(':');
+            json.push_str(value);
+        }
+        json.push('}');
+        json
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{quote, Object};
+
+    #[test]
+    fn writes_objects() {
+        assert_eq!(quote("a \"b\"\n\\\u{1}"), r#""a \"b\"\n\\\u0001""#);
+        let json = Object::new()
+            .string("message", "IPv6 is down!")
+            .number("rtt_ms", 1.5)
+            .number("nan", f64::NAN)
+            .bool("ok", false)
+            .finish();
+        assert_eq!(json, r#"{"message":"IPv6 is down!","rtt_ms":1.5,"nan":null,"ok":false}"#);
+    }
+}
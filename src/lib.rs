@@ -0,0 +1,43 @@
+//! monitors network downtime of IPv4 and IPv6 independently
+//!
+//! ```no_run
+//! # async fn run(target: network_monitor::Target) {
+//! use futures_util::StreamExt;
+//! use network_monitor::{LogSink, Monitor};
+//!
+//! let (handle, mut events) = Monitor::builder()
+//!     .target(target)
+//!     .sink(LogSink)
+//!     .start()
+//!     .unwrap();
+//!
+//! while let Some(event) = events.next().await {
+//!     println!("{event:?}");
+//! }
+//! # drop(handle);
+//! # }
+//! ```
+
+pub mod event;
+pub mod export;
+pub mod history;
+pub mod hook;
+pub mod json;
+pub mod metrics;
+pub mod monitor;
+pub mod probe;
+pub mod report;
+pub mod resolve;
+pub mod sink;
+pub mod state;
+pub mod stats;
+mod target;
+pub mod webhook;
+
+pub use event::{Event, Family, NetworkEvent, OutageCause, TraceReason};
+pub use monitor::{EventStream, Monitor, MonitorBuilder, MonitorHandle};
+pub use probe::{Probe, ProbeKind};
+pub use resolve::{DnsProtocol, Nameservers, ResolverSettings};
+pub use sink::{LogSink, Sink};
+pub use stats::RttStats;
+pub use target::Target;
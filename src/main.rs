@@ -1,15 +1,38 @@
-use std::{io::Write, net::IpAddr, time::{Duration, Instant}};
+use std::{io::Write, net::SocketAddr, path::{Path, PathBuf}, time::Duration};
 
 use flexi_logger::{style, Cleanup, Criterion, DeferredNow, FileSpec, LogSpecification, Naming};
-use futures_util::{FutureExt, StreamExt};
-use log::{debug, error, info, trace, warn, Record};
+use futures_util::StreamExt;
+use chrono::SecondsFormat;
+use log::{debug, error, info, warn, kv::{self, Key, Value, VisitSource}, trace, Record};
+use network_monitor::{
+    history::{self, History, Recorder},
+    export::Exporter,
+    hook::{HookSettings, Hooks},
+    json,
+    metrics::Metrics,
+    probe::{Burst, PathMtuProbe},
+    report,
+    resolve::resolve_target,
+    state::DegradedThresholds,
+    webhook::Notifier,
+    DnsProtocol,
+    Family,
+    LogSink,
+    Monitor,
+    Nameservers,
+    ResolverSettings
+};
 use once_cell::sync::Lazy;
 use tokio::select;
 
-use crate::cli::ARGS;
+use crate::cli::{CommandArg, ImportArgs, LogFormatArg, ReportArgs, ARGS};
 
 mod cli;
 
+/// how long to wait for samples, notifications and events kept in memory to
+/// be sent or saved when exiting
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(15);
+
 fn formatter_stderr(write: &mut dyn Write, now: &mut DeferredNow, record: &Record) -> std::io::Result<()>{
     write!(
         write,
@@ -32,6 +55,41 @@ fn formatter_file(write: &mut dyn Write, now: &mut DeferredNow, record: &Record)
     )
 }
 
+/// adds the key-values of a record to a JSON object, keeping numbers and
+/// booleans as they are
+struct JsonFields<'a>(&'a mut json::Object);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let key = key.as_str();
+        if let Some(b) = value.to_bool() {
+            self.0.bool(key, b);
+        } else if let Some(n) = value.to_u64() {
+            self.0.raw(key, n.to_string());
+        } else if let Some(n) = value.to_i64() {
+            self.0.raw(key, n.to_string());
+        } else if let Some(n) = value.to_f64() {
+            self.0.number(key, n);
+        } else {
+            self.0.string(key, &value.to_string());
+        }
+        Ok(())
+    }
+}
+
+/// writes a record as a single line JSON object, for both stderr and files
+fn formatter_json(write: &mut dyn Write, now: &mut DeferredNow, record: &Record) -> std::io::Result<()> {
+    let mut object = json::Object::new();
+    object
+        .string("timestamp", &now.now().to_utc().to_rfc3339_opts(SecondsFormat::Micros, true))
+        .string("level", record.level().as_str())
+        .string("target", record.target())
+        .string("message", &record.args().to_string());
+    // only fails if the visitor does
+    let _ = record.key_values().visit(&mut JsonFields(&mut object));
+    write!(write, "{}", object.finish())
+}
+
 #[cfg(unix)]
 async fn watch_sigs(tx: tokio::sync::watch::Sender<bool>) {
     use tokio::signal::unix::SignalKind;
@@ -62,44 +120,78 @@ async fn watch_sigs(tx: tokio::sync::watch::Sender<bool>) {
     trace!("sent cancellation signal");
 }
 
-async fn monitor_ip(addr: IpAddr, is_error: tokio::sync::mpsc::Sender<bool>) {
-    let payload: [u8; 256] = core::array::from_fn(|i| i as u8);
-    let mut errors: u32 = 0;
-
-    let mut interval = tokio::time::interval(Duration::from_secs(ARGS.interval));
-    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
-
-    loop {
-        let ping = surge_ping::ping(addr, &payload).await;
-
-        match ping {
-            Ok(ping) => {
-                errors = 0;
-                debug!("ping to {addr} successful");
-                trace!("{ping:?}");
-            },
-            Err(e) => {
-                errors += 1;
-                warn!("ping to {addr} failed {errors} times");
-                debug!("{e:?}");
-            },
+/// prints the summaries of each period of the report to stdout
+fn report(args: &ReportArgs, dir: &Path) -> Result<(), String> {
+    let path = dir.join(history::FILE_NAME);
+    let error = |e: history::Error| format!("could not read the history in {}: {e}", path.display());
+    let history = History::open_read_only(&path).map_err(error)?;
+    let (since, until) = args.range();
+    let outages = history.outages(since.to_utc(), until.to_utc()).map_err(error)?;
+    let latency = history.latency(since.to_utc(), until.to_utc()).map_err(error)?;
+
+    let periods = match args.by {
+        Some(by) => report::periods(since, until, by.into()),
+        None => vec![(since, until)],
+    };
+    for (i, (start, end)) in periods.into_iter().enumerate() {
+        if i > 0 {
+            println!();
         }
-
-        if errors >= ARGS.hysteresis {
-            is_error.send(true).await.expect("receiver died");
-        } else {
-            is_error.send(false).await.expect("receiver died");
+        println!("{} to {}", start.format("%Y-%m-%d %H:%M"), end.format("%Y-%m-%d %H:%M"));
+        let summaries = report::summarize(&outages, &latency, start.to_utc(), end.to_utc());
+        if summaries.is_empty() {
+            println!("nothing was saved");
+        }
+        for summary in summaries {
+            println!("{summary}");
         }
+    }
+    Ok(())
+}
 
-        interval.tick().await;
+/// saves the outages in old logs to the history, printing how many there were
+fn import(args: &ImportArgs, dir: &Path) -> Result<(), String> {
+    let mut files = Vec::new();
+    let paths = match args.paths.is_empty() {
+        true => vec![dir],
+        false => args.paths.iter().map(PathBuf::as_path).collect(),
+    };
+    for path in paths {
+        match path.is_dir() {
+            true => files.extend(
+                history::log_files(path)
+                    .map_err(|e| format!("could not list {}: {e}", path.display()))?
+            ),
+            false => files.push(path.to_path_buf()),
+        }
     }
 
+    let path = dir.join(history::FILE_NAME);
+    let imported = History::open(&path)
+        .and_then(|mut history| history.import_logs(&files))
+        .map_err(|e| format!("could not import into the history in {}: {e}", path.display()))?;
+    println!("imported {} outages from {} files", imported.outages, files.len());
+    if imported.skipped > 0 {
+        println!("skipped {} recoveries from outages that began before the oldest log", imported.skipped);
+    }
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() {
-    // gotta init this outside of the async runtime
-    tokio::task::spawn_blocking(|| Lazy::force(&ARGS)).await.unwrap();
+    Lazy::force(&ARGS);
+
+    if let (Some(command), Some(dir)) = (&ARGS.command, &ARGS.out_dir) {
+        let result = match command {
+            CommandArg::Report(args) => report(args, dir),
+            CommandArg::Import(args) => import(args, dir),
+        };
+        if let Err(e) = result {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
 
     let level = match ARGS.verbosity {
         0 => LogSpecification::info(),
@@ -113,6 +205,10 @@ async fn main() {
         .rotate(Criterion::Size(1024 * 1024 * 5), Naming::Numbers, Cleanup::KeepCompressedFiles(30))
         .format_for_stderr(formatter_stderr)
         .format_for_files(formatter_file);
+    let logger = match ARGS.log_format {
+        LogFormatArg::Text => logger,
+        LogFormatArg::Json => logger.format_for_stderr(formatter_json).format_for_files(formatter_json),
+    };
 
     if let Some(dir) = &ARGS.out_dir {
         logger.log_to_file(
@@ -127,7 +223,46 @@ async fn main() {
 
     debug!("{:#?}", *ARGS);
 
-    info!("monitoring started, pinging {} and {} every {}s", ARGS.hostname.0, ARGS.hostname.1, ARGS.interval);
+    let resolver_settings = ResolverSettings {
+        nameservers: if ARGS.nameservers.is_empty() {
+            ARGS.resolver.into()
+        } else {
+            let protocol = DnsProtocol::from(ARGS.dns_protocol);
+            Nameservers::Custom(
+                ARGS.nameservers.iter()
+                    .map(|(ip, port)| SocketAddr::new(*ip, port.unwrap_or(protocol.default_port())))
+                    .collect()
+            )
+        },
+        protocol: ARGS.dns_protocol.into(),
+        tls_name: ARGS.dns_tls_name.clone(),
+    };
+    let resolver = match resolver_settings.build() {
+        Ok(r) => r,
+        Err(e) => {
+            error!("{e}");
+            std::process::exit(1);
+        },
+    };
+
+    let mut targets = Vec::new();
+    for arg in &ARGS.hostnames {
+        match resolve_target(&resolver, &arg.host).await {
+            Ok(target) => {
+                let target = target.with_probe(arg.probe.clone()).with_marking(arg.marking);
+                info!("monitoring {target} using {}", target.probe);
+                targets.push(target);
+            },
+            Err(e) => {
+                error!("could not resolve {}: {e}", arg.host);
+                std::process::exit(1);
+            },
+        }
+    }
+    for name in &ARGS.dns_probes {
+        info!("checking DNS by resolving {name}");
+    }
+    info!("monitoring started, probing every {}s", ARGS.interval);
 
     let (tx, mut rx) = tokio::sync::watch::channel(false);
 
@@ -135,131 +270,119 @@ async fn main() {
     // gracefully shutdown
     tokio::spawn(watch_sigs(tx));
 
-    let (v4_tx, mut v4_rx) = tokio::sync::mpsc::channel(16);
-    let v4_thread = tokio::spawn(monitor_ip(ARGS.hostname.0.into(), v4_tx));
-
-    let (v6_tx, mut v6_rx) = tokio::sync::mpsc::channel(16);
-    let v6_thread = tokio::spawn(monitor_ip(ARGS.hostname.1.into(), v6_tx));
-
-    let mut v4_down = false;
-    let mut v6_down = false;
-
-    let mut v4_error_active = false;
-    let mut v6_error_active = false;
-
-    let mut v4_error_start: Option<Instant> = None;
-    let mut v6_error_start: Option<Instant> = None;
-
-    // if this stream ever yields anything then quit
-    let mut should_end = futures_util::future::select(
-        futures_util::future::select_all([v4_thread, v6_thread]),
-        Box::pin(rx.changed())
-    ).into_stream();
-
-    // TODO: make this not as stupid
-    loop {
-        select! {
-            v4 = v4_rx.recv() => {
-                v4_down = v4.unwrap();
+    let mut builder = Monitor::builder()
+        .targets(targets)
+        .interval(Duration::from_secs(ARGS.interval))
+        .hysteresis(ARGS.hysteresis)
+        .families(match (ARGS.ipv4_only, ARGS.ipv6_only) {
+            (true, _) => vec![Family::V4],
+            (_, true) => vec![Family::V6],
+            _ => vec![Family::V4, Family::V6],
+        })
+        .resolve_interval(Some(Duration::from_secs(ARGS.resolve_interval)).filter(|i| !i.is_zero()))
+        .resolver(resolver_settings)
+        .dns_probes(ARGS.dns_probes.iter().cloned())
+        .stats_window(Some(Duration::from_secs(ARGS.stats_window)).filter(|w| !w.is_zero()))
+        .degraded((ARGS.degraded_loss.is_some() || ARGS.degraded_p95.is_some()).then(|| DegradedThresholds {
+            loss: ARGS.degraded_loss,
+            p95: ARGS.degraded_p95.map(Duration::from_millis),
+            window: ARGS.degraded_window,
+            enter: ARGS.degraded_enter,
+            leave: ARGS.degraded_leave,
+        }))
+        .burst((ARGS.burst > 1).then(|| Burst { count: ARGS.burst, gap: Duration::from_millis(ARGS.burst_gap) }))
+        .traceroute(ARGS.traceroute)
+        .localize(ARGS.localize)
+        .path_mtu((ARGS.pmtu_interval > 0).then(|| PathMtuProbe {
+            sizes: ARGS.pmtu_sizes.clone(),
+            interval: Duration::from_secs(ARGS.pmtu_interval),
+        }))
+        .sink(LogSink);
+    if let Some(quorum) = ARGS.quorum {
+        builder = builder.quorum(quorum);
+    }
+    if let Some(addr) = ARGS.metrics_listen {
+        match tokio::net::TcpListener::bind(addr).await {
+            Ok(listener) => {
+                info!("serving metrics at http://{addr}/metrics");
+                let metrics = Metrics::new();
+                tokio::spawn(metrics.clone().serve(listener));
+                builder = builder.sink(metrics);
             },
-            v6 = v6_rx.recv() => {
-                v6_down = v6.unwrap();
+            Err(e) => {
+                error!("could not listen for metrics on {addr}: {e}");
+                std::process::exit(1);
             },
-            _ = should_end.next() => { break }
         }
-
-        // this is an abomination
-        match (v4_down, v6_down) {
-            (true, true) => {
-                if !(v4_error_active && v6_error_active) {
-                    error!("network is down!");
-                }
-                (v4_error_active, v6_error_active) = (true, true);
-            },
-            (true, false) => {
-                if !v4_error_active {
-                    error!("IPv4 is down!");
-                }
-                if v6_error_active {
-                    if let Some(start) = v6_error_start {
-                        let secs = start.elapsed().as_secs();
-                        info!(
-                            "IPv6 is back online, and was down for {:02}:{:02}:{:02}",
-                            secs / 3600,
-                            (secs / 60) % 60,
-                            secs % 60
-                        );
-                        v6_error_start = None;
-                    } else {
-                        info!("IPv6 is back online;")
-                    }
-                }
-                (v4_error_active, v6_error_active) = (true, false);
+    }
+    // tasks sending or saving what's kept in memory, which are waited for
+    // before exiting
+    let mut flushing = Vec::new();
+    for collector in &ARGS.export {
+        info!("pushing samples to {collector}");
+        let (exporter, task) = Exporter::start(collector.clone());
+        builder = builder.sink(exporter);
+        flushing.push(task);
+    }
+    for webhook in &ARGS.webhooks {
+        info!("notifying the {webhook} of outages");
+        let (notifier, task) = Notifier::start(webhook.clone());
+        builder = builder.sink(notifier);
+        flushing.push(task);
+    }
+    if !ARGS.on_down.is_empty() || !ARGS.on_up.is_empty() {
+        builder = builder.sink(Hooks::start(HookSettings {
+            on_down: ARGS.on_down.clone(),
+            on_up: ARGS.on_up.clone(),
+            timeout: Duration::from_secs(ARGS.hook_timeout),
+            min_downtime: Duration::from_secs(ARGS.hook_min_downtime),
+            cooldown: Duration::from_secs(ARGS.hook_cooldown),
+        }));
+    }
+    if let Some(dir) = &ARGS.out_dir {
+        match History::open(dir.join(history::FILE_NAME)) {
+            Ok(history) => {
+                let (recorder, task) = Recorder::start(history);
+                builder = builder.sink(recorder);
+                flushing.push(task);
             },
-            (false, true) => {
-                if !v6_error_active {
-                    error!("IPv6 is down!");
-                }
-                if v4_error_active {
-                    if let Some(start) = v4_error_start {
-                        let secs = start.elapsed().as_secs();
-                        info!(
-                            "IPv4 is back online, and was down for {:02}:{:02}:{:02}",
-                            secs / 3600,
-                            (secs / 60) % 60,
-                            secs % 60
-                        );
-                        v4_error_start = None;
-                    } else {
-                        info!("IPv4 is back online")
-                    }
-                }
-                (v4_error_active, v6_error_active) = (false, true);
+            Err(e) => {
+                error!("could not open the history in {}: {e}", dir.display());
+                std::process::exit(1);
             },
-            (false, false) => {
-                if v4_error_active && v6_error_active {
-                    if let Some(start) = v4_error_start {
-                        let secs = start.elapsed().as_secs();
-                        info!(
-                            "network is back online, and was down for {:02}:{:02}:{:02}",
-                            secs / 3600,
-                            (secs / 60) % 60,
-                            secs % 60
-                        );
-                        v4_error_start = None;
-                    } else {
-                        info!("network is back online")
-                    }
-                } else if v4_error_active {
-                    if let Some(start) = v4_error_start {
-                        let secs = start.elapsed().as_secs();
-                        info!(
-                            "IPv4 is back online, and was down for {:02}:{:02}:{:02}",
-                            secs / 3600,
-                            (secs / 60) % 60,
-                            secs % 60
-                        );
-                        v4_error_start = None;
-                    } else {
-                        info!("IPv4 is back online")
-                    }
-                } else if v6_error_active {
-                    if let Some(start) = v6_error_start {
-                        let secs = start.elapsed().as_secs();
-                        info!(
-                            "IPv6 is back online, and was down for {:02}:{:02}:{:02}",
-                            secs / 3600,
-                            (secs / 60) % 60,
-                            secs % 60
-                        );
-                        v6_error_start = None;
-                    } else {
-                        info!("IPv6 is back online")
-                    }
-                }
-                (v4_error_active, v6_error_active) = (false, false);
+        }
+    }
+    let (handle, mut events) = match builder.start() {
+        Ok(started) => started,
+        Err(e) => {
+            error!("{e}");
+            std::process::exit(1);
+        },
+    };
+
+    loop {
+        select! {
+            event = events.next() => match event {
+                Some(event) => trace!("{event:?}"),
+                // monitor stopped on its own
+                None => break,
             },
-        };
+            _ = rx.changed() => {
+                handle.shutdown();
+                break
+            }
+        }
+    }
+    // the sinks are dropped along with the monitor, which lets the exporters
+    // and notifiers know to send what they still have, and the history to
+    // save the last events
+    handle.join().await;
+    if !flushing.is_empty() {
+        let flushed = futures_util::future::join_all(flushing);
+        if tokio::time::timeout(FLUSH_TIMEOUT, flushed).await.is_err() {
+            warn!("gave up on sending and saving what was kept in memory after {FLUSH_TIMEOUT:?}");
+        }
     }
+
     info!("logging stopped");
 }
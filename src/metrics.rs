@@ -0,0 +1,404 @@
+//! Prometheus metrics of probe results and outages, served over HTTP
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::Duration
+};
+
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream}
+};
+
+use crate::{
+    event::{Event, Family, NetworkEvent},
+    probe::ProbeKind,
+    sink::Sink
+};
+
+/// upper bounds of the round trip time histogram buckets in seconds
+const RTT_BUCKETS: [f64; 13] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// requests are only read up to this many bytes
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// how long a scrape may take before the connection is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    /// observations in each of [`RTT_BUCKETS`], not cumulative
+    buckets: [u64; RTT_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = RTT_BUCKETS.iter().position(|bound| value <= *bound) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// whether something is down, and how often and how long it was
+#[derive(Debug, Clone, Default)]
+struct Status {
+    /// when the ongoing outage began, as far as events tell
+    down_since: Option<DateTime<Utc>>,
+    outages: u64,
+    /// of outages that are over
+    downtime: Duration,
+}
+
+impl Status {
+    fn down(&mut self, at: DateTime<Utc>) {
+        if self.down_since.is_none() {
+            self.down_since = Some(at);
+            self.outages += 1;
+        }
+    }
+
+    fn up(&mut self, downtime: Duration) {
+        if self.down_since.take().is_some() {
+            self.downtime += downtime;
+        }
+    }
+
+    /// cumulative downtime including the ongoing outage, which only ever goes
+    /// up since the downtime an outage ends with is measured from the first
+    /// failed probe, before the outage was noticed
+    fn downtime(&self, now: DateTime<Utc>) -> Duration {
+        let ongoing = self.down_since.map(|since| (now - since).to_std().unwrap_or_default());
+        self.downtime + ongoing.unwrap_or_default()
+    }
+}
+
+/// target, family and kind of probe
+type ProbeLabels = (String, Family, ProbeKind);
+
+#[derive(Debug, Default)]
+struct Registry {
+    successes: BTreeMap<ProbeLabels, u64>,
+    failures: BTreeMap<ProbeLabels, u64>,
+    rtts: BTreeMap<ProbeLabels, Histogram>,
+    targets: BTreeMap<(String, Family), Status>,
+    families: BTreeMap<Family, Status>,
+    network: Status,
+    /// only once DNS probes report anything
+    dns: Option<Status>,
+}
+
+impl Registry {
+    fn observe(&mut self, event: &Event) {
+        let at = event.timestamp;
+        match &event.kind {
+            NetworkEvent::ProbeSucceeded { target, family, probe, rtt, .. } => {
+                let labels = (target.clone(), *family, *probe);
+                *self.successes.entry(labels.clone()).or_default() += 1;
+                self.rtts.entry(labels).or_default().observe(rtt.as_secs_f64());
+                self.seen(target, *family);
+            },
+            NetworkEvent::ProbeFailed { target, family, probe, .. } => {
+                *self.failures.entry((target.clone(), *family, *probe)).or_default() += 1;
+                self.seen(target, *family);
+            },
+            NetworkEvent::TargetDown { target, family, .. } => {
+                self.targets.entry((target.clone(), *family)).or_default().down(at);
+            },
+            NetworkEvent::TargetUp { target, family, downtime, .. } => {
+                self.targets.entry((target.clone(), *family)).or_default().up(*downtime);
+            },
+            NetworkEvent::FamilyDown { family, .. } => self.families.entry(*family).or_default().down(at),
+            NetworkEvent::FamilyUp { family, downtime, .. } => self.families.entry(*family).or_default().up(*downtime),
+            NetworkEvent::NetworkDown { .. } => self.network.down(at),
+            NetworkEvent::NetworkUp { downtime, .. } => self.network.up(*downtime),
+            NetworkEvent::DnsProbeSucceeded { .. } | NetworkEvent::DnsProbeFailed { .. } => {
+                self.dns.get_or_insert_with(Status::default);
+            },
+            NetworkEvent::DnsDown => self.dns.get_or_insert_with(Status::default).down(at),
+            NetworkEvent::DnsUp { downtime } => self.dns.get_or_insert_with(Status::default).up(*downtime),
+            _ => {},
+        }
+    }
+
+    /// starts reporting the target and its family as up once probed
+    fn seen(&mut self, target: &str, family: Family) {
+        self.targets.entry((target.to_string(), family)).or_default();
+        self.families.entry(family).or_default();
+    }
+
+    /// the metrics in the Prometheus text format
+    fn render(&self, now: DateTime<Utc>) -> String {
+        let mut out = String::new();
+        let probe_labels = |(target, family, probe): &ProbeLabels| format!(
+            "target=\"{}\",family=\"{}\",probe=\"{}\"",
+            escape(target),
+            family_label(*family),
+            probe_label(*probe)
+        );
+        let target_labels = |(target, family): &(String, Family)| {
+            format!("target=\"{}\",family=\"{}\"", escape(target), family_label(*family))
+        };
+        let family_labels = |family: &Family| format!("family=\"{}\"", family_label(*family));
+
+        header(&mut out, "probes_total", "counter", "probes sent to each target, by result");
+        for (labels, count) in &self.successes {
+            let _ = writeln!(out, "network_monitor_probes_total{{{},result=\"success\"}} {count}", probe_labels(labels));
+        }
+        for (labels, count) in &self.failures {
+            let _ = writeln!(out, "network_monitor_probes_total{{{},result=\"failure\"}} {count}", probe_labels(labels));
+        }
+
+        header(&mut out, "rtt_seconds", "histogram", "round trip times of successful probes");
+        for (labels, histogram) in &self.rtts {
+            let labels = probe_labels(labels);
+            let mut cumulative = 0;
+            for (bound, count) in RTT_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(out, "network_monitor_rtt_seconds_bucket{{{labels},le=\"{bound}\"}} {cumulative}");
+            }
+            let _ = writeln!(out, "network_monitor_rtt_seconds_bucket{{{labels},le=\"+Inf\"}} {}", histogram.count);
+            let _ = writeln!(out, "network_monitor_rtt_seconds_sum{{{labels}}} {}", histogram.sum);
+            let _ = writeln!(out, "network_monitor_rtt_seconds_count{{{labels}}} {}", histogram.count);
+        }
+
+        statuses(&mut out, "target_", "each target over each family", &self.targets, target_labels, now);
+        statuses(&mut out, "family_", "each family", &self.families, family_labels, now);
+        if !self.families.is_empty() {
+            let network = BTreeMap::from([((), self.network.clone())]);
+            statuses(&mut out, "network_", "the whole network", &network, |_| String::new(), now);
+        }
+        if let Some(dns) = &self.dns {
+            let dns = BTreeMap::from([((), dns.clone())]);
+            statuses(&mut out, "dns_", "DNS", &dns, |_| String::new(), now);
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP network_monitor_{name} {help}");
+    let _ = writeln!(out, "# TYPE network_monitor_{name} {kind}");
+}
+
+/// the up gauge, outage counter and downtime counter of everything in
+/// `statuses`
+fn statuses<K>(
+    out: &mut String,
+    prefix: &str,
+    what: &str,
+    statuses: &BTreeMap<K, Status>,
+    labels: impl Fn(&K) -> String,
+    now: DateTime<Utc>
+) {
+    if statuses.is_empty() {
+        return;
+    }
+    let labels: Vec<(String, &Status)> = statuses.iter().map(|(key, status)| (labels(key), status)).collect();
+    let mut series = |name: &str, kind: &str, help: &str, value: &dyn Fn(&Status) -> String| {
+        header(out, &format!("{prefix}{name}"), kind, &format!("{help}, for {what}"));
+        for (labels, status) in &labels {
+            let _ = match labels.is_empty() {
+                true => writeln!(out, "network_monitor_{prefix}{name} {}", value(status)),
+                false => writeln!(out, "network_monitor_{prefix}{name}{{{labels}}} {}", value(status)),
+            };
+        }
+    };
+    series("up", "gauge", "whether it's up", &|status| u8::from(status.down_since.is_none()).to_string());
+    series("outages_total", "counter", "how many outages there were", &|status| status.outages.to_string());
+    series(
+        "downtime_seconds_total",
+        "counter",
+        "how long it was down for in total",
+        &|status| status.downtime(now).as_secs_f64().to_string()
+    );
+}
+
+/// escapes a label value
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn family_label(family: Family) -> &'static str {
+    match family {
+        Family::V4 => "ipv4",
+        Family::V6 => "ipv6",
+    }
+}
+
+fn probe_label(probe: ProbeKind) -> &'static str {
+    match probe {
+        ProbeKind::Icmp => "icmp",
+        ProbeKind::Tcp => "tcp",
+        ProbeKind::Http => "http",
+    }
+}
+
+/// Prometheus metrics fed by events, as a [`Sink`], that can be served over
+/// HTTP with [`serve`](Metrics::serve)
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the current metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        self.registry.lock().unwrap().render(Utc::now())
+    }
+
+    /// answers requests for "/metrics" on `listener` forever
+    pub async fn serve(self, listener: TcpListener) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    // like running out of file descriptors, which may pass
+                    warn!("could not accept a metrics connection: {e}");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                },
+            };
+            let metrics = self.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(REQUEST_TIMEOUT, metrics.respond(stream)).await {
+                    Ok(Ok(())) => {},
+                    Ok(Err(e)) => debug!("could not answer a metrics request: {e}"),
+                    Err(_) => debug!("metrics request timed out"),
+                }
+            });
+        }
+    }
+
+    async fn respond(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+            let read = stream.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..read]);
+        }
+
+        let request = String::from_utf8_lossy(&request);
+        let mut parts = request.lines().next().unwrap_or_default().split_whitespace();
+        let (method, path) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+        let path = path.split_once('?').map_or(path, |(path, _)| path);
+        let (status, body) = match (method, path) {
+            ("GET" | "HEAD", "/metrics") => ("200 OK", self.render()),
+            ("GET" | "HEAD", _) => ("404 Not Found", "metrics are at /metrics\n".to_string()),
+            _ => ("405 Method Not Allowed", "only GET is supported\n".to_string()),
+        };
+
+        let mut response = format!(
+            "HTTP/1.1 {status}\r\n\
+            Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+            Content-Length: {}\r\n\
+            Connection: close\r\n\r\n",
+            body.len()
+        );
+        if method != "HEAD" {
+            response.push_str(&body);
+        }
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await
+    }
+}
+
+impl Sink for Metrics {
+    fn handle(&mut self, event: &Event) {
+        self.registry.lock().unwrap().observe(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}};
+
+    use super::{Metrics, Registry};
+    use crate::{event::{Event, Family, NetworkEvent}, probe::ProbeKind, sink::Sink};
+
+    fn at(secs: i64, kind: NetworkEvent) -> Event {
+        Event { timestamp: Utc.timestamp_opt(secs, 0).unwrap(), kind }
+    }
+
+    #[test]
+    fn counts_probes_and_outages() {
+        let mut registry = Registry::default();
+        let target = "example.com".to_string();
+        let addr = "2001:db8::1".parse().unwrap();
+        registry.observe(&at(0, NetworkEvent::ProbeSucceeded {
+            target: target.clone(),
+            family: Family::V6,
+            addr,
+            probe: ProbeKind::Icmp,
+            rtt: Duration::from_millis(20),
+            http: None,
+        }));
+        registry.observe(&at(10, NetworkEvent::ProbeFailed {
+            target: target.clone(),
+            family: Family::V6,
+            addr,
+            probe: ProbeKind::Icmp,
+            error: "timed out".to_string(),
+            failures: 1,
+        }));
+        registry.observe(&at(20, NetworkEvent::FamilyDown { family: Family::V6, cause: None }));
+
+        let metrics = registry.render(Utc.timestamp_opt(50, 0).unwrap());
+        let labels = r#"target="example.com",family="ipv6",probe="icmp""#;
+        assert!(metrics.contains(&format!("network_monitor_probes_total{{{labels},result=\"success\"}} 1\n")));
+        assert!(metrics.contains(&format!("network_monitor_probes_total{{{labels},result=\"failure\"}} 1\n")));
+        assert!(metrics.contains(&format!("network_monitor_rtt_seconds_bucket{{{labels},le=\"0.01\"}} 0\n")));
+        assert!(metrics.contains(&format!("network_monitor_rtt_seconds_bucket{{{labels},le=\"0.025\"}} 1\n")));
+        assert!(metrics.contains("network_monitor_target_up{target=\"example.com\",family=\"ipv6\"} 1\n"));
+        assert!(metrics.contains("network_monitor_family_up{family=\"ipv6\"} 0\n"));
+        assert!(metrics.contains("network_monitor_family_downtime_seconds_total{family=\"ipv6\"} 30\n"));
+        assert!(metrics.contains("network_monitor_network_up 1\n"));
+
+        // the downtime the outage ends with counts instead of the time since
+        // it was noticed
+        registry.observe(&at(60, NetworkEvent::FamilyUp { family: Family::V6, downtime: Duration::from_secs(55), cause: None }));
+        let metrics = registry.render(Utc.timestamp_opt(100, 0).unwrap());
+        assert!(metrics.contains("network_monitor_family_up{family=\"ipv6\"} 1\n"));
+        assert!(metrics.contains("network_monitor_family_outages_total{family=\"ipv6\"} 1\n"));
+        assert!(metrics.contains("network_monitor_family_downtime_seconds_total{family=\"ipv6\"} 55\n"));
+    }
+
+    #[tokio::test]
+    async fn serves_metrics() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut metrics = Metrics::new();
+        metrics.handle(&at(0, NetworkEvent::DnsDown));
+        tokio::spawn(metrics.serve(listener));
+
+        let request = |request: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let response = request("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\nnetwork_monitor_dns_outages_total 1\n"), "{response}");
+        assert!(request("GET / HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 404"));
+        assert!(request("POST /metrics HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 405"));
+    }
+}
@@ -0,0 +1,1128 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::IpAddr,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant}
+};
+
+use futures_util::Stream;
+use log::{trace, warn};
+use tokio::{select, sync::{broadcast, mpsc, watch}, task::JoinHandle, time::Interval};
+use trust_dns_resolver::TokioAsyncResolver;
+
+use crate::{
+    event::{Event, Family, NetworkEvent, OutageCause, TraceReason},
+    probe::{
+        monitor_dns,
+        monitor_ip,
+        monitor_landmarks,
+        monitor_pmtu,
+        traceroute,
+        Burst,
+        LandmarkOutcome,
+        PathMtuProbe,
+        Probe,
+        ProbeContext,
+        Route
+    },
+    resolve::{self, ResolverSettings},
+    sink::Sink,
+    state::{AggregateRule, DegradedThresholds, LinkState, LinkTracker, Observation, QualityWindow, Transition},
+    stats::RttWindows,
+    target::Target
+};
+
+/// how many events a subscriber can fall behind before it starts missing them
+const EVENT_BUFFER: usize = 256;
+
+/// smallest MTU any IPv4 link must support
+const MIN_PACKET_SIZE: usize = 68;
+
+/// largest packet IPv4 and IPv6 without jumbograms allow
+const MAX_PACKET_SIZE: usize = 65_535;
+
+/// entry point for the monitoring API, see [`Monitor::builder`]
+pub struct Monitor;
+
+impl Monitor {
+    pub fn builder() -> MonitorBuilder {
+        MonitorBuilder::default()
+    }
+}
+
+pub struct MonitorBuilder {
+    targets: Vec<Target>,
+    families: Vec<Family>,
+    quorum: Option<usize>,
+    interval: Duration,
+    hysteresis: u32,
+    resolve_interval: Option<Duration>,
+    resolver: ResolverSettings,
+    dns_probes: Vec<String>,
+    stats_window: Option<Duration>,
+    burst: Option<Burst>,
+    degraded: Option<DegradedThresholds>,
+    path_mtu: Option<PathMtuProbe>,
+    traceroute: bool,
+    localize: bool,
+    sinks: Vec<Box<dyn Sink>>,
+}
+
+impl Default for MonitorBuilder {
+    fn default() -> Self {
+        Self {
+            targets: Vec::new(),
+            families: vec![Family::V4, Family::V6],
+            quorum: None,
+            interval: Duration::from_secs(15),
+            hysteresis: 2,
+            resolve_interval: Some(Duration::from_secs(300)),
+            resolver: ResolverSettings::default(),
+            dns_probes: Vec::new(),
+            stats_window: Some(Duration::from_secs(300)),
+            burst: None,
+            degraded: None,
+            path_mtu: None,
+            traceroute: false,
+            localize: false,
+            sinks: Vec::new(),
+        }
+    }
+}
+
+impl MonitorBuilder {
+    /// adds a host to be pinged, at least one is required
+    pub fn target(mut self, target: Target) -> Self {
+        self.targets.push(target);
+        self
+    }
+
+    /// adds several hosts to be pinged
+    pub fn targets(mut self, targets: impl IntoIterator<Item = Target>) -> Self {
+        self.targets.extend(targets);
+        self
+    }
+
+    /// restricts monitoring to these families, defaults to both
+    pub fn families(mut self, families: impl IntoIterator<Item = Family>) -> Self {
+        self.families = families.into_iter().collect();
+        self
+    }
+
+    /// how many targets must be down over a family for the family itself to
+    /// be considered down, defaults to all of them
+    ///
+    /// outages of fewer targets than this are reported as the targets' own
+    pub fn quorum(mut self, quorum: usize) -> Self {
+        self.quorum = Some(quorum);
+        self
+    }
+
+    /// interval between ping attempts, defaults to 15 seconds
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// how many errors in a row must occur for an outage to be reported,
+    /// defaults to 2
+    pub fn hysteresis(mut self, hysteresis: u32) -> Self {
+        self.hysteresis = hysteresis;
+        self
+    }
+
+    /// longest time between re-resolving the targets' hostnames, they're also
+    /// re-resolved once their records expire, defaults to 5 minutes
+    ///
+    /// `None` keeps using the addresses the targets were created with forever
+    pub fn resolve_interval(mut self, interval: Option<Duration>) -> Self {
+        self.resolve_interval = interval;
+        self
+    }
+
+    /// how hostnames are re-resolved, defaults to Cloudflare's nameservers
+    pub fn resolver(mut self, resolver: ResolverSettings) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// adds a name to be resolved every interval to check that DNS works,
+    /// DNS is considered down once none of these resolve anymore
+    pub fn dns_probe(mut self, name: impl Into<String>) -> Self {
+        self.dns_probes.push(name.into());
+        self
+    }
+
+    /// adds several names to be resolved to check that DNS works
+    pub fn dns_probes(mut self, names: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.dns_probes.extend(names.into_iter().map(Into::into));
+        self
+    }
+
+    /// how often the round trip times of each target are summarized, defaults
+    /// to 5 minutes
+    ///
+    /// `None` disables the summaries
+    pub fn stats_window(mut self, window: Option<Duration>) -> Self {
+        self.stats_window = window;
+        self
+    }
+
+    /// sends bursts of several echo requests every interval instead of a
+    /// single one, measuring packet loss and jitter, disabled by default
+    ///
+    /// pings only fail once every request of a burst went unanswered
+    pub fn burst(mut self, burst: Option<Burst>) -> Self {
+        self.burst = burst;
+        self
+    }
+
+    /// when targets that still respond are considered degraded, based on
+    /// their packet loss and latency, disabled by default
+    pub fn degraded(mut self, thresholds: Option<DegradedThresholds>) -> Self {
+        self.degraded = thresholds;
+        self
+    }
+
+    /// measures the path MTU to every target with don't-fragment pings of
+    /// several sizes, disabled by default
+    pub fn path_mtu(mut self, probe: Option<PathMtuProbe>) -> Self {
+        self.path_mtu = probe;
+        self
+    }
+
+    /// traces the route to targets when they go down and again once they come
+    /// back, which needs raw sockets, disabled by default
+    pub fn traceroute(mut self, enabled: bool) -> Self {
+        self.traceroute = enabled;
+        self
+    }
+
+    /// pings the default gateway and the ISP's first hop of each family along
+    /// with the targets, telling whether outages are in the local network,
+    /// at the gateway, the ISP or the targets themselves, disabled by default
+    ///
+    /// the gateway comes from the routing table, so this only works on Linux
+    pub fn localize(mut self, enabled: bool) -> Self {
+        self.localize = enabled;
+        self
+    }
+
+    /// adds an output that gets every event, in the order they were added
+    pub fn sink(mut self, sink: impl Sink) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    /// spawns the monitor onto the current tokio runtime
+    pub fn start(mut self) -> Result<(MonitorHandle, EventStream), &'static str> {
+        if self.targets.is_empty() {
+            return Err("At least one target must be provided");
+        }
+        for target in self.targets.iter_mut() {
+            if !self.families.contains(&Family::V4) {
+                target.v4 = None;
+            }
+            if !self.families.contains(&Family::V6) {
+                target.v6 = None;
+            }
+            if target.families().next().is_none() {
+                return Err("Every target must have an address in at least one of the monitored families");
+            }
+        }
+        let rule = match self.quorum {
+            None => AggregateRule::All,
+            Some(0) => return Err("The quorum must be at least 1"),
+            Some(n) if n > self.targets.len() => {
+                return Err("The quorum can't be larger than the number of targets")
+            },
+            Some(n) => AggregateRule::AtLeast(n),
+        };
+        if self.interval.is_zero() {
+            return Err("The interval must not be zero");
+        }
+        if self.hysteresis == 0 {
+            return Err("The hysteresis must be at least 1");
+        }
+        if self.burst.is_some_and(|b| b.count == 0) {
+            return Err("Bursts must have at least one echo request");
+        }
+        if let Some(thresholds) = self.degraded {
+            if thresholds.window == 0 || thresholds.enter == 0 || thresholds.leave == 0 {
+                return Err("The degraded window and hysteresis must be at least 1");
+            }
+            if thresholds.loss.is_some_and(|loss| !(0.0..100.0).contains(&loss)) {
+                return Err("The degraded loss threshold must be a percentage below 100");
+            }
+        }
+        if self.stats_window.is_some_and(|w| w.is_zero()) {
+            return Err("The stats window must not be zero");
+        }
+        if let Some(probe) = self.path_mtu.as_mut() {
+            if probe.interval.is_zero() {
+                return Err("The path MTU interval must not be zero");
+            }
+            if probe.sizes.is_empty() {
+                return Err("At least one packet size is needed for measuring the path MTU");
+            }
+            if probe.sizes.iter().any(|size| !(MIN_PACKET_SIZE..=MAX_PACKET_SIZE).contains(size)) {
+                return Err("Path MTU packet sizes must be between 68 and 65535 bytes");
+            }
+            probe.sizes.sort_unstable();
+            probe.sizes.dedup();
+        }
+        if self.dns_probes.iter().any(|name| name.is_empty()) {
+            return Err("DNS probes must have a name to resolve");
+        }
+        let resolver = self.resolver.build()?;
+        // HTTP probes time a lookup of their host, which is only worth it if
+        // it's not answered from the cache
+        let has_http = self.targets.iter().any(|target| matches!(target.probe, Probe::Http(_)));
+        let dns_resolver = match self.dns_probes.is_empty() && !has_http {
+            true => None,
+            false => Some(self.resolver.build_uncached()?),
+        };
+
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let (events, events_rx) = broadcast::channel(EVENT_BUFFER);
+        let stream = EventStream::new(events_rx.resubscribe());
+
+        // only the task holds the sender, so streams end once it stops
+        let task = tokio::spawn(run(self, rule, resolver, dns_resolver, events, shutdown_rx));
+
+        Ok((MonitorHandle { shutdown, events: events_rx, task }, stream))
+    }
+}
+
+/// controls a running monitor, dropping it shuts the monitor down
+pub struct MonitorHandle {
+    shutdown: watch::Sender<bool>,
+    /// never read, only kept to subscribe from
+    events: broadcast::Receiver<Event>,
+    task: JoinHandle<()>,
+}
+
+impl MonitorHandle {
+    /// returns a new stream of the events emitted from now on
+    pub fn subscribe(&self) -> EventStream {
+        EventStream::new(self.events.resubscribe())
+    }
+
+    /// asks the monitor to stop, use [`MonitorHandle::join`] to wait for it
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// whether the monitor has stopped, either by being shut down or because
+    /// a probe died
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// waits for the monitor to stop
+    pub async fn join(self) {
+        if let Err(e) = self.task.await {
+            if e.is_panic() {
+                std::panic::resume_unwind(e.into_panic());
+            }
+        }
+    }
+}
+
+/// stream of the events emitted by a monitor, ends once the monitor stops
+///
+/// events are dropped for subscribers that fall too far behind
+pub struct EventStream {
+    inner: Pin<Box<dyn Stream<Item = Event> + Send>>,
+}
+
+impl EventStream {
+    fn new(rx: broadcast::Receiver<Event>) -> Self {
+        let inner = futures_util::stream::unfold(rx, |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((event, rx)),
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        trace!("event subscriber lagged behind by {n} events");
+                    },
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        });
+        Self { inner: Box::pin(inner) }
+    }
+}
+
+impl Stream for EventStream {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+struct Emitter {
+    sinks: Vec<Box<dyn Sink>>,
+    events: broadcast::Sender<Event>,
+}
+
+impl Emitter {
+    fn emit(&mut self, kind: NetworkEvent) {
+        let event = Event::now(kind);
+        for sink in self.sinks.iter_mut() {
+            sink.handle(&event);
+        }
+        // no subscribers is fine
+        let _ = self.events.send(event);
+    }
+}
+
+/// tracks every target over every family, attributing outages either to
+/// single targets or to the whole family
+struct NetworkTracker {
+    targets: Vec<String>,
+    /// reachability of each target, by index, over each family it has an
+    /// address in
+    families: HashMap<Family, LinkTracker<usize>>,
+    /// families are down when enough of their targets are
+    uplink: LinkTracker<Family>,
+    /// whether each name checked by DNS probes resolves, by index, DNS is
+    /// down once none of them do
+    dns: LinkTracker<usize>,
+    /// when responding targets are degraded, if they can be at all
+    degraded: Option<DegradedThresholds>,
+    /// recent loss and latency of each target over each family
+    quality: HashMap<(usize, Family), QualityWindow>,
+    /// latest path MTU to each target over each family, and whether larger
+    /// packets were dropped
+    mtus: HashMap<(usize, Family), (usize, bool)>,
+    /// largest path MTU to any target of each family
+    family_mtus: HashMap<Family, usize>,
+    /// latest results of the local link, gateway and ISP of each family, if
+    /// outages are localized
+    landmarks: HashMap<Family, LandmarkOutcome>,
+    /// where each ongoing outage was when it began
+    causes: HashMap<Outage, Option<OutageCause>>,
+}
+
+/// something that can be down
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Outage {
+    Target(usize, Family),
+    Family(Family),
+    Network,
+}
+
+impl NetworkTracker {
+    fn new(targets: &[Target], dns_probes: &[String], hysteresis: u32, rule: AggregateRule, now: Instant) -> Self {
+        let mut families: HashMap<Family, LinkTracker<usize>> = HashMap::new();
+        let mut uplink = LinkTracker::new(1, AggregateRule::All);
+        for (i, target) in targets.iter().enumerate() {
+            for family in target.families() {
+                families.entry(family)
+                    .or_insert_with(|| LinkTracker::new(hysteresis, rule))
+                    .add_path(i, now);
+                uplink.add_path(family, now);
+            }
+        }
+        let mut dns = LinkTracker::new(hysteresis, AggregateRule::All);
+        for i in 0..dns_probes.len() {
+            dns.add_path(i, now);
+        }
+        Self {
+            targets: targets.iter().map(|t| t.host.clone()).collect(),
+            families,
+            uplink,
+            dns,
+            degraded: None,
+            quality: HashMap::new(),
+            mtus: HashMap::new(),
+            family_mtus: HashMap::new(),
+            landmarks: HashMap::new(),
+            causes: HashMap::new(),
+        }
+    }
+
+    /// turns a probe that got `received` out of `sent` replies into an
+    /// observation, judging the target's recent loss and latency if degraded
+    /// thresholds are set
+    fn judge(&mut self, target: usize, family: Family, sent: u16, received: u16, rtt: Option<Duration>) -> Observation {
+        let Some(thresholds) = self.degraded else {
+            return match rtt {
+                Some(_) => Observation::Success,
+                None => Observation::Failure,
+            };
+        };
+        // outages aren't counted as loss, the window starts over once the
+        // target is back
+        if rtt.is_none() && self.families[&family].state(&target) == Some(LinkState::Down) {
+            return Observation::Failure;
+        }
+        let degraded = self.quality.entry((target, family))
+            .or_insert_with(|| QualityWindow::new(thresholds))
+            .record(sent, received, rtt);
+        match (rtt, degraded) {
+            (None, _) => Observation::Failure,
+            (Some(_), true) => Observation::Degraded,
+            (Some(_), false) => Observation::Success,
+        }
+    }
+
+    fn failures(&self, target: usize, family: Family) -> u32 {
+        self.families[&family].failures(&target)
+    }
+
+    fn dns_failures(&self, name: usize) -> u32 {
+        self.dns.failures(&name)
+    }
+
+    fn observe_dns(&mut self, name: usize, observation: Observation, now: Instant) -> Vec<NetworkEvent> {
+        self.dns.observe(&name, observation, now)
+            .into_iter()
+            .filter_map(|transition| match transition {
+                Transition::NetworkDown => Some(NetworkEvent::DnsDown),
+                Transition::NetworkUp { downtime } => Some(NetworkEvent::DnsUp { downtime }),
+                // single names failing are reported through their probes
+                Transition::Path { .. } => None,
+            })
+            .collect()
+    }
+
+    /// records the path MTU measured to a target, `blocked` being the
+    /// smallest packet that didn't get through if any
+    fn observe_pmtu(&mut self, target: usize, family: Family, mtu: usize, blocked: Option<usize>) -> Vec<NetworkEvent> {
+        let mut events = Vec::new();
+        let host = self.targets[target].clone();
+
+        let previous = self.mtus.insert((target, family), (mtu, blocked.is_some()));
+        if previous.map(|(mtu, _)| mtu) != Some(mtu) {
+            events.push(NetworkEvent::PathMtuChanged {
+                target: host.clone(),
+                family,
+                previous: previous.map(|(mtu, _)| mtu),
+                mtu
+            });
+        }
+        // only reported once they start being dropped, any further changes
+        // show up as path MTU changes
+        if let Some(size) = blocked.filter(|_| !previous.is_some_and(|(_, blocked)| blocked)) {
+            events.push(NetworkEvent::LargePacketsDropped { target: host, family, mtu, size });
+        }
+
+        let family_mtu = self.mtus.iter()
+            .filter(|((_, f), _)| *f == family)
+            .map(|(_, (mtu, _))| *mtu)
+            .max()
+            .unwrap_or(mtu);
+        let previous = self.family_mtus.insert(family, family_mtu);
+        if previous != Some(family_mtu) {
+            events.push(NetworkEvent::FamilyMtuChanged { family, previous, mtu: family_mtu });
+        }
+
+        events
+    }
+
+    /// records whether the local link, gateway and ISP of a family answer
+    fn observe_landmarks(&mut self, outcome: LandmarkOutcome) {
+        self.landmarks.insert(outcome.family, outcome);
+    }
+
+    /// where an outage over `family` is, based on the latest landmark
+    /// results, `None` if there aren't any
+    fn cause(&self, family: Family, family_down: bool) -> Option<OutageCause> {
+        let landmarks = self.landmarks.get(&family)?;
+        let fails = |landmark: Option<(IpAddr, bool)>| landmark.is_some_and(|(_, answered)| !answered);
+        Some(if !landmarks.link_up {
+            OutageCause::Lan
+        } else if fails(landmarks.gateway) {
+            OutageCause::Gateway
+        } else if fails(landmarks.isp) || (family_down && landmarks.isp.is_none()) {
+            OutageCause::Isp
+        } else {
+            OutageCause::Remote
+        })
+    }
+
+    fn observe(&mut self, target: usize, family: Family, observation: Observation, now: Instant) -> Vec<NetworkEvent> {
+        let tracker = self.families.get_mut(&family).expect("every family is tracked");
+        let transitions = tracker.observe(&target, observation, now);
+        let down_since = tracker.network_down_since();
+        let cause = self.cause(family, down_since.is_some());
+        let mut events = Vec::new();
+        let mut family_state = None;
+
+        for transition in transitions {
+            match transition {
+                Transition::Path { key, to: LinkState::Down, .. } => {
+                    if let Some(window) = self.quality.get_mut(&(key, family)) {
+                        window.clear();
+                    }
+                    self.causes.insert(Outage::Target(key, family), cause);
+                    events.push(NetworkEvent::TargetDown { target: self.targets[key].clone(), family, cause });
+                },
+                Transition::Path { key, from, to, duration } => {
+                    let target = self.targets[key].clone();
+                    if from == LinkState::Down {
+                        events.push(NetworkEvent::TargetUp {
+                            target: target.clone(),
+                            family,
+                            downtime: duration,
+                            cause: self.causes.remove(&Outage::Target(key, family)).flatten()
+                        });
+                    }
+                    match to {
+                        LinkState::Degraded => {
+                            let reason = self.quality.get(&(key, family))
+                                .and_then(|w| w.reason())
+                                .unwrap_or("too much loss or latency")
+                                .to_string();
+                            events.push(NetworkEvent::TargetDegraded { target, family, reason });
+                        },
+                        _ if from == LinkState::Degraded => {
+                            events.push(NetworkEvent::TargetRecovered { target, family, duration });
+                        },
+                        _ => {},
+                    }
+                },
+                Transition::NetworkDown => family_state = Some((LinkState::Down, down_since.unwrap_or(now))),
+                Transition::NetworkUp { .. } => family_state = Some((LinkState::Up, now)),
+            }
+        }
+
+        if let Some((state, since)) = family_state {
+            for transition in self.uplink.set_state(&family, state, since, now) {
+                events.push(match transition {
+                    Transition::Path { key, to: LinkState::Down, .. } => {
+                        self.causes.insert(Outage::Family(key), cause);
+                        NetworkEvent::FamilyDown { family: key, cause }
+                    },
+                    Transition::Path { key, duration, .. } => NetworkEvent::FamilyUp {
+                        family: key,
+                        downtime: duration,
+                        cause: self.causes.remove(&Outage::Family(key)).flatten()
+                    },
+                    Transition::NetworkDown => {
+                        // the outage closest to this host explains the others
+                        let cause = self.causes.iter()
+                            .filter(|(outage, _)| matches!(outage, Outage::Family(_)))
+                            .filter_map(|(_, cause)| *cause)
+                            .min();
+                        self.causes.insert(Outage::Network, cause);
+                        NetworkEvent::NetworkDown { cause }
+                    },
+                    Transition::NetworkUp { downtime } => NetworkEvent::NetworkUp {
+                        downtime,
+                        cause: self.causes.remove(&Outage::Network).flatten()
+                    },
+                });
+            }
+        }
+
+        events
+    }
+}
+
+async fn run(
+    config: MonitorBuilder,
+    rule: AggregateRule,
+    resolver: TokioAsyncResolver,
+    dns_resolver: Option<TokioAsyncResolver>,
+    events: broadcast::Sender<Event>,
+    mut shutdown: watch::Receiver<bool>
+) {
+    let MonitorBuilder {
+        targets,
+        interval,
+        hysteresis,
+        resolve_interval,
+        dns_probes,
+        stats_window,
+        burst,
+        degraded,
+        path_mtu,
+        traceroute: trace_outages,
+        localize,
+        sinks,
+        ..
+    } = config;
+    let mut emitter = Emitter { sinks, events };
+
+    let (tx, mut rx) = mpsc::channel(16);
+    let (notice_tx, mut notices) = mpsc::channel(16);
+    let (pmtu_tx, mut pmtu_rx) = mpsc::channel(16);
+    let (trace_tx, mut traces) = mpsc::channel::<(usize, Family, TraceReason, io::Result<Route>)>(16);
+    let mut tasks = Vec::new();
+    // current address of each target over each family, for traceroutes
+    let mut current_addrs = HashMap::new();
+    for (i, target) in targets.iter().enumerate() {
+        let mut addrs = HashMap::new();
+        for family in target.families() {
+            let (addr_tx, addr_rx) = watch::channel(target.addr(family).expect("family has an address"));
+            tasks.push(tokio::spawn(monitor_ip(
+                i,
+                target.probe.clone(),
+                addr_rx,
+                interval,
+                ProbeContext::new(dns_resolver.clone(), burst, target.marking),
+                tx.clone()
+            )));
+            if let Some(probe) = &path_mtu {
+                tasks.push(tokio::spawn(monitor_pmtu(
+                    i,
+                    probe.clone(),
+                    addr_tx.subscribe(),
+                    target.marking,
+                    pmtu_tx.clone()
+                )));
+            }
+            current_addrs.insert((i, family), addr_tx.subscribe());
+            addrs.insert(family, addr_tx);
+        }
+
+        match resolve_interval {
+            Some(resolve_interval) if !target.is_literal() => {
+                tasks.push(tokio::spawn(resolve::watch_target(
+                    target.host.clone(),
+                    resolver.clone(),
+                    resolve_interval,
+                    addrs,
+                    notice_tx.clone()
+                )));
+            },
+            _ => {},
+        }
+    }
+    drop(tx);
+    drop(notice_tx);
+    drop(pmtu_tx);
+
+    let (landmark_tx, mut landmarks) = mpsc::channel(16);
+    if localize {
+        for family in [Family::V4, Family::V6] {
+            // the ISP's first hop is on the way to any target
+            let toward = (0..targets.len()).find_map(|i| current_addrs.get(&(i, family))).cloned();
+            if let Some(toward) = toward {
+                tasks.push(tokio::spawn(monitor_landmarks(family, toward, interval, landmark_tx.clone())));
+            }
+        }
+    }
+    drop(landmark_tx);
+
+    let (dns_tx, mut dns_rx) = mpsc::channel(16);
+    if let Some(dns_resolver) = &dns_resolver {
+        for (i, name) in dns_probes.iter().enumerate() {
+            tasks.push(tokio::spawn(monitor_dns(i, name.clone(), dns_resolver.clone(), interval, dns_tx.clone())));
+        }
+    }
+    drop(dns_tx);
+
+    let mut tracker = NetworkTracker::new(
+        &targets,
+        &dns_probes,
+        hysteresis,
+        rule,
+        Instant::now()
+    );
+    tracker.degraded = degraded;
+    let mut rtts = RttWindows::default();
+    let mut stats_tick = stats_window.map(|window| {
+        tokio::time::interval_at(tokio::time::Instant::now() + window, window)
+    });
+
+    loop {
+        let outcome = select! {
+            outcome = rx.recv() => match outcome {
+                Some(outcome) => outcome,
+                None => break,
+            },
+            Some(notice) = notices.recv() => {
+                emitter.emit(notice);
+                continue;
+            },
+            _ = tick(&mut stats_tick) => {
+                let window = stats_window.expect("only ticks with a window");
+                for (target, family, probe, stats) in rtts.flush() {
+                    emitter.emit(NetworkEvent::LatencySummary {
+                        target: targets[target].host.clone(),
+                        family,
+                        probe,
+                        window,
+                        stats
+                    });
+                }
+                continue;
+            },
+            Some(outcome) = dns_rx.recv() => {
+                let observation = match &outcome.result {
+                    Ok(_) => Observation::Success,
+                    Err(_) => Observation::Failure,
+                };
+                let transitions = tracker.observe_dns(outcome.name, observation, Instant::now());
+                let name = dns_probes[outcome.name].clone();
+                emitter.emit(match outcome.result {
+                    Ok(latency) => NetworkEvent::DnsProbeSucceeded { name, latency },
+                    Err(reason) => NetworkEvent::DnsProbeFailed {
+                        name,
+                        reason,
+                        failures: tracker.dns_failures(outcome.name)
+                    },
+                });
+                for event in transitions {
+                    emitter.emit(event);
+                }
+                continue;
+            },
+            Some(outcome) = pmtu_rx.recv() => {
+                // nothing got through at all, which the regular probes
+                // already report if it's an outage
+                if let Some(mtu) = outcome.mtu {
+                    let family = Family::of(&outcome.addr);
+                    for event in tracker.observe_pmtu(outcome.target, family, mtu, outcome.blocked) {
+                        emitter.emit(event);
+                    }
+                }
+                continue;
+            },
+            Some(outcome) = landmarks.recv() => {
+                trace!("{outcome:?}");
+                tracker.observe_landmarks(outcome);
+                continue;
+            },
+            Some((target, family, reason, route)) = traces.recv() => {
+                let target = targets[target].host.clone();
+                match route {
+                    Ok(route) => emitter.emit(NetworkEvent::RouteTraced {
+                        target,
+                        family,
+                        reason,
+                        route
+                    }),
+                    Err(e) => warn!("could not trace the route to {target} over {family}: {e}"),
+                }
+                continue;
+            },
+            _ = shutdown.wait_for(|s| *s) => break,
+        };
+
+        let target = &targets[outcome.target];
+        let family = Family::of(&outcome.addr);
+        let (sent, received) = match (&outcome.burst, &outcome.result) {
+            (Some(burst), _) => (burst.sent, burst.received),
+            (None, Ok(_)) => (1, 1),
+            (None, Err(_)) => (1, 0),
+        };
+        let rtt = outcome.result.as_ref().ok().map(|m| m.rtt);
+        let observation = tracker.judge(outcome.target, family, sent, received, rtt);
+        let transitions = tracker.observe(outcome.target, family, observation, Instant::now());
+
+        if let Ok(measurement) = &outcome.result {
+            rtts.record(outcome.target, family, outcome.kind, measurement.rtt);
+        }
+        match outcome.result {
+            Ok(measurement) => emitter.emit(NetworkEvent::ProbeSucceeded {
+                target: target.host.clone(),
+                family,
+                addr: outcome.addr,
+                probe: outcome.kind,
+                rtt: measurement.rtt,
+                http: measurement.http
+            }),
+            Err(error) => emitter.emit(NetworkEvent::ProbeFailed {
+                target: target.host.clone(),
+                family,
+                addr: outcome.addr,
+                probe: outcome.kind,
+                error,
+                failures: tracker.failures(outcome.target, family)
+            }),
+        }
+        if let Some(stats) = outcome.burst {
+            emitter.emit(NetworkEvent::BurstCompleted {
+                target: target.host.clone(),
+                family,
+                addr: outcome.addr,
+                stats
+            });
+        }
+
+        for event in transitions {
+            let reason = match &event {
+                NetworkEvent::TargetDown { .. } => Some(TraceReason::Outage),
+                NetworkEvent::TargetUp { .. } => Some(TraceReason::Recovery),
+                _ => None,
+            };
+            emitter.emit(event);
+
+            if let Some(reason) = reason.filter(|_| trace_outages) {
+                let addr = *current_addrs[&(outcome.target, family)].borrow();
+                let trace_tx = trace_tx.clone();
+                let index = outcome.target;
+                tasks.retain(|task: &JoinHandle<()>| !task.is_finished());
+                tasks.push(tokio::spawn(async move {
+                    let _ = trace_tx.send((index, family, reason, traceroute(addr).await)).await;
+                }));
+            }
+        }
+    }
+
+    for task in tasks {
+        task.abort();
+    }
+    trace!("monitor stopped");
+}
+
+/// waits for the next tick of `interval`, or forever if there isn't one
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        },
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::IpAddr, time::{Duration, Instant}};
+
+    use futures_util::StreamExt;
+
+    use super::{Monitor, NetworkTracker};
+    use crate::{
+        event::{Family, NetworkEvent, OutageCause},
+        probe::{LandmarkOutcome, Marking, Probe},
+        state::{AggregateRule, DegradedThresholds, Observation},
+        target::Target
+    };
+
+    fn target(host: &str) -> Target {
+        Target {
+            host: host.to_string(),
+            v4: Some("192.0.2.1".parse().unwrap()),
+            v6: Some("2001:db8::1".parse().unwrap()),
+            probe: Probe::default(),
+            marking: Marking::default(),
+        }
+    }
+
+    fn tracker(rule: AggregateRule) -> (NetworkTracker, Instant) {
+        let now = Instant::now();
+        let targets = [target("a"), target("b"), target("c")];
+        (NetworkTracker::new(&targets, &[], 1, rule, now), now)
+    }
+
+    #[test]
+    fn single_target_outage_is_attributed_to_target() {
+        let (mut tracker, t0) = tracker(AggregateRule::AtLeast(2));
+
+        assert_eq!(
+            tracker.observe(1, Family::V4, Observation::Failure, t0),
+            vec![NetworkEvent::TargetDown { target: "b".to_string(), family: Family::V4, cause: None }]
+        );
+        assert_eq!(
+            tracker.observe(1, Family::V4, Observation::Success, t0 + Duration::from_secs(30)),
+            vec![NetworkEvent::TargetUp {
+                target: "b".to_string(),
+                family: Family::V4,
+                downtime: Duration::from_secs(30),
+                cause: None
+            }]
+        );
+    }
+
+    #[test]
+    fn single_stack_targets_only_count_towards_their_family() {
+        let t0 = Instant::now();
+        let targets = [target("a"), Target::from("192.0.2.2".parse::<std::net::IpAddr>().unwrap())];
+        let mut tracker = NetworkTracker::new(&targets, &[], 1, AggregateRule::All, t0);
+
+        assert_eq!(
+            tracker.observe(0, Family::V6, Observation::Failure, t0),
+            vec![
+                NetworkEvent::TargetDown { target: "a".to_string(), family: Family::V6, cause: None },
+                NetworkEvent::FamilyDown { family: Family::V6, cause: None },
+            ]
+        );
+    }
+
+    #[test]
+    fn quorum_takes_family_and_network_down() {
+        let (mut tracker, t0) = tracker(AggregateRule::AtLeast(2));
+        let secs = Duration::from_secs;
+
+        tracker.observe(0, Family::V4, Observation::Failure, t0);
+        assert_eq!(
+            tracker.observe(2, Family::V4, Observation::Failure, t0 + secs(10)),
+            vec![
+                NetworkEvent::TargetDown { target: "c".to_string(), family: Family::V4, cause: None },
+                NetworkEvent::FamilyDown { family: Family::V4, cause: None },
+            ]
+        );
+
+        tracker.observe(0, Family::V6, Observation::Failure, t0 + secs(20));
+        assert_eq!(
+            tracker.observe(1, Family::V6, Observation::Failure, t0 + secs(20)),
+            vec![
+                NetworkEvent::TargetDown { target: "b".to_string(), family: Family::V6, cause: None },
+                NetworkEvent::FamilyDown { family: Family::V6, cause: None },
+                NetworkEvent::NetworkDown { cause: None },
+            ]
+        );
+
+        assert_eq!(
+            tracker.observe(2, Family::V4, Observation::Success, t0 + secs(60)),
+            vec![
+                NetworkEvent::TargetUp { target: "c".to_string(), family: Family::V4, downtime: secs(50), cause: None },
+                NetworkEvent::FamilyUp { family: Family::V4, downtime: secs(50), cause: None },
+                NetworkEvent::NetworkUp { downtime: secs(40), cause: None },
+            ]
+        );
+    }
+
+    #[test]
+    fn degraded_targets() {
+        let (mut tracker, t0) = tracker(AggregateRule::All);
+        let secs = Duration::from_secs;
+        tracker.degraded = Some(DegradedThresholds { loss: Some(10.0), window: 10, enter: 1, leave: 1, ..Default::default() });
+
+        let observation = tracker.judge(0, Family::V4, 10, 8, Some(secs(0)));
+        assert_eq!(observation, Observation::Degraded);
+        let events = tracker.observe(0, Family::V4, observation, t0);
+        assert!(matches!(&events[..], [NetworkEvent::TargetDegraded { family: Family::V4, .. }]));
+
+        // 2 lost out of 20
+        let observation = tracker.judge(0, Family::V4, 10, 10, Some(secs(0)));
+        assert_eq!(observation, Observation::Success);
+        assert_eq!(
+            tracker.observe(0, Family::V4, observation, t0 + secs(60)),
+            vec![NetworkEvent::TargetRecovered { target: "a".to_string(), family: Family::V4, duration: secs(60) }]
+        );
+    }
+
+    #[test]
+    fn dns_is_tracked_separately() {
+        let t0 = Instant::now();
+        let secs = Duration::from_secs;
+        let names = ["example.com".to_string(), "example.org".to_string()];
+        let mut tracker = NetworkTracker::new(&[target("a")], &names, 1, AggregateRule::All, t0);
+
+        assert_eq!(tracker.observe_dns(0, Observation::Failure, t0), vec![]);
+        assert_eq!(tracker.observe_dns(1, Observation::Failure, t0 + secs(10)), vec![NetworkEvent::DnsDown]);
+        // the network itself is still fine
+        assert_eq!(tracker.observe(0, Family::V4, Observation::Success, t0 + secs(10)), vec![]);
+        assert_eq!(
+            tracker.observe_dns(0, Observation::Success, t0 + secs(70)),
+            vec![NetworkEvent::DnsUp { downtime: secs(60) }]
+        );
+    }
+
+    #[test]
+    fn path_mtu_changes() {
+        let (mut tracker, _) = tracker(AggregateRule::All);
+        let changed = |target: &str, previous, mtu| NetworkEvent::PathMtuChanged {
+            target: target.to_string(),
+            family: Family::V6,
+            previous,
+            mtu
+        };
+
+        assert_eq!(
+            tracker.observe_pmtu(0, Family::V6, 1500, None),
+            vec![changed("a", None, 1500), NetworkEvent::FamilyMtuChanged { family: Family::V6, previous: None, mtu: 1500 }]
+        );
+        assert_eq!(tracker.observe_pmtu(0, Family::V6, 1500, None), vec![]);
+        // a single target behind a smaller MTU doesn't change the family's
+        assert_eq!(
+            tracker.observe_pmtu(1, Family::V6, 1420, Some(1460)),
+            vec![
+                changed("b", None, 1420),
+                NetworkEvent::LargePacketsDropped { target: "b".to_string(), family: Family::V6, mtu: 1420, size: 1460 },
+            ]
+        );
+        // only reported when large packets start being dropped
+        assert_eq!(tracker.observe_pmtu(1, Family::V6, 1280, Some(1400)), vec![changed("b", Some(1420), 1280)]);
+        assert_eq!(
+            tracker.observe_pmtu(0, Family::V6, 1480, Some(1492)),
+            vec![
+                changed("a", Some(1500), 1480),
+                NetworkEvent::LargePacketsDropped { target: "a".to_string(), family: Family::V6, mtu: 1480, size: 1492 },
+                NetworkEvent::FamilyMtuChanged { family: Family::V6, previous: Some(1500), mtu: 1480 },
+            ]
+        );
+    }
+
+    #[test]
+    fn outages_are_localized() {
+        let (mut tracker, t0) = tracker(AggregateRule::AtLeast(2));
+        let gateway = "192.0.2.254".parse().unwrap();
+        let isp = "198.51.100.1".parse().unwrap();
+        let landmarks = |link_up, gateway_answers, isp: Option<(IpAddr, bool)>| LandmarkOutcome {
+            family: Family::V4,
+            link_up,
+            gateway: Some((gateway, gateway_answers)),
+            isp,
+        };
+
+        // nothing to go by yet
+        assert_eq!(tracker.cause(Family::V4, true), None);
+
+        tracker.observe_landmarks(landmarks(true, true, Some((isp, true))));
+        assert_eq!(
+            tracker.observe(0, Family::V4, Observation::Failure, t0),
+            vec![NetworkEvent::TargetDown { target: "a".to_string(), family: Family::V4, cause: Some(OutageCause::Remote) }]
+        );
+
+        tracker.observe_landmarks(landmarks(true, false, Some((isp, false))));
+        assert_eq!(
+            tracker.observe(1, Family::V4, Observation::Failure, t0),
+            vec![
+                NetworkEvent::TargetDown { target: "b".to_string(), family: Family::V4, cause: Some(OutageCause::Gateway) },
+                NetworkEvent::FamilyDown { family: Family::V4, cause: Some(OutageCause::Gateway) },
+            ]
+        );
+
+        // recoveries carry the cause the outage began with
+        tracker.observe_landmarks(landmarks(true, true, Some((isp, true))));
+        let events = tracker.observe(1, Family::V4, Observation::Success, t0 + Duration::from_secs(10));
+        assert!(events.contains(&NetworkEvent::FamilyUp {
+            family: Family::V4,
+            downtime: Duration::from_secs(10),
+            cause: Some(OutageCause::Gateway)
+        }));
+
+        tracker.observe_landmarks(landmarks(false, false, None));
+        assert_eq!(tracker.cause(Family::V4, false), Some(OutageCause::Lan));
+        // every target is down but the ISP's first hop isn't known
+        tracker.observe_landmarks(landmarks(true, true, None));
+        assert_eq!(tracker.cause(Family::V4, true), Some(OutageCause::Isp));
+        assert_eq!(tracker.cause(Family::V4, false), Some(OutageCause::Remote));
+    }
+
+    #[tokio::test]
+    async fn streams_end_once_the_monitor_stops() {
+        let target = Target {
+            host: "127.0.0.1".to_string(),
+            v4: Some("127.0.0.1".parse().unwrap()),
+            v6: None,
+            probe: Probe::Tcp { port: 9, timeout: Duration::from_secs(1) },
+            marking: Marking::default(),
+        };
+        let (handle, mut events) = Monitor::builder()
+            .target(target)
+            .families([Family::V4])
+            .start()
+            .unwrap();
+        let mut subscribed = handle.subscribe();
+        handle.shutdown();
+        while !handle.is_finished() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // the handle is still around, which mustn't keep the streams open
+        let timeout = Duration::from_secs(5);
+        while tokio::time::timeout(timeout, events.next()).await.unwrap().is_some() {}
+        while tokio::time::timeout(timeout, subscribed.next()).await.unwrap().is_some() {}
+        handle.join().await;
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    fmt::Display,
+    io,
+    time::{Duration, Instant}
+};
+
+use tokio::sync::mpsc;
+use trust_dns_resolver::{
+    error::{ResolveError, ResolveErrorKind},
+    proto::{error::ProtoErrorKind, op::ResponseCode},
+    TokioAsyncResolver
+};
+
+/// why resolving a name failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsFailure {
+    /// no nameserver answered in time
+    Timeout,
+    /// the nameserver couldn't answer, usually because it couldn't reach the
+    /// name's authoritative nameservers
+    ServFail,
+    /// the name doesn't exist
+    NxDomain,
+    /// the name exists but has neither A nor AAAA records
+    NoRecords,
+    Other(String),
+}
+
+impl DnsFailure {
+    fn classify(error: &ResolveError) -> Self {
+        match error.kind() {
+            ResolveErrorKind::Timeout => Self::Timeout,
+            ResolveErrorKind::Proto(e) if matches!(e.kind(), ProtoErrorKind::Timeout) => Self::Timeout,
+            ResolveErrorKind::Io(e) if e.kind() == io::ErrorKind::TimedOut => Self::Timeout,
+            ResolveErrorKind::NoRecordsFound { response_code, .. } => match *response_code {
+                ResponseCode::ServFail => Self::ServFail,
+                ResponseCode::NXDomain => Self::NxDomain,
+                ResponseCode::NoError => Self::NoRecords,
+                code => Self::Other(code.to_string()),
+            },
+            _ => Self::Other(error.to_string()),
+        }
+    }
+}
+
+impl Display for DnsFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DnsFailure::Timeout => write!(f, "timed out"),
+            DnsFailure::ServFail => write!(f, "SERVFAIL"),
+            DnsFailure::NxDomain => write!(f, "NXDOMAIN"),
+            DnsFailure::NoRecords => write!(f, "no records"),
+            DnsFailure::Other(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// result of a single DNS probe
+#[derive(Debug, Clone)]
+pub(crate) struct DnsOutcome {
+    /// index of the name being resolved
+    pub name: usize,
+    /// how long resolving took if it succeeded
+    pub result: Result<Duration, DnsFailure>,
+}
+
+async fn lookup(resolver: &TokioAsyncResolver, name: &str) -> Result<Duration, DnsFailure> {
+    let start = Instant::now();
+    match resolver.lookup_ip(name).await {
+        Ok(_) => Ok(start.elapsed()),
+        Err(e) => Err(DnsFailure::classify(&e)),
+    }
+}
+
+/// resolves `name` every `interval` forever, sending each result to `outcomes`
+///
+/// `resolver` shouldn't cache anything, otherwise only the first lookup
+/// actually reaches the nameservers
+pub(crate) async fn monitor_dns(
+    index: usize,
+    name: String,
+    resolver: TokioAsyncResolver,
+    interval: Duration,
+    outcomes: mpsc::Sender<DnsOutcome>
+) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        let result = lookup(&resolver, &name).await;
+
+        if outcomes.send(DnsOutcome { name: index, result }).await.is_err() {
+            // monitor was shut down
+            return;
+        }
+
+        interval.tick().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use trust_dns_resolver::{
+        error::{ResolveError, ResolveErrorKind},
+        proto::op::{Query, ResponseCode}
+    };
+
+    use super::DnsFailure;
+
+    fn no_records(response_code: ResponseCode) -> ResolveError {
+        ResolveError::from(ResolveErrorKind::NoRecordsFound {
+            query: Box::new(Query::default()),
+            soa: None,
+            negative_ttl: None,
+            response_code,
+            trusted: true,
+        })
+    }
+
+    #[test]
+    fn failure_reasons() {
+        let classify = |e: ResolveError| DnsFailure::classify(&e);
+        assert_eq!(classify(ResolveErrorKind::Timeout.into()), DnsFailure::Timeout);
+        assert_eq!(classify(no_records(ResponseCode::ServFail)), DnsFailure::ServFail);
+        assert_eq!(classify(no_records(ResponseCode::NXDomain)), DnsFailure::NxDomain);
+        assert_eq!(classify(no_records(ResponseCode::NoError)), DnsFailure::NoRecords);
+        assert!(matches!(classify(no_records(ResponseCode::Refused)), DnsFailure::Other(_)));
+    }
+}
@@ -0,0 +1,431 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant}
+};
+
+use once_cell::sync::Lazy;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}
+};
+use tokio_rustls::{rustls, TlsConnector};
+use trust_dns_resolver::TokioAsyncResolver;
+use url::Url;
+
+use super::{tcp, Marking};
+
+/// responses are only read up to this many bytes
+const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+
+static TLS_CONFIG: Lazy<Arc<rustls::ClientConfig>> = Lazy::new(|| {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+    }));
+    Arc::new(
+        rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth()
+    )
+});
+
+/// what an HTTP probe checks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpCheck {
+    pub url: Url,
+    /// status the response must have, any below 400 is accepted if `None`
+    pub status: Option<u16>,
+    /// text the response body must contain
+    pub body_contains: Option<String>,
+    pub timeout: Duration,
+}
+
+/// how long each step of an HTTP request took
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HttpTimings {
+    /// looking up the URL's host without the resolver's cache, `None` for IP
+    /// addresses or when the lookup failed, which doesn't fail the request
+    pub dns: Option<Duration>,
+    pub connect: Duration,
+    /// TLS handshake, `None` for plain HTTP
+    pub tls: Option<Duration>,
+    /// from sending the request to the first byte of the response
+    pub ttfb: Duration,
+    /// the whole request from connecting to reading the response, without
+    /// the lookup
+    pub total: Duration,
+}
+
+impl Display for HttpTimings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        if let Some(dns) = self.dns {
+            write!(f, "dns {:.2}ms, ", ms(dns))?;
+        }
+        write!(f, "connect {:.2}ms, ", ms(self.connect))?;
+        if let Some(tls) = self.tls {
+            write!(f, "tls {:.2}ms, ", ms(tls))?;
+        }
+        write!(f, "first byte {:.2}ms", ms(self.ttfb))
+    }
+}
+
+/// response status and as much of the body as was read
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    body: Vec<u8>,
+}
+
+pub(super) async fn get(
+    check: &HttpCheck,
+    addr: IpAddr,
+    resolver: Option<&TokioAsyncResolver>,
+    marking: Marking
+) -> Result<HttpTimings, String> {
+    let request = async {
+        match tokio::time::timeout(check.timeout, request(check, addr, marking)).await {
+            Ok(res) => res,
+            Err(_) => Err(format!("request timed out after {:?}", check.timeout)),
+        }
+    };
+    // the lookup is only timed to be reported, the address was already known,
+    // so it runs alongside the request to keep a slow resolver from failing it
+    let (result, dns) = tokio::join!(request, lookup(check, addr, resolver));
+    result.map(|timings| HttpTimings { dns, ..timings })
+}
+
+/// how long looking up the URL's host over `addr`'s family took, if it's a
+/// domain and the lookup succeeded within the probe's timeout
+async fn lookup(check: &HttpCheck, addr: IpAddr, resolver: Option<&TokioAsyncResolver>) -> Option<Duration> {
+    let (Some(url::Host::Domain(domain)), Some(resolver)) = (check.url.host(), resolver) else {
+        return None;
+    };
+    let start = Instant::now();
+    let lookup = async {
+        match addr {
+            IpAddr::V4(_) => resolver.ipv4_lookup(domain).await.map(|_| ()),
+            IpAddr::V6(_) => resolver.ipv6_lookup(domain).await.map(|_| ()),
+        }
+    };
+    match tokio::time::timeout(check.timeout, lookup).await {
+        Ok(Ok(())) => Some(start.elapsed()),
+        _ => None,
+    }
+}
+
+async fn request(check: &HttpCheck, addr: IpAddr, marking: Marking) -> Result<HttpTimings, String> {
+    let url = &check.url;
+    let port = url.port_or_known_default().ok_or("URL has no port")?;
+
+    let start = Instant::now();
+    let tcp = tcp::open(SocketAddr::new(addr, port), marking).await
+        .map_err(|e| format!("could not connect: {e}"))?;
+    let connect = start.elapsed();
+
+    let (response, tls, ttfb) = match url.scheme() {
+        "https" => {
+            let tls_start = Instant::now();
+            let server_name = server_name(url)?;
+            let stream = TlsConnector::from(TLS_CONFIG.clone()).connect(server_name, tcp).await
+                .map_err(|e| format!("TLS handshake failed: {e}"))?;
+            let tls = tls_start.elapsed();
+            let (response, ttfb) = exchange(stream, "GET", url, &[], b"", check.body_contains.is_some()).await?;
+            (response, Some(tls), ttfb)
+        },
+        "http" => {
+            let (response, ttfb) = exchange(tcp, "GET", url, &[], b"", check.body_contains.is_some()).await?;
+            (response, None, ttfb)
+        },
+        scheme => return Err(format!("unsupported scheme {scheme}")),
+    };
+
+    match check.status {
+        Some(status) if response.status != status => {
+            return Err(format!("expected status {status}, got {}", response.status));
+        },
+        None if response.status >= 400 => return Err(format!("got error status {}", response.status)),
+        _ => {},
+    }
+    if let Some(needle) = &check.body_contains {
+        if !String::from_utf8_lossy(&response.body).contains(needle.as_str()) {
+            return Err(format!("response body did not contain {needle:?}"));
+        }
+    }
+
+    Ok(HttpTimings { dns: None, connect, tls, ttfb, total: start.elapsed() })
+}
+
+/// sends `body` to `url` with a POST, looking up its host with the system's
+/// resolver, returning the response's status, which may be an error one
+pub(crate) async fn post(url: &Url, headers: &[(&str, &str)], body: &[u8], timeout: Duration) -> Result<u16, String> {
+    let send = async {
+        let host = url.host_str().ok_or("URL has no host")?;
+        let port = url.port_or_known_default().ok_or("URL has no port")?;
+        let addrs = tokio::net::lookup_host((host.trim_start_matches('[').trim_end_matches(']'), port)).await
+            .map_err(|e| format!("could not resolve {host}: {e}"))?;
+        let mut error = format!("{host} has no addresses");
+        for addr in addrs {
+            let tcp = match tcp::open(addr, Marking::default()).await {
+                Ok(tcp) => tcp,
+                Err(e) => {
+                    error = format!("could not connect to {addr}: {e}");
+                    continue;
+                },
+            };
+            let (response, _) = match url.scheme() {
+                "https" => {
+                    let server_name = server_name(url)?;
+                    let stream = TlsConnector::from(TLS_CONFIG.clone()).connect(server_name, tcp).await
+                        .map_err(|e| format!("TLS handshake failed: {e}"))?;
+                    exchange(stream, "POST", url, headers, body, false).await?
+                },
+                "http" => exchange(tcp, "POST", url, headers, body, false).await?,
+                scheme => return Err(format!("unsupported scheme {scheme}")),
+            };
+            return Ok(response.status);
+        }
+        Err(error)
+    };
+    match tokio::time::timeout(timeout, send).await {
+        Ok(res) => res,
+        Err(_) => Err(format!("request timed out after {timeout:?}")),
+    }
+}
+
+/// the name the certificate of `url`'s host is checked against, its address
+/// for IP literals, which `host_str` would give with brackets for IPv6
+fn server_name(url: &Url) -> Result<rustls::ServerName, String> {
+    match url.host().ok_or("URL has no host")? {
+        url::Host::Domain(domain) => rustls::ServerName::try_from(domain)
+            .map_err(|_| format!("{domain} is not a valid TLS server name")),
+        url::Host::Ipv4(ip) => Ok(rustls::ServerName::IpAddress(ip.into())),
+        url::Host::Ipv6(ip) => Ok(rustls::ServerName::IpAddress(ip.into())),
+    }
+}
+
+/// sends a request for `url` and reads the response, returning it along with
+/// the time to its first byte
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    method: &str,
+    url: &Url,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    read_body: bool
+) -> Result<(Response, Duration), String> {
+    let mut path = url.path().to_string();
+    if let Some(query) = url.query() {
+        path.push('?');
+        path.push_str(query);
+    }
+    let host = match url.port() {
+        Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+        None => url.host_str().unwrap_or_default().to_string(),
+    };
+    let mut request = format!(
+        "{method} {path} HTTP/1.1\r\nHost: {host}\r\nUser-Agent: {}/{}\r\nAccept: */*\r\nConnection: close\r\n",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{name}: {value}\r\n"));
+    }
+    if !body.is_empty() || method != "GET" {
+        request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    request.push_str("\r\n");
+    let mut request = request.into_bytes();
+    request.extend_from_slice(body);
+
+    let sent = Instant::now();
+    stream.write_all(&request).await.map_err(|e| format!("could not send request: {e}"))?;
+
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 8192];
+    let mut ttfb = None;
+    let mut head_len = None;
+    loop {
+        let n = match stream.read(&mut chunk).await {
+            Ok(n) => n,
+            // lots of servers don't bother closing TLS connections properly
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => 0,
+            Err(e) => return Err(format!("could not read response: {e}")),
+        };
+        ttfb.get_or_insert_with(|| sent.elapsed());
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        if head_len.is_none() {
+            head_len = find(&buf, b"\r\n\r\n").map(|i| i + 4);
+        }
+        match head_len {
+            Some(_) if !read_body => break,
+            Some(len) if body_complete(&buf[..len], &buf[len..]) => break,
+            _ => {},
+        }
+        if buf.len() >= MAX_RESPONSE_SIZE {
+            break;
+        }
+    }
+
+    let head_len = head_len.ok_or("response ended before its headers did")?;
+    let head = String::from_utf8_lossy(&buf[..head_len]);
+    let status = parse_status(&head).ok_or("malformed response status line")?;
+    let body = match header(&head, "transfer-encoding") {
+        Some(te) if te.eq_ignore_ascii_case("chunked") => dechunk(&buf[head_len..]),
+        _ => buf[head_len..].to_vec(),
+    };
+
+    Ok((Response { status, body }, ttfb.unwrap_or_default()))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// parses the status code out of "HTTP/1.1 200 OK"
+fn parse_status(head: &str) -> Option<u16> {
+    let line = head.lines().next()?;
+    let mut parts = line.split_whitespace();
+    if !parts.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// whether the whole body has been read, if it can be known before the
+/// connection is closed
+fn body_complete(head: &[u8], body: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(head);
+    if let Some(len) = header(&head, "content-length").and_then(|l| l.parse::<usize>().ok()) {
+        return body.len() >= len;
+    }
+    match header(&head, "transfer-encoding") {
+        Some(te) if te.eq_ignore_ascii_case("chunked") => body.ends_with(b"0\r\n\r\n"),
+        _ => false,
+    }
+}
+
+/// decodes a chunked body, stopping at anything malformed
+fn dechunk(mut body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    while let Some(line_end) = find(body, b"\r\n") {
+        let size = String::from_utf8_lossy(&body[..line_end]);
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let Ok(size) = usize::from_str_radix(size, 16) else { break };
+        body = &body[line_end + 2..];
+        if size == 0 {
+            break;
+        }
+        let end = size.min(body.len());
+        out.extend_from_slice(&body[..end]);
+        body = &body[end..];
+        body = body.strip_prefix(b"\r\n").unwrap_or(body);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, UdpSocket}};
+    use trust_dns_resolver::{
+        config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+        TokioAsyncResolver
+    };
+
+    use tokio_rustls::rustls::ServerName;
+
+    use super::{dechunk, get, parse_status, server_name, HttpCheck};
+    use crate::probe::Marking;
+
+    #[test]
+    fn status_line() {
+        assert_eq!(parse_status("HTTP/1.1 204 No Content\r\n\r\n"), Some(204));
+        assert_eq!(parse_status("HTTP/1.0 301\r\n"), Some(301));
+        assert_eq!(parse_status("SSH-2.0-OpenSSH\r\n"), None);
+    }
+
+    #[test]
+    fn chunked_body() {
+        assert_eq!(dechunk(b"5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\n\r\n"), b"hello, world");
+    }
+
+    #[test]
+    fn server_names() {
+        let name = |url: &str| server_name(&url.parse().unwrap()).unwrap();
+        assert_eq!(name("https://example.com/"), ServerName::try_from("example.com").unwrap());
+        assert_eq!(name("https://192.0.2.1/"), ServerName::IpAddress("192.0.2.1".parse().unwrap()));
+        assert_eq!(name("https://[2001:db8::1]:8443/"), ServerName::IpAddress("2001:db8::1".parse().unwrap()));
+    }
+
+    async fn serve_once(response: &'static str) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await.unwrap();
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        port
+    }
+
+    fn check(port: u16, status: Option<u16>, body_contains: Option<&str>) -> HttpCheck {
+        HttpCheck {
+            url: format!("http://127.0.0.1:{port}/health").parse().unwrap(),
+            status,
+            body_contains: body_contains.map(str::to_string),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    #[tokio::test]
+    async fn checks_status_and_body() {
+        let addr = "127.0.0.1".parse().unwrap();
+        let ok = "HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\nall ok!";
+
+        let port = serve_once(ok).await;
+        let timings = get(&check(port, Some(200), Some("ok!")), addr, None, Marking::default()).await.unwrap();
+        assert_eq!(timings.dns, None);
+        assert_eq!(timings.tls, None);
+
+        let port = serve_once(ok).await;
+        assert!(get(&check(port, Some(204), None), addr, None, Marking::default()).await.is_err());
+
+        let port = serve_once(ok).await;
+        assert!(get(&check(port, None, Some("broken")), addr, None, Marking::default()).await.is_err());
+
+        let port = serve_once("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n").await;
+        assert!(get(&check(port, None, None), addr, None, Marking::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn slow_lookups_dont_fail_requests() {
+        // a nameserver that never answers
+        let nameserver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = nameserver.local_addr().unwrap().port();
+        let servers = NameServerConfigGroup::from_ips_clear(&["127.0.0.1".parse().unwrap()], port, true);
+        let resolver = TokioAsyncResolver::tokio(ResolverConfig::from_parts(None, vec![], servers), ResolverOpts::default());
+
+        let port = serve_once("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+        let mut check = check(port, Some(200), None);
+        check.url = format!("http://monitored.test:{port}/health").parse().unwrap();
+        check.timeout = Duration::from_millis(500);
+        let timings = get(&check, "127.0.0.1".parse().unwrap(), Some(&resolver), Marking::default()).await.unwrap();
+        assert_eq!(timings.dns, None);
+    }
+}
@@ -0,0 +1,321 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::IpAddr,
+    pin::pin,
+    sync::atomic::{AtomicU16, Ordering},
+    time::{Duration, Instant}
+};
+
+use futures_util::future::join_all;
+use log::{debug, trace};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use surge_ping::{Client, Config, PingIdentifier, PingSequence, ICMP};
+use tokio::{net::UdpSocket, select};
+
+use super::Marking;
+use crate::event::Family;
+
+/// how long to keep listening for duplicate replies once every request of a
+/// burst was answered or timed out
+const DUPLICATE_GRACE: Duration = Duration::from_millis(100);
+
+/// sends several echo requests per probe instead of just one, so that packet
+/// loss and jitter can be measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Burst {
+    /// how many echo requests are sent
+    pub count: u16,
+    /// time between sending each of them
+    pub gap: Duration,
+}
+
+/// loss, jitter and ordering of the replies to a burst of echo requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BurstStats {
+    pub sent: u16,
+    pub received: u16,
+    /// RFC 3550 interarrival jitter of the round trip times, smoothed over
+    /// every burst sent to the address so far
+    pub jitter: Duration,
+    /// replies that arrived after the reply to a later request
+    pub reordered: u16,
+    /// extra copies of replies, `None` if they can't be seen because raw
+    /// ICMP sockets aren't available or failed during the burst
+    pub duplicates: Option<u16>,
+}
+
+impl BurstStats {
+    /// percentage of requests that went unanswered
+    pub fn loss(&self) -> f64 {
+        match self.sent {
+            0 => 0.0,
+            sent => f64::from(sent - self.received) / f64::from(sent) * 100.0,
+        }
+    }
+}
+
+/// a persistent ICMP socket used for every ping to one address
+pub(super) struct IcmpSession {
+    client: Client,
+    /// sees every echo reply, including duplicates the client drops
+    listener: Option<ReplyListener>,
+    ident: u16,
+    next_seq: u16,
+    bursts: u32,
+    /// RFC 3550 jitter estimate in seconds
+    jitter: f64,
+    last_rtt: Option<Duration>,
+}
+
+impl IcmpSession {
+    pub fn new(family: Family, marking: Marking) -> io::Result<Self> {
+        static SESSIONS: AtomicU16 = AtomicU16::new(0);
+
+        let kind = match family {
+            Family::V4 => ICMP::V4,
+            Family::V6 => ICMP::V6,
+        };
+        let client = Client::new(&Config::builder().kind(kind).build())?;
+        mark(&client, family, marking)?;
+        let listener = match ReplyListener::open(family) {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                trace!("can't watch for duplicate {family} echo replies: {e}");
+                None
+            },
+        };
+        Ok(Self {
+            client,
+            listener,
+            ident: (std::process::id() as u16) ^ SESSIONS.fetch_add(1, Ordering::Relaxed).rotate_left(8),
+            next_seq: 0,
+            bursts: 0,
+            jitter: 0.0,
+            last_rtt: None,
+        })
+    }
+
+    /// sends a single echo request with `size` bytes of payload, returning
+    /// its round trip time
+    pub async fn ping(&mut self, addr: IpAddr, timeout: Duration, size: usize) -> Result<Duration, String> {
+        let (result, _) = self.burst(addr, Burst { count: 1, gap: Duration::ZERO }, timeout, size).await;
+        result
+    }
+
+    /// sends `burst.count` echo requests `burst.gap` apart, returning their
+    /// average round trip time, which fails only if none were answered
+    pub async fn burst(
+        &mut self,
+        addr: IpAddr,
+        burst: Burst,
+        timeout: Duration,
+        size: usize
+    ) -> (Result<Duration, String>, BurstStats) {
+        self.bursts = self.bursts.wrapping_add(1);
+        let token = (u64::from(self.ident) << 32 | u64::from(self.bursts)).to_be_bytes();
+        let payload: Vec<u8> = token.iter().copied()
+            .chain((token.len()..).map(|i| i as u8))
+            .take(size)
+            .collect();
+        // replies can only be told apart from others with the whole token
+        let listener = self.listener.as_ref().filter(|_| size >= token.len());
+
+        let first_seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(burst.count);
+
+        let client = &self.client;
+        let payload = &payload;
+        let ident = PingIdentifier(self.ident);
+        let pings = join_all((0..burst.count).map(|i| async move {
+            tokio::time::sleep(burst.gap * u32::from(i)).await;
+            let mut pinger = client.pinger(addr, ident).await;
+            pinger.timeout(timeout);
+            let sent = Instant::now();
+            let result = pinger.ping(PingSequence(first_seq.wrapping_add(i)), payload).await;
+            if let Ok((packet, _)) = &result {
+                trace!("{packet:?}");
+            }
+            (sent, result.map(|(_, rtt)| rtt))
+        }));
+
+        let mut copies = HashMap::new();
+        // duplicates aren't counted for bursts the listener failed during
+        let mut listen_error = None;
+        let results = match listener {
+            Some(listener) => {
+                let mut pings = pin!(pings);
+                let results = select! {
+                    results = &mut pings => Some(results),
+                    e = listener.listen(addr, &token, &mut copies) => {
+                        listen_error = Some(e);
+                        None
+                    },
+                };
+                match results {
+                    Some(results) => {
+                        let grace = tokio::time::timeout(DUPLICATE_GRACE, listener.listen(addr, &token, &mut copies));
+                        listen_error = grace.await.ok();
+                        results
+                    },
+                    None => pings.await,
+                }
+            },
+            None => pings.await,
+        };
+        if let Some(e) = &listen_error {
+            debug!("could not listen for duplicate echo replies from {addr}: {e}");
+        }
+
+        let mut rtts = Vec::new();
+        let mut arrivals = Vec::new();
+        let mut error = None;
+        for (i, (sent, result)) in results.into_iter().enumerate() {
+            match result {
+                Ok(rtt) => {
+                    rtts.push(rtt);
+                    arrivals.push((sent + rtt, i));
+                    if let Some(last) = self.last_rtt {
+                        let d = rtt.as_secs_f64() - last.as_secs_f64();
+                        self.jitter += (d.abs() - self.jitter) / 16.0;
+                    }
+                    self.last_rtt = Some(rtt);
+                },
+                Err(e) => error = Some(e.to_string()),
+            }
+        }
+
+        let stats = BurstStats {
+            sent: burst.count,
+            received: rtts.len() as u16,
+            jitter: Duration::from_secs_f64(self.jitter),
+            reordered: reordered(arrivals),
+            duplicates: listener.filter(|_| listen_error.is_none()).map(|_| copies.values().map(|n: &u16| n.saturating_sub(1)).sum()),
+        };
+        let result = match rtts.len() {
+            0 => Err(error.unwrap_or_else(|| "no echo requests were sent".to_string())),
+            n => Ok(rtts.iter().sum::<Duration>() / n as u32),
+        };
+        (result, stats)
+    }
+}
+
+/// marks the packets sent by `client`
+pub(super) fn mark(client: &Client, family: Family, marking: Marking) -> io::Result<()> {
+    // the client keeps the socket open for at least as long as it's borrowed
+    #[cfg(unix)]
+    let socket = unsafe { std::os::fd::BorrowedFd::borrow_raw(client.get_socket().get_native_sock()) };
+    #[cfg(windows)]
+    let socket = unsafe { std::os::windows::io::BorrowedSocket::borrow_raw(client.get_socket().get_native_sock()) };
+    marking.apply(SockRef::from(&socket), family)
+}
+
+/// counts replies that arrived after the reply to a later request, given
+/// their arrival times and the order the requests were sent in
+fn reordered(mut arrivals: Vec<(Instant, usize)>) -> u16 {
+    arrivals.sort();
+    let mut latest = None;
+    let mut reordered = 0;
+    for (_, i) in arrivals {
+        match latest {
+            Some(latest) if i < latest => reordered += 1,
+            _ => latest = Some(i),
+        }
+    }
+    reordered
+}
+
+/// a raw ICMP socket that gets a copy of every echo reply
+struct ReplyListener {
+    socket: UdpSocket,
+    family: Family,
+}
+
+impl ReplyListener {
+    fn open(family: Family) -> io::Result<Self> {
+        let (domain, protocol) = match family {
+            Family::V4 => (Domain::IPV4, Protocol::ICMPV4),
+            Family::V6 => (Domain::IPV6, Protocol::ICMPV6),
+        };
+        let socket = Socket::new(domain, Type::RAW, Some(protocol))?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket: UdpSocket::from_std(socket.into())?, family })
+    }
+
+    /// counts the echo replies from `addr` whose payload starts with `token`
+    /// by sequence number, until the socket fails
+    async fn listen(&self, addr: IpAddr, token: &[u8], copies: &mut HashMap<u16, u16>) -> io::Error {
+        let mut buf = [0u8; 2048];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => return e,
+            };
+            if from.ip() != addr {
+                continue;
+            }
+            if let Some(seq) = parse_reply(&buf[..len], self.family, token) {
+                *copies.entry(seq).or_default() += 1;
+            }
+        }
+    }
+}
+
+/// the sequence number of an echo reply carrying `token`, raw IPv4 sockets
+/// include the IP header while IPv6 ones don't
+fn parse_reply(packet: &[u8], family: Family, token: &[u8]) -> Option<u16> {
+    let (icmp, reply_type) = match family {
+        Family::V4 => (packet.get(usize::from(packet.first()? & 0x0f) * 4..)?, 0),
+        Family::V6 => (packet, 129),
+    };
+    if *icmp.first()? != reply_type {
+        return None;
+    }
+    // type, code, checksum and identifier come before the sequence number
+    let seq = u16::from_be_bytes([*icmp.get(6)?, *icmp.get(7)?]);
+    icmp.get(8..)?.starts_with(token).then_some(seq)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{parse_reply, reordered, BurstStats};
+    use crate::event::Family;
+
+    #[test]
+    fn counts_reordered_replies() {
+        let t0 = Instant::now();
+        let at = |ms| t0 + Duration::from_millis(ms);
+
+        assert_eq!(reordered(vec![(at(10), 0), (at(20), 1), (at(30), 2)]), 0);
+        // 1 overtook 0, and 3 overtook 2
+        assert_eq!(reordered(vec![(at(25), 0), (at(20), 1), (at(50), 2), (at(40), 3)]), 2);
+        // a single late reply is only counted once
+        assert_eq!(reordered(vec![(at(90), 0), (at(20), 1), (at(30), 2)]), 1);
+    }
+
+    #[test]
+    fn parses_echo_replies() {
+        let token = [1, 2, 3, 4];
+        let mut v6 = vec![129, 0, 0, 0, 0, 7, 0, 42];
+        v6.extend_from_slice(&token);
+        assert_eq!(parse_reply(&v6, Family::V6, &token), Some(42));
+        assert_eq!(parse_reply(&v6, Family::V6, &[9, 9]), None);
+
+        // 20 byte IPv4 header in front of an echo request rather than a reply
+        let mut v4 = vec![0x45];
+        v4.resize(20, 0);
+        v4.extend_from_slice(&[8, 0, 0, 0, 0, 7, 0, 42]);
+        v4.extend_from_slice(&token);
+        assert_eq!(parse_reply(&v4, Family::V4, &token), None);
+        v4[20] = 0;
+        assert_eq!(parse_reply(&v4, Family::V4, &token), Some(42));
+    }
+
+    #[test]
+    fn loss_percentage() {
+        let stats = BurstStats { sent: 8, received: 6, jitter: Duration::ZERO, reordered: 0, duplicates: None };
+        assert_eq!(stats.loss(), 25.0);
+    }
+}
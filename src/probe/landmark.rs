@@ -0,0 +1,242 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::{Duration, Instant}
+};
+
+use log::{debug, warn};
+use surge_ping::{Client, Config, PingIdentifier, PingSequence, ICMP};
+use tokio::sync::{mpsc, watch};
+
+use super::{trace::traceroute, DEFAULT_PING_TIMEOUT};
+use crate::event::Family;
+
+/// how long to wait before looking for the first ISP hop again when it
+/// couldn't be found
+const DISCOVERY_BACKOFF: Duration = Duration::from_secs(600);
+
+/// the route packets that leave the local network take, from the routing
+/// table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct DefaultRoute {
+    pub interface: String,
+    /// `None` for point-to-point links like PPP, which have no gateway
+    pub gateway: Option<IpAddr>,
+    metric: u32,
+}
+
+impl DefaultRoute {
+    /// the default route of `family` with the lowest metric, only supported
+    /// on Linux
+    pub fn current(family: Family) -> Option<Self> {
+        let (path, parse): (_, fn(&str) -> Option<Self>) = match family {
+            Family::V4 => ("/proc/net/route", parse_v4_route),
+            Family::V6 => ("/proc/net/ipv6_route", parse_v6_route),
+        };
+        let table = std::fs::read_to_string(path).ok()?;
+        table.lines().filter_map(parse).min_by_key(|route| route.metric)
+    }
+
+    /// whether the route's interface is up, interfaces that don't report it,
+    /// like most tunnels, count as up
+    pub fn is_up(&self) -> bool {
+        match std::fs::read_to_string(format!("/sys/class/net/{}/operstate", self.interface)) {
+            Ok(state) => matches!(state.trim(), "up" | "unknown"),
+            Err(_) => false,
+        }
+    }
+}
+
+/// a default route line of /proc/net/route, whose addresses are in host byte
+/// order
+fn parse_v4_route(line: &str) -> Option<DefaultRoute> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [interface, destination, gateway, _flags, _refs, _use, metric, mask, ..] = fields.as_slice() else {
+        return None;
+    };
+    if *destination != "00000000" || *mask != "00000000" {
+        return None;
+    }
+    let gateway = Ipv4Addr::from(u32::from_str_radix(gateway, 16).ok()?.swap_bytes());
+    Some(DefaultRoute {
+        interface: interface.to_string(),
+        gateway: Some(IpAddr::V4(gateway)).filter(|_| !gateway.is_unspecified()),
+        metric: metric.parse().ok()?,
+    })
+}
+
+/// a default route line of /proc/net/ipv6_route
+fn parse_v6_route(line: &str) -> Option<DefaultRoute> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [destination, prefix, _source, _source_prefix, next_hop, metric, _refs, _use, _flags, interface] = fields.as_slice() else {
+        return None;
+    };
+    if u128::from_str_radix(destination, 16).ok()? != 0 || *prefix != "00" || *interface == "lo" {
+        return None;
+    }
+    let gateway = Ipv6Addr::from(u128::from_str_radix(next_hop, 16).ok()?);
+    Some(DefaultRoute {
+        interface: interface.to_string(),
+        gateway: Some(IpAddr::V6(gateway)).filter(|_| !gateway.is_unspecified()),
+        metric: u32::from_str_radix(metric, 16).ok()?,
+    })
+}
+
+/// whether `addr` is only used within local networks, so it can't be a hop
+/// of the ISP
+fn is_private(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => addr.is_private() || addr.is_link_local() || addr.is_loopback(),
+        // unique local and link local addresses
+        IpAddr::V6(addr) => (addr.segments()[0] & 0xfe00) == 0xfc00
+            || (addr.segments()[0] & 0xffc0) == 0xfe80
+            || addr.is_loopback(),
+    }
+}
+
+/// whether the local link, the gateway and the first hop of the ISP answered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LandmarkOutcome {
+    pub family: Family,
+    /// whether there's a default route over an interface that's up
+    pub link_up: bool,
+    /// the gateway and whether it answered
+    pub gateway: Option<(IpAddr, bool)>,
+    /// the first hop past the local network and whether it answered
+    pub isp: Option<(IpAddr, bool)>,
+}
+
+/// whether `addr` answers either of two pings
+async fn answers(client: &Client, addr: IpAddr, ident: PingIdentifier, seq: &mut u16) -> bool {
+    let mut pinger = client.pinger(addr, ident).await;
+    pinger.timeout(DEFAULT_PING_TIMEOUT);
+    for _ in 0..2 {
+        *seq = seq.wrapping_add(1);
+        if pinger.ping(PingSequence(*seq), &[0; 16]).await.is_ok() {
+            return true;
+        }
+    }
+    false
+}
+
+/// pings the gateway and the first hop of the ISP on the way to `toward`
+/// every `interval` forever, sending each result to `outcomes`
+///
+/// the ISP's first hop is found by tracing the route to `toward` once the
+/// gateway answers, and again whenever the default route changes
+pub(crate) async fn monitor_landmarks(
+    family: Family,
+    toward: watch::Receiver<IpAddr>,
+    interval: Duration,
+    outcomes: mpsc::Sender<LandmarkOutcome>
+) {
+    let kind = match family {
+        Family::V4 => ICMP::V4,
+        Family::V6 => ICMP::V6,
+    };
+    let ident = PingIdentifier(std::process::id() as u16 ^ 0x6c6d ^ (family as u16));
+    let mut seq = 0;
+    // bound to the route's interface so link local gateways can be reached
+    let mut client: Option<(String, Client)> = None;
+    let mut route = None;
+    let mut isp = None;
+    let mut next_discovery = Instant::now();
+
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        let current = DefaultRoute::current(family);
+        if current != route {
+            debug!("{family} default route is now {current:?}");
+            route = current;
+            isp = None;
+            next_discovery = Instant::now();
+        }
+
+        let mut outcome = LandmarkOutcome { family, link_up: false, gateway: None, isp: None };
+        if let Some(route) = route.as_ref().filter(|route| route.is_up()) {
+            outcome.link_up = true;
+            if client.as_ref().map(|(interface, _)| interface) != Some(&route.interface) {
+                let config = Config::builder().kind(kind).interface(&route.interface).build();
+                client = match Client::new(&config) {
+                    Ok(new) => Some((route.interface.clone(), new)),
+                    Err(e) => {
+                        warn!("could not open {family} socket for pinging the gateway: {e}");
+                        None
+                    },
+                };
+            }
+        }
+
+        if let (true, Some((_, client)), Some(route)) = (outcome.link_up, &client, &route) {
+            if let Some(gateway) = route.gateway {
+                outcome.gateway = Some((gateway, answers(client, gateway, ident, &mut seq).await));
+            }
+            let gateway_answers = outcome.gateway.is_none_or(|(_, answered)| answered);
+            if isp.is_none() && gateway_answers && Instant::now() >= next_discovery {
+                let target = *toward.borrow();
+                isp = match traceroute(target).await {
+                    Ok(trace) => trace.hops.iter()
+                        .filter_map(|hop| hop.addr)
+                        .find(|addr| Some(*addr) != route.gateway && *addr != target && !is_private(*addr)),
+                    Err(e) => {
+                        debug!("could not trace the route to {target} for finding the ISP: {e}");
+                        None
+                    },
+                };
+                match isp {
+                    Some(addr) => debug!("first {family} hop of the ISP is {addr}"),
+                    None => next_discovery = Instant::now() + DISCOVERY_BACKOFF,
+                }
+            }
+            if let Some(addr) = isp {
+                outcome.isp = Some((addr, answers(client, addr, ident, &mut seq).await));
+            }
+        }
+
+        if outcomes.send(outcome).await.is_err() {
+            // monitor was shut down
+            return;
+        }
+
+        interval.tick().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_private, parse_v4_route, parse_v6_route, DefaultRoute};
+
+    #[test]
+    fn parses_default_routes() {
+        let v4 = "eth0\t00000000\t0102A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0";
+        assert_eq!(
+            parse_v4_route(v4),
+            Some(DefaultRoute { interface: "eth0".to_string(), gateway: Some("192.168.2.1".parse().unwrap()), metric: 100 })
+        );
+        assert_eq!(parse_v4_route("eth0\t0002A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0"), None);
+        assert_eq!(parse_v4_route("Iface\tDestination\tGateway\tFlags"), None);
+        // point-to-point links have no gateway
+        let ppp = "ppp0\t00000000\t00000000\t0001\t0\t0\t0\t00000000\t0\t0\t0";
+        assert_eq!(parse_v4_route(ppp).unwrap().gateway, None);
+
+        let v6 = "00000000000000000000000000000000 00 00000000000000000000000000000000 00 \
+            fe800000000000000000000000000001 00000400 00000001 00000000 00000003 eth0";
+        assert_eq!(
+            parse_v6_route(v6),
+            Some(DefaultRoute { interface: "eth0".to_string(), gateway: Some("fe80::1".parse().unwrap()), metric: 1024 })
+        );
+        let unreachable = "00000000000000000000000000000000 00 00000000000000000000000000000000 00 \
+            00000000000000000000000000000000 ffffffff 00000001 00000000 00200200 lo";
+        assert_eq!(parse_v6_route(unreachable), None);
+    }
+
+    #[test]
+    fn private_addresses() {
+        assert!(is_private("192.168.0.1".parse().unwrap()));
+        assert!(is_private("fe80::1".parse().unwrap()));
+        assert!(is_private("fd00::1".parse().unwrap()));
+        assert!(!is_private("100.64.0.1".parse().unwrap()));
+        assert!(!is_private("2001:db8::1".parse().unwrap()));
+    }
+}
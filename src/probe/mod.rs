@@ -0,0 +1,236 @@
+//! the different ways targets can be probed
+
+use std::{fmt::Display, io, net::IpAddr, time::Duration};
+
+use socket2::SockRef;
+use tokio::sync::{mpsc, watch};
+use trust_dns_resolver::TokioAsyncResolver;
+
+use crate::event::Family;
+
+mod dns;
+mod http;
+mod icmp;
+mod landmark;
+mod pmtu;
+mod tcp;
+mod trace;
+
+pub use dns::DnsFailure;
+pub use http::{HttpCheck, HttpTimings};
+pub use icmp::{Burst, BurstStats};
+pub use pmtu::PathMtuProbe;
+pub use trace::{Hop, Route};
+pub(crate) use dns::monitor_dns;
+pub(crate) use http::post;
+pub(crate) use landmark::{monitor_landmarks, LandmarkOutcome};
+pub(crate) use pmtu::monitor_pmtu;
+pub(crate) use trace::traceroute;
+
+/// how long TCP and HTTP probes wait by default
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// how long pings wait for a reply by default
+pub const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// how many bytes of payload echo requests carry by default
+pub const DEFAULT_PAYLOAD_SIZE: usize = 256;
+
+/// how a target is probed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Probe {
+    /// ICMP echo requests with `size` bytes of payload
+    Icmp {
+        timeout: Duration,
+        size: usize,
+    },
+    /// TCP handshakes with the given port
+    Tcp {
+        port: u16,
+        timeout: Duration,
+    },
+    /// HTTP(S) GET requests, checking the response's status and body
+    Http(HttpCheck),
+}
+
+impl Default for Probe {
+    fn default() -> Self {
+        Probe::Icmp { timeout: DEFAULT_PING_TIMEOUT, size: DEFAULT_PAYLOAD_SIZE }
+    }
+}
+
+impl Probe {
+    pub fn kind(&self) -> ProbeKind {
+        match self {
+            Probe::Icmp { .. } => ProbeKind::Icmp,
+            Probe::Tcp { .. } => ProbeKind::Tcp,
+            Probe::Http(_) => ProbeKind::Http,
+        }
+    }
+
+    /// probes `addr` once, returning how long it took, along with the loss
+    /// and jitter of pings when bursts are enabled
+    async fn run(&self, addr: IpAddr, ctx: &mut ProbeContext) -> (Result<Measurement, String>, Option<BurstStats>) {
+        match self {
+            Probe::Icmp { timeout, size } => {
+                let session = match ctx.icmp.as_mut() {
+                    Some(session) => session,
+                    None => match icmp::IcmpSession::new(Family::of(&addr), ctx.marking) {
+                        Ok(session) => ctx.icmp.insert(session),
+                        Err(e) => return (Err(format!("could not open ICMP socket: {e}")), None),
+                    },
+                };
+                match ctx.burst {
+                    Some(burst) => {
+                        let (result, stats) = session.burst(addr, burst, *timeout, *size).await;
+                        (result.map(Measurement::from), Some(stats))
+                    },
+                    None => (session.ping(addr, *timeout, *size).await.map(Measurement::from), None),
+                }
+            },
+            Probe::Tcp { port, timeout } => {
+                (tcp::connect((addr, *port).into(), *timeout, ctx.marking).await.map(Measurement::from), None)
+            },
+            Probe::Http(check) => {
+                let result = http::get(check, addr, ctx.resolver.as_ref(), ctx.marking).await
+                    .map(|timings| Measurement { rtt: timings.total, http: Some(timings) });
+                (result, None)
+            },
+        }
+    }
+}
+
+impl Display for Probe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Probe::Icmp { .. } => write!(f, "ping"),
+            Probe::Tcp { port, .. } => write!(f, "TCP port {port}"),
+            Probe::Http(check) => write!(f, "GET {}", check.url),
+        }
+    }
+}
+
+/// how the packets of a probe are marked, the system's defaults are used for
+/// anything that's `None`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Marking {
+    /// TTL of IPv4 packets or hop limit of IPv6 ones
+    pub ttl: Option<u32>,
+    /// differentiated services code point, from 0 to 63
+    pub dscp: Option<u8>,
+}
+
+impl Marking {
+    fn apply(&self, socket: SockRef, family: Family) -> io::Result<()> {
+        if let Some(ttl) = self.ttl {
+            match family {
+                Family::V4 => socket.set_ttl(ttl)?,
+                Family::V6 => socket.set_unicast_hops_v6(ttl)?,
+            }
+        }
+        if let Some(dscp) = self.dscp {
+            // DSCP is the upper 6 bits of the TOS or traffic class byte
+            let tos = u32::from(dscp) << 2;
+            match family {
+                Family::V4 => socket.set_tos(tos)?,
+                #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd"))]
+                Family::V6 => socket.set_tclass_v6(tos)?,
+                #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd")))]
+                Family::V6 => return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "DSCP marking of IPv6 packets isn't supported on this platform"
+                )),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// which kind of [`Probe`] produced a result
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ProbeKind {
+    Icmp,
+    Tcp,
+    Http,
+}
+
+impl Display for ProbeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProbeKind::Icmp => write!(f, "ping"),
+            ProbeKind::Tcp => write!(f, "TCP connection"),
+            ProbeKind::Http => write!(f, "HTTP request"),
+        }
+    }
+}
+
+/// what a successful probe measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Measurement {
+    pub rtt: Duration,
+    /// breakdown of the time taken by HTTP probes
+    pub http: Option<HttpTimings>,
+}
+
+impl From<Duration> for Measurement {
+    fn from(rtt: Duration) -> Self {
+        Self { rtt, http: None }
+    }
+}
+
+/// result of a single probe
+#[derive(Debug, Clone)]
+pub(crate) struct ProbeOutcome {
+    /// index of the target being probed
+    pub target: usize,
+    pub kind: ProbeKind,
+    pub addr: IpAddr,
+    /// what the probe measured if it succeeded
+    pub result: Result<Measurement, String>,
+    /// loss and jitter of the pings, if bursts are enabled
+    pub burst: Option<BurstStats>,
+}
+
+/// what probes of a single address keep between runs
+pub(crate) struct ProbeContext {
+    /// an uncached resolver only used for timing the DNS lookups of HTTP
+    /// probes, the requests themselves always go to the probed address
+    resolver: Option<TokioAsyncResolver>,
+    burst: Option<Burst>,
+    marking: Marking,
+    /// opened on the first ping and reused after that
+    icmp: Option<icmp::IcmpSession>,
+}
+
+impl ProbeContext {
+    pub fn new(resolver: Option<TokioAsyncResolver>, burst: Option<Burst>, marking: Marking) -> Self {
+        Self { resolver, burst, marking, icmp: None }
+    }
+}
+
+/// probes `addr` every `interval` forever, sending each result to `outcomes`
+///
+/// `addr` may change between probes when the target is re-resolved
+pub(crate) async fn monitor_ip(
+    target: usize,
+    probe: Probe,
+    addr: watch::Receiver<IpAddr>,
+    interval: Duration,
+    mut ctx: ProbeContext,
+    outcomes: mpsc::Sender<ProbeOutcome>
+) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        let addr = *addr.borrow();
+        let (result, burst) = probe.run(addr, &mut ctx).await;
+
+        if outcomes.send(ProbeOutcome { target, kind: probe.kind(), addr, result, burst }).await.is_err() {
+            // monitor was shut down
+            return;
+        }
+
+        interval.tick().await;
+    }
+}
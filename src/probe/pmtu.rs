@@ -0,0 +1,189 @@
+use std::{io, net::IpAddr, time::Duration};
+
+use log::{trace, warn};
+use surge_ping::{Client, Config, PingIdentifier, PingSequence, ICMP};
+use tokio::sync::{mpsc, watch};
+
+use super::{icmp::mark, Marking, DEFAULT_PING_TIMEOUT};
+use crate::event::Family;
+
+/// how many echo requests of each size may go unanswered before the size is
+/// considered too large, so a single lost packet doesn't look like a smaller
+/// path MTU
+const ATTEMPTS: u16 = 2;
+
+/// periodically sends don't-fragment echo requests of several sizes to find
+/// the largest packets that make it to a target
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathMtuProbe {
+    /// sizes of the whole IP packets that are tried, from smallest to largest
+    pub sizes: Vec<usize>,
+    /// time between measurements
+    pub interval: Duration,
+}
+
+impl Default for PathMtuProbe {
+    fn default() -> Self {
+        Self {
+            // IPv6's minimum MTU, common tunnel and PPPoE MTUs, then Ethernet's
+            sizes: vec![1280, 1400, 1420, 1460, 1480, 1492, 1500],
+            interval: Duration::from_secs(300),
+        }
+    }
+}
+
+/// bytes taken up by the IP and ICMP headers of an echo request
+fn overhead(family: Family) -> usize {
+    match family {
+        Family::V4 => 20 + 8,
+        Family::V6 => 40 + 8,
+    }
+}
+
+/// result of a single path MTU measurement
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PmtuOutcome {
+    /// index of the target being probed
+    pub target: usize,
+    pub addr: IpAddr,
+    /// largest packet that got through, `None` if not even the smallest did
+    pub mtu: Option<usize>,
+    /// smallest packet that didn't get through, `None` if every size did
+    pub blocked: Option<usize>,
+}
+
+/// makes every echo request sent by `client` have don't fragment set, while
+/// ignoring the path MTU the kernel has cached so larger packets are still
+/// sent rather than failing locally
+fn dont_fragment(client: &Client, family: Family) -> io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let (level, name, value) = match family {
+            Family::V4 => (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_PROBE),
+            Family::V6 => (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, libc::IPV6_PMTUDISC_PROBE),
+        };
+        let result = unsafe {
+            libc::setsockopt(
+                client.get_socket().get_native_sock(),
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of_val(&value) as libc::socklen_t
+            )
+        };
+        match result {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let _ = (client, family);
+        Err(io::Error::new(io::ErrorKind::Unsupported, "path MTU probes are only supported on Linux"))
+    }
+}
+
+/// sends echo requests of increasing sizes to `addr` until one of them goes
+/// unanswered
+async fn measure(
+    client: &Client,
+    ident: PingIdentifier,
+    seq: &mut u16,
+    addr: IpAddr,
+    sizes: &[usize]
+) -> (Option<usize>, Option<usize>) {
+    let family = Family::of(&addr);
+    let mut pinger = client.pinger(addr, ident).await;
+    pinger.timeout(DEFAULT_PING_TIMEOUT);
+
+    let mut mtu = None;
+    for &size in sizes {
+        let payload = vec![0u8; size.saturating_sub(overhead(family))];
+        let mut answered = false;
+        for _ in 0..ATTEMPTS {
+            *seq = seq.wrapping_add(1);
+            match pinger.ping(PingSequence(*seq), &payload).await {
+                Ok(_) => {
+                    answered = true;
+                    break;
+                },
+                Err(e) => trace!("{size} byte echo request to {addr} failed: {e}"),
+            }
+        }
+        if !answered {
+            return (mtu, Some(size));
+        }
+        mtu = Some(size);
+    }
+    (mtu, None)
+}
+
+/// measures the path MTU to `addr` every `probe.interval` forever, sending
+/// each result to `outcomes`
+///
+/// `addr` may change between measurements when the target is re-resolved,
+/// but never to a different family
+pub(crate) async fn monitor_pmtu(
+    target: usize,
+    probe: PathMtuProbe,
+    addr: watch::Receiver<IpAddr>,
+    marking: Marking,
+    outcomes: mpsc::Sender<PmtuOutcome>
+) {
+    let family = Family::of(&addr.borrow());
+    let kind = match family {
+        Family::V4 => ICMP::V4,
+        Family::V6 => ICMP::V6,
+    };
+    let client = Client::new(&Config::builder().kind(kind).build())
+        .and_then(|client| dont_fragment(&client, family).map(|_| client))
+        .and_then(|client| mark(&client, family, marking).map(|_| client));
+    let client = match client {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("could not open {family} socket for measuring the path MTU: {e}");
+            return;
+        },
+    };
+    let ident = PingIdentifier(std::process::id() as u16 ^ 0x4d54 ^ target as u16);
+    let mut seq = 0;
+
+    let mut interval = tokio::time::interval(probe.interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        let addr = *addr.borrow();
+        let (mtu, blocked) = measure(&client, ident, &mut seq, addr, &probe.sizes).await;
+
+        if outcomes.send(PmtuOutcome { target, addr, mtu, blocked }).await.is_err() {
+            // monitor was shut down
+            return;
+        }
+
+        interval.tick().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::{mpsc, watch};
+
+    use super::{monitor_pmtu, PathMtuProbe};
+    use crate::probe::Marking;
+
+    #[tokio::test]
+    async fn loopback_fits_every_size() {
+        let (_addr_tx, addr) = watch::channel("127.0.0.1".parse().unwrap());
+        let (tx, mut rx) = mpsc::channel(1);
+        let probe = PathMtuProbe { sizes: vec![576, 1500, 9000], interval: Duration::from_secs(60) };
+        let task = tokio::spawn(monitor_pmtu(0, probe, addr, Marking::default(), tx));
+
+        // no outcome at all if ICMP sockets can't be opened here
+        if let Some(outcome) = rx.recv().await {
+            assert_eq!((outcome.mtu, outcome.blocked), (Some(9000), None));
+        }
+        task.abort();
+    }
+}
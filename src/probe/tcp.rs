@@ -0,0 +1,48 @@
+use std::{net::SocketAddr, time::{Duration, Instant}};
+
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use tokio::net::{TcpSocket, TcpStream};
+
+use super::Marking;
+use crate::event::Family;
+
+/// opens a TCP connection to `addr` with its packets marked as in `marking`
+pub(super) async fn open(addr: SocketAddr, marking: Marking) -> std::io::Result<TcpStream> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    marking.apply(SockRef::from(&socket), Family::of(&addr.ip()))?;
+    socket.set_nonblocking(true)?;
+    TcpSocket::from_std_stream(socket.into()).connect(addr).await
+}
+
+/// completes a TCP handshake with `addr`, returning how long it took
+pub(super) async fn connect(addr: SocketAddr, timeout: Duration, marking: Marking) -> Result<Duration, String> {
+    let start = Instant::now();
+    match tokio::time::timeout(timeout, open(addr, marking)).await {
+        Ok(Ok(_stream)) => Ok(start.elapsed()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("connection timed out after {timeout:?}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+
+    use super::connect;
+    use crate::probe::Marking;
+
+    #[tokio::test]
+    async fn connects_to_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let marked = Marking { ttl: Some(8), dscp: Some(46) };
+
+        assert!(connect(addr, Duration::from_secs(1), Marking::default()).await.is_ok());
+        assert!(connect(addr, Duration::from_secs(1), marked).await.is_ok());
+
+        drop(listener);
+        assert!(connect(addr, Duration::from_secs(1), Marking::default()).await.is_err());
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    fmt::Display,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicU16, Ordering},
+    time::{Duration, Instant}
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+use crate::event::Family;
+
+/// highest TTL a traceroute goes up to
+const MAX_HOPS: u8 = 30;
+
+/// how long each hop gets to answer
+const HOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// a traceroute gives up after this many hops in a row didn't answer, since
+/// nothing past where an outage is will
+const MAX_SILENT_HOPS: u8 = 5;
+
+/// a single hop of a traced route
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hop {
+    pub ttl: u8,
+    /// who answered, `None` if nothing did in time
+    pub addr: Option<IpAddr>,
+    pub rtt: Option<Duration>,
+}
+
+/// the hops packets take to reach a target
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub hops: Vec<Hop>,
+    /// whether the target itself answered
+    pub reached: bool,
+}
+
+impl Route {
+    /// the furthest hop that answered
+    pub fn last_responsive(&self) -> Option<&Hop> {
+        self.hops.iter().rev().find(|hop| hop.addr.is_some())
+    }
+}
+
+impl Display for Route {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, hop) in self.hops.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            match (hop.addr, hop.rtt) {
+                (Some(addr), Some(rtt)) => write!(f, "{} {addr} {:.2}ms", hop.ttl, rtt.as_secs_f64() * 1000.0)?,
+                _ => write!(f, "{} *", hop.ttl)?,
+            }
+        }
+        match (self.reached, self.last_responsive()) {
+            (true, _) => write!(f, ", reached the target"),
+            (false, Some(hop)) => write!(
+                f,
+                ", last responsive hop was {} ({})",
+                hop.ttl,
+                hop.addr.expect("responsive hops have an address")
+            ),
+            (false, None) => write!(f, ", no hop responded"),
+        }
+    }
+}
+
+/// traces the route to `addr` with ICMP echo requests of increasing TTL,
+/// which needs a raw socket
+pub(crate) async fn traceroute(addr: IpAddr) -> io::Result<Route> {
+    static TRACES: AtomicU16 = AtomicU16::new(0);
+
+    let family = Family::of(&addr);
+    let (domain, protocol) = match family {
+        Family::V4 => (Domain::IPV4, Protocol::ICMPV4),
+        Family::V6 => (Domain::IPV6, Protocol::ICMPV6),
+    };
+    let socket = Socket::new(domain, Type::RAW, Some(protocol))?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket.into())?;
+    let ident = (std::process::id() as u16) ^ TRACES.fetch_add(1, Ordering::Relaxed).rotate_left(4) ^ 0x7472;
+
+    let mut route = Route { hops: Vec::new(), reached: false };
+    let mut silent = 0;
+    for ttl in 1..=MAX_HOPS {
+        match family {
+            Family::V4 => socket.set_ttl(u32::from(ttl))?,
+            Family::V6 => socket2::SockRef::from(&socket).set_unicast_hops_v6(u32::from(ttl))?,
+        }
+        let request = echo_request(family, ident, u16::from(ttl));
+        let sent = Instant::now();
+        socket.send_to(&request, SocketAddr::new(addr, 0)).await?;
+
+        let reply = tokio::time::timeout(HOP_TIMEOUT, async {
+            let mut buf = [0u8; 1500];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await?;
+                if let Some(reply) = parse_reply(&buf[..len], family, ident, u16::from(ttl)) {
+                    return io::Result::Ok((from.ip(), reply));
+                }
+            }
+        }).await;
+
+        match reply {
+            Ok(Ok((from, reply))) => {
+                silent = 0;
+                route.hops.push(Hop { ttl, addr: Some(from), rtt: Some(sent.elapsed()) });
+                if reply == Reply::Echo || from == addr {
+                    route.reached = true;
+                    break;
+                }
+            },
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                silent += 1;
+                route.hops.push(Hop { ttl, addr: None, rtt: None });
+                if silent >= MAX_SILENT_HOPS {
+                    break;
+                }
+            },
+        }
+    }
+    Ok(route)
+}
+
+/// an ICMP echo request, the kernel fills in the checksum of ICMPv6 ones
+fn echo_request(family: Family, ident: u16, seq: u16) -> Vec<u8> {
+    let kind = match family {
+        Family::V4 => 8,
+        Family::V6 => 128,
+    };
+    let mut packet = vec![kind, 0, 0, 0];
+    packet.extend_from_slice(&ident.to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(b"network_monitor traceroute");
+    if family == Family::V4 {
+        let checksum = checksum(&packet);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+    packet
+}
+
+/// internet checksum from RFC 1071
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data.chunks(2)
+        .map(|pair| u32::from(u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reply {
+    /// the destination answered
+    Echo,
+    /// a router on the way ran out of TTL or couldn't deliver the request
+    Error,
+}
+
+/// what a packet received on a raw ICMP socket says about the echo request
+/// with `ident` and `seq`, if it's about it at all
+///
+/// raw IPv4 sockets include the IP header while IPv6 ones don't, errors
+/// quote the IP header of the request followed by its ICMP header
+fn parse_reply(packet: &[u8], family: Family, ident: u16, seq: u16) -> Option<Reply> {
+    let (icmp, echo_reply, errors): (_, u8, &[u8]) = match family {
+        Family::V4 => (packet.get(usize::from(packet.first()? & 0x0f) * 4..)?, 0, &[3, 11]),
+        Family::V6 => (packet, 129, &[1, 3]),
+    };
+    let (reply, quoted) = match *icmp.first()? {
+        kind if kind == echo_reply => (Reply::Echo, icmp),
+        kind if errors.contains(&kind) => {
+            let ip = icmp.get(8..)?;
+            let header_len = match family {
+                Family::V4 => usize::from(ip.first()? & 0x0f) * 4,
+                Family::V6 => 40,
+            };
+            (Reply::Error, ip.get(header_len..)?)
+        },
+        _ => return None,
+    };
+    let matches = quoted.get(4..6)? == ident.to_be_bytes() && quoted.get(6..8)? == seq.to_be_bytes();
+    matches.then_some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checksum, echo_request, parse_reply, Reply};
+    use crate::event::Family;
+
+    #[test]
+    fn checksums_requests() {
+        let request = echo_request(Family::V4, 0x1234, 7);
+        // a packet including its own checksum sums up to zero
+        assert_eq!(checksum(&request), 0);
+    }
+
+    #[test]
+    fn parses_replies_and_errors() {
+        let mut reply = vec![129, 0, 0, 0, 0x12, 0x34, 0, 7];
+        reply.extend_from_slice(b"payload");
+        assert_eq!(parse_reply(&reply, Family::V6, 0x12_34, 7), Some(Reply::Echo));
+        assert_eq!(parse_reply(&reply, Family::V6, 0x12_34, 8), None);
+
+        // IPv4 time exceeded quoting a 20 byte IP header and the request
+        let mut exceeded = vec![0x45];
+        exceeded.resize(20, 0);
+        exceeded.extend_from_slice(&[11, 0, 0, 0, 0, 0, 0, 0, 0x45]);
+        exceeded.resize(48, 0);
+        exceeded.extend_from_slice(&echo_request(Family::V4, 0x1234, 3)[..8]);
+        assert_eq!(parse_reply(&exceeded, Family::V4, 0x1234, 3), Some(Reply::Error));
+        assert_eq!(parse_reply(&exceeded, Family::V4, 0x4321, 3), None);
+    }
+}
@@ -0,0 +1,231 @@
+//! uptime summaries of the saved history
+
+use std::{collections::BTreeSet, fmt::Display, time::Duration};
+
+use chrono::{DateTime, Datelike, Days, Local, Months, NaiveTime, TimeZone, Utc};
+
+use crate::{
+    event::{format_duration, Family},
+    history::{LatencyRecord, OutageRecord, OutageScope}
+};
+
+/// how a report is split up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    Day,
+    /// starting on mondays
+    Week,
+    Month,
+}
+
+/// splits the time between `since` and `until` into calendar periods in
+/// local time, the first and last ones being cut short by `since` and
+/// `until`
+pub fn periods(since: DateTime<Local>, until: DateTime<Local>, by: Period) -> Vec<(DateTime<Local>, DateTime<Local>)> {
+    let midnight = |time: DateTime<Local>| {
+        let date = time.date_naive();
+        let date = match by {
+            Period::Day => date,
+            Period::Week => date - Days::new(u64::from(date.weekday().num_days_from_monday())),
+            Period::Month => date.with_day(1).expect("every month has a first day"),
+        };
+        // midnight can be skipped by daylight saving time, in which case the
+        // period starts whenever the day does
+        Local.from_local_datetime(&date.and_time(NaiveTime::MIN)).earliest().unwrap_or(time)
+    };
+    let next = |start: DateTime<Local>| match by {
+        Period::Day => start + Days::new(1),
+        Period::Week => start + Days::new(7),
+        Period::Month => start + Months::new(1),
+    };
+
+    let mut periods = Vec::new();
+    let mut start = since;
+    while start < until {
+        let end = next(midnight(start)).min(until);
+        periods.push((start, end));
+        start = end;
+    }
+    periods
+}
+
+/// round trip times of a family averaged over the saved summaries, weighted
+/// by how many probes each covered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyPercentiles {
+    pub median: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+}
+
+/// how reliable a family was over some time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Summary {
+    pub family: Family,
+    /// length of the time covered
+    pub period: Duration,
+    /// how long the family was down for, within the time covered
+    pub downtime: Duration,
+    /// outages that overlap the time covered
+    pub outages: usize,
+    /// mean time to recovery, the average length of the outages
+    pub mttr: Option<Duration>,
+    pub longest: Option<Duration>,
+    pub latency: Option<LatencyPercentiles>,
+}
+
+impl Summary {
+    /// percentage of the time covered the family was up
+    pub fn uptime(&self) -> f64 {
+        match self.period.is_zero() {
+            true => 100.0,
+            false => 100.0 - self.downtime.as_secs_f64() / self.period.as_secs_f64() * 100.0,
+        }
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {:.3}% uptime, {} outages, {} down",
+            self.family,
+            self.uptime(),
+            self.outages,
+            format_duration(self.downtime)
+        )?;
+        if let (Some(mttr), Some(longest)) = (self.mttr, self.longest) {
+            write!(f, ", MTTR {}, longest {}", format_duration(mttr), format_duration(longest))?;
+        }
+        if let Some(latency) = self.latency {
+            let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+            write!(
+                f,
+                ", latency median {:.2}ms, p95 {:.2}ms, p99 {:.2}ms",
+                ms(latency.median),
+                ms(latency.p95),
+                ms(latency.p99)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// summarizes every family that has outages or latency saved between `since`
+/// and `until`, ongoing outages count as lasting until `until`
+pub fn summarize(
+    outages: &[OutageRecord],
+    latency: &[LatencyRecord],
+    since: DateTime<Utc>,
+    until: DateTime<Utc>
+) -> Vec<Summary> {
+    let family_outages = |family: Family| outages.iter().filter(move |outage| {
+        outage.scope == OutageScope::Family(family)
+            && outage.start < until
+            && outage.end.is_none_or(|end| end > since)
+    });
+    let families: BTreeSet<Family> = latency.iter()
+        .filter(|record| (since..until).contains(&record.time))
+        .map(|record| record.family)
+        .chain([Family::V4, Family::V6].into_iter().filter(|family| family_outages(*family).next().is_some()))
+        .collect();
+
+    families.into_iter().map(|family| {
+        let mut downtime = Duration::ZERO;
+        let mut durations = Vec::new();
+        for outage in family_outages(family) {
+            let end = outage.end.unwrap_or(until);
+            durations.push((end - outage.start).to_std().unwrap_or_default());
+            downtime += (end.min(until) - outage.start.max(since)).to_std().unwrap_or_default();
+        }
+
+        let samples: Vec<&LatencyRecord> = latency.iter()
+            .filter(|record| record.family == family && (since..until).contains(&record.time))
+            .collect();
+        let total = samples.iter().map(|record| record.samples as f64).sum::<f64>();
+        let weighted = |get: fn(&LatencyRecord) -> Duration| Duration::from_secs_f64(
+            samples.iter().map(|record| get(record).as_secs_f64() * record.samples as f64).sum::<f64>() / total
+        );
+
+        Summary {
+            family,
+            period: (until - since).to_std().unwrap_or_default(),
+            downtime,
+            outages: durations.len(),
+            mttr: (!durations.is_empty()).then(|| durations.iter().sum::<Duration>() / durations.len() as u32),
+            longest: durations.iter().max().copied(),
+            latency: (total > 0.0).then(|| LatencyPercentiles {
+                median: weighted(|record| record.median),
+                p95: weighted(|record| record.p95),
+                p99: weighted(|record| record.p99),
+            }),
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{Local, TimeZone, Utc};
+
+    use super::{periods, summarize, Period};
+    use crate::{event::Family, history::{LatencyRecord, OutageRecord, OutageScope}};
+
+    #[test]
+    fn splits_into_periods() {
+        let since = Local.with_ymd_and_hms(2024, 1, 30, 12, 0, 0).unwrap();
+        let until = Local.with_ymd_and_hms(2024, 3, 2, 0, 0, 0).unwrap();
+
+        let months = periods(since, until, Period::Month);
+        assert_eq!(months.len(), 3);
+        assert_eq!(months[0], (since, Local.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap()));
+        assert_eq!(months[2].1, until);
+
+        // the 30th of january 2024 was a tuesday
+        let weeks = periods(since, until, Period::Week);
+        assert_eq!(weeks[0].1, Local.with_ymd_and_hms(2024, 2, 5, 0, 0, 0).unwrap());
+        assert_eq!(periods(since, until, Period::Day).len(), 32);
+    }
+
+    #[test]
+    fn summarizes_outages() {
+        let at = |secs| Utc.timestamp_opt(secs, 0).unwrap();
+        let outage = |start, end| OutageRecord {
+            scope: OutageScope::Family(Family::V6),
+            start: at(start),
+            end,
+            cause: None,
+            interrupted: false,
+        };
+        let outages = [
+            // only half of it is within the report
+            outage(-50, Some(at(50))),
+            outage(300, Some(at(400))),
+            // still ongoing
+            outage(900, None),
+        ];
+        let latency = |samples, ms| LatencyRecord {
+            time: at(500),
+            target: "example.com".to_string(),
+            family: Family::V4,
+            samples,
+            median: Duration::from_millis(ms),
+            p95: Duration::from_millis(ms),
+            p99: Duration::from_millis(ms),
+        };
+
+        let summaries = summarize(&outages, &[latency(1, 10), latency(3, 30)], at(0), at(1000));
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0].latency.unwrap().median, Duration::from_millis(25));
+        assert_eq!(summaries[0].uptime(), 100.0);
+
+        let v6 = &summaries[1];
+        assert_eq!(v6.outages, 3);
+        assert_eq!(v6.downtime, Duration::from_secs(50 + 100 + 100));
+        assert_eq!(v6.uptime(), 75.0);
+        assert_eq!(v6.longest, Some(Duration::from_secs(100)));
+        assert_eq!(v6.mttr, Some(Duration::from_secs(100)));
+        assert_eq!(v6.latency, None);
+    }
+}
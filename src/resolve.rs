@@ -0,0 +1,271 @@
+//! keeps the addresses of monitored hostnames up to date
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant}
+};
+
+use log::trace;
+use tokio::sync::{mpsc, watch};
+use trust_dns_resolver::{
+    config::{LookupIpStrategy, NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig, ResolverOpts},
+    TokioAsyncResolver
+};
+
+use crate::{event::{Family, NetworkEvent}, probe::{Marking, Probe, DEFAULT_TIMEOUT}, target::Target};
+
+/// never re-resolve more often than this, no matter how short the TTL is, also
+/// used as the delay before retrying a failed resolution
+pub(crate) const MIN_RESOLVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// addresses a hostname resolved to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Resolution {
+    pub v4: Vec<Ipv4Addr>,
+    pub v6: Vec<Ipv6Addr>,
+    /// when the records expire
+    pub valid_until: Instant,
+}
+
+/// which nameservers hostnames are resolved with
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Nameservers {
+    /// whatever the system is configured to use, like `/etc/resolv.conf`
+    System,
+    Google,
+    Quad9,
+    #[default]
+    Cloudflare,
+    Custom(Vec<SocketAddr>),
+}
+
+/// how nameservers are talked to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DnsProtocol {
+    /// regular unencrypted DNS over UDP, falling back to TCP
+    #[default]
+    Plain,
+    /// DNS over TLS
+    Tls,
+    /// DNS over HTTPS
+    Https,
+}
+
+impl DnsProtocol {
+    pub fn default_port(&self) -> u16 {
+        match self {
+            DnsProtocol::Plain => 53,
+            DnsProtocol::Tls => 853,
+            DnsProtocol::Https => 443,
+        }
+    }
+}
+
+/// how to resolve hostnames, defaults to Cloudflare's nameservers over plain
+/// DNS
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ResolverSettings {
+    pub nameservers: Nameservers,
+    pub protocol: DnsProtocol,
+    /// name the certificates of custom nameservers are checked against when
+    /// using DNS over TLS or HTTPS, like "dns.google"
+    pub tls_name: Option<String>,
+}
+
+impl ResolverSettings {
+    pub fn build(&self) -> Result<TokioAsyncResolver, &'static str> {
+        let (config, opts) = self.config()?;
+        Ok(TokioAsyncResolver::tokio(config, opts))
+    }
+
+    /// builds a resolver that asks the nameservers every time, for probing
+    /// them rather than looking up targets
+    pub(crate) fn build_uncached(&self) -> Result<TokioAsyncResolver, &'static str> {
+        let (config, mut opts) = self.config()?;
+        opts.cache_size = 0;
+        opts.attempts = 1;
+        opts.timeout = DEFAULT_TIMEOUT;
+        Ok(TokioAsyncResolver::tokio(config, opts))
+    }
+
+    fn config(&self) -> Result<(ResolverConfig, ResolverOpts), &'static str> {
+        use {DnsProtocol as P, Nameservers as N};
+
+        let mut opts = ResolverOpts::default();
+        let config = match (&self.nameservers, self.protocol) {
+            (N::System, P::Plain) => {
+                let (config, system_opts) = trust_dns_resolver::system_conf::read_system_conf()
+                    .map_err(|_| "Could not read the system's DNS configuration")?;
+                opts = system_opts;
+                config
+            },
+            (N::System, _) => return Err("Encrypted DNS can't be used with the system's nameservers"),
+            (N::Google, P::Plain) => ResolverConfig::google(),
+            (N::Google, P::Tls) => ResolverConfig::google_tls(),
+            (N::Google, P::Https) => ResolverConfig::google_https(),
+            (N::Quad9, P::Plain) => ResolverConfig::quad9(),
+            (N::Quad9, P::Tls) => ResolverConfig::quad9_tls(),
+            (N::Quad9, P::Https) => ResolverConfig::quad9_https(),
+            (N::Cloudflare, P::Plain) => ResolverConfig::cloudflare(),
+            (N::Cloudflare, P::Tls) => ResolverConfig::cloudflare_tls(),
+            (N::Cloudflare, P::Https) => ResolverConfig::cloudflare_https(),
+            (N::Custom(addrs), protocol) => {
+                if addrs.is_empty() {
+                    return Err("At least one nameserver must be provided");
+                }
+                let mut group = NameServerConfigGroup::new();
+                for addr in addrs {
+                    match protocol {
+                        P::Plain => {
+                            group.push(NameServerConfig::new(*addr, Protocol::Udp));
+                            group.push(NameServerConfig::new(*addr, Protocol::Tcp));
+                        },
+                        P::Tls | P::Https => {
+                            let mut config = NameServerConfig::new(*addr, match protocol {
+                                P::Tls => Protocol::Tls,
+                                _ => Protocol::Https,
+                            });
+                            config.tls_dns_name = Some(
+                                self.tls_name.clone()
+                                    .ok_or("A TLS name must be provided to use encrypted DNS with custom nameservers")?
+                            );
+                            group.push(config);
+                        },
+                    }
+                }
+                ResolverConfig::from_parts(None, Vec::new(), group)
+            },
+        };
+        opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        Ok((config, opts))
+    }
+}
+
+/// looks up the A and AAAA records of `host`, errors if neither are present
+///
+/// IP addresses are used as they are
+pub async fn resolve_target(resolver: &TokioAsyncResolver, host: &str) -> Result<Target, &'static str> {
+    if let Ok(addr) = host.parse::<IpAddr>() {
+        return Ok(Target::from(addr));
+    }
+
+    let resolution = resolve(resolver, host).await
+        .map_err(|_| "There was an error while trying to resolve the hostname")?;
+    if resolution.v4.is_empty() && resolution.v6.is_empty() {
+        return Err("The provided domain is invalid");
+    }
+    Ok(Target {
+        host: host.to_string(),
+        v4: resolution.v4.first().copied(),
+        v6: resolution.v6.first().copied(),
+        probe: Probe::default(),
+        marking: Marking::default(),
+    })
+}
+
+pub(crate) async fn resolve(resolver: &TokioAsyncResolver, host: &str) -> Result<Resolution, String> {
+    let lookup = resolver.lookup_ip(host).await.map_err(|e| e.to_string())?;
+
+    let mut resolution = Resolution { v4: Vec::new(), v6: Vec::new(), valid_until: lookup.valid_until() };
+    for addr in lookup.iter() {
+        match addr {
+            IpAddr::V4(v4) => resolution.v4.push(v4),
+            IpAddr::V6(v6) => resolution.v6.push(v6),
+        }
+    }
+    Ok(resolution)
+}
+
+/// picks the address to use out of `candidates`, sticking with `current` if
+/// it's still valid so that probes don't hop between addresses needlessly
+fn pick(current: IpAddr, candidates: impl IntoIterator<Item = IpAddr>) -> Option<IpAddr> {
+    let mut first = None;
+    for candidate in candidates {
+        if candidate == current {
+            return None;
+        }
+        first.get_or_insert(candidate);
+    }
+    first
+}
+
+/// re-resolves `host` whenever its records expire, but at least every
+/// `max_interval`, updating the addresses probes use for each family in
+/// `addrs`
+///
+/// the last known good addresses are kept when resolution fails or doesn't
+/// return any addresses for a family, and families that weren't in `addrs`
+/// are ignored even once they resolve, as nothing probes them
+pub(crate) async fn watch_target(
+    host: String,
+    resolver: TokioAsyncResolver,
+    max_interval: Duration,
+    addrs: HashMap<Family, watch::Sender<IpAddr>>,
+    events: mpsc::Sender<NetworkEvent>
+) {
+    let max_interval = max_interval.max(MIN_RESOLVE_INTERVAL);
+    let mut next = max_interval;
+
+    loop {
+        tokio::time::sleep(next).await;
+
+        let new_events = match resolve(&resolver, &host).await {
+            Ok(resolution) => {
+                trace!("{host} resolved to {resolution:?}");
+                next = resolution.valid_until
+                    .saturating_duration_since(Instant::now())
+                    .clamp(MIN_RESOLVE_INTERVAL, max_interval);
+
+                let mut new_events = Vec::new();
+                for (family, sender) in addrs.iter() {
+                    let candidates: Vec<IpAddr> = match family {
+                        Family::V4 => resolution.v4.iter().copied().map(IpAddr::from).collect(),
+                        Family::V6 => resolution.v6.iter().copied().map(IpAddr::from).collect(),
+                    };
+                    let current = *sender.borrow();
+                    if let Some(new) = pick(current, candidates) {
+                        sender.send_replace(new);
+                        new_events.push(NetworkEvent::AddressChanged {
+                            target: host.clone(),
+                            family: *family,
+                            old: current,
+                            new
+                        });
+                    }
+                }
+                new_events
+            },
+            Err(error) => {
+                next = MIN_RESOLVE_INTERVAL;
+                vec![NetworkEvent::ResolutionFailed { target: host.clone(), error }]
+            },
+        };
+
+        for event in new_events {
+            if events.send(event).await.is_err() {
+                // monitor was shut down
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::pick;
+
+    #[test]
+    fn pick_keeps_current_address() {
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+        let c: IpAddr = "192.0.2.3".parse().unwrap();
+
+        assert_eq!(pick(a, [b, a]), None);
+        assert_eq!(pick(a, [b, c]), Some(b));
+        // nothing resolved, keep the last known good one
+        assert_eq!(pick(a, []), None);
+    }
+}
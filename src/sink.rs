@@ -0,0 +1,227 @@
+use std::time::Duration;
+
+use log::{debug, kv::{self, Key, Source, Value, VisitSource}, Level, Record};
+
+use crate::event::{Event, NetworkEvent, OutageCause};
+
+/// an output that gets every event produced by a [`Monitor`](crate::Monitor)
+///
+/// sinks are called in order from the monitor's own task, so they shouldn't
+/// block for long, anything slow should be handed off to a separate task
+pub trait Sink: Send + 'static {
+    fn handle(&mut self, event: &Event);
+}
+
+impl<F: FnMut(&Event) + Send + 'static> Sink for F {
+    fn handle(&mut self, event: &Event) {
+        self(event)
+    }
+}
+
+/// writes events to the [`log`] facade
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogSink;
+
+impl Sink for LogSink {
+    fn handle(&mut self, event: &Event) {
+        let kind = &event.kind;
+        let level = match kind {
+            NetworkEvent::ProbeSucceeded { .. } => Level::Debug,
+            NetworkEvent::ProbeFailed { .. } => Level::Warn,
+            NetworkEvent::BurstCompleted { stats, .. } => match stats.received {
+                // the probe failing altogether was already logged
+                0 => Level::Debug,
+                n if n < stats.sent => Level::Warn,
+                _ => Level::Debug,
+            },
+            NetworkEvent::TargetDown { .. } => Level::Warn,
+            NetworkEvent::TargetUp { .. } => Level::Info,
+            NetworkEvent::TargetDegraded { .. } => Level::Warn,
+            NetworkEvent::TargetRecovered { .. } => Level::Info,
+            NetworkEvent::FamilyDown { .. } | NetworkEvent::NetworkDown { .. } => Level::Error,
+            NetworkEvent::FamilyUp { .. } | NetworkEvent::NetworkUp { .. } => Level::Info,
+            NetworkEvent::AddressChanged { .. } => Level::Info,
+            NetworkEvent::ResolutionFailed { .. } => Level::Warn,
+            NetworkEvent::DnsProbeSucceeded { .. } => Level::Debug,
+            NetworkEvent::DnsProbeFailed { .. } => Level::Warn,
+            NetworkEvent::DnsDown => Level::Error,
+            NetworkEvent::DnsUp { .. } => Level::Info,
+            NetworkEvent::LatencySummary { .. } => Level::Info,
+            NetworkEvent::PathMtuChanged { previous, mtu, .. } => match previous {
+                Some(previous) if mtu < previous => Level::Info,
+                _ => Level::Debug,
+            },
+            NetworkEvent::FamilyMtuChanged { previous, mtu, .. } => match previous {
+                Some(previous) if mtu < previous => Level::Warn,
+                _ => Level::Info,
+            },
+            NetworkEvent::LargePacketsDropped { .. } => Level::Warn,
+            NetworkEvent::RouteTraced { .. } => Level::Info,
+        };
+        if level > log::max_level() {
+            return;
+        }
+
+        // the event's fields go along with the message, for structured logs
+        log::logger().log(
+            &Record::builder()
+                .args(format_args!("{kind}"))
+                .level(level)
+                .target(module_path!())
+                .module_path_static(Some(module_path!()))
+                .file_static(Some(file!()))
+                .line(Some(line!()))
+                .key_values(&Fields(kind))
+                .build()
+        );
+        if let NetworkEvent::ProbeFailed { error, .. } = kind {
+            debug!("{error}");
+        }
+    }
+}
+
+/// the fields of an event as log key-values, durations are in milliseconds
+struct Fields<'a>(&'a NetworkEvent);
+
+impl Source for Fields<'_> {
+    fn visit<'kvs>(&'kvs self, visitor: &mut dyn VisitSource<'kvs>) -> Result<(), kv::Error> {
+        let ms = |duration: &Duration| Value::from(duration.as_secs_f64() * 1000.0);
+        let mut pair = |key: &'static str, value: Value<'kvs>| visitor.visit_pair(Key::from_str(key), value);
+        pair("event", Value::from(self.0.name()))?;
+        match self.0 {
+            NetworkEvent::ProbeSucceeded { target, family, addr, probe, rtt, .. } => {
+                pair("host", Value::from(target.as_str()))?;
+                pair("family", Value::from_display(family))?;
+                pair("addr", Value::from_display(addr))?;
+                pair("probe", Value::from_display(probe))?;
+                pair("rtt_ms", ms(rtt))
+            },
+            NetworkEvent::ProbeFailed { target, family, addr, probe, error, failures } => {
+                pair("host", Value::from(target.as_str()))?;
+                pair("family", Value::from_display(family))?;
+                pair("addr", Value::from_display(addr))?;
+                pair("probe", Value::from_display(probe))?;
+                pair("error", Value::from(error.as_str()))?;
+                pair("failures", Value::from(*failures))
+            },
+            NetworkEvent::BurstCompleted { target, family, addr, stats } => {
+                pair("host", Value::from(target.as_str()))?;
+                pair("family", Value::from_display(family))?;
+                pair("addr", Value::from_display(addr))?;
+                pair("sent", Value::from(stats.sent))?;
+                pair("received", Value::from(stats.received))?;
+                pair("loss_percent", Value::from(stats.loss()))?;
+                pair("jitter_ms", ms(&stats.jitter))?;
+                pair("reordered", Value::from(stats.reordered))?;
+                match stats.duplicates {
+                    Some(duplicates) => pair("duplicates", Value::from(duplicates)),
+                    None => Ok(()),
+                }
+            },
+            NetworkEvent::TargetDown { target, family, cause } => {
+                pair("host", Value::from(target.as_str()))?;
+                pair("family", Value::from_display(family))?;
+                cause_pair(&mut pair, cause)
+            },
+            NetworkEvent::TargetUp { target, family, downtime, cause } => {
+                pair("host", Value::from(target.as_str()))?;
+                pair("family", Value::from_display(family))?;
+                pair("downtime_ms", ms(downtime))?;
+                cause_pair(&mut pair, cause)
+            },
+            NetworkEvent::TargetDegraded { target, family, reason } => {
+                pair("host", Value::from(target.as_str()))?;
+                pair("family", Value::from_display(family))?;
+                pair("reason", Value::from(reason.as_str()))
+            },
+            NetworkEvent::TargetRecovered { target, family, duration } => {
+                pair("host", Value::from(target.as_str()))?;
+                pair("family", Value::from_display(family))?;
+                pair("duration_ms", ms(duration))
+            },
+            NetworkEvent::FamilyDown { family, cause } => {
+                pair("family", Value::from_display(family))?;
+                cause_pair(&mut pair, cause)
+            },
+            NetworkEvent::FamilyUp { family, downtime, cause } => {
+                pair("family", Value::from_display(family))?;
+                pair("downtime_ms", ms(downtime))?;
+                cause_pair(&mut pair, cause)
+            },
+            NetworkEvent::NetworkDown { cause } => cause_pair(&mut pair, cause),
+            NetworkEvent::NetworkUp { downtime, cause } => {
+                pair("downtime_ms", ms(downtime))?;
+                cause_pair(&mut pair, cause)
+            },
+            NetworkEvent::AddressChanged { target, family, old, new } => {
+                pair("host", Value::from(target.as_str()))?;
+                pair("family", Value::from_display(family))?;
+                pair("old_addr", Value::from_display(old))?;
+                pair("addr", Value::from_display(new))
+            },
+            NetworkEvent::ResolutionFailed { target, error } => {
+                pair("host", Value::from(target.as_str()))?;
+                pair("error", Value::from(error.as_str()))
+            },
+            NetworkEvent::DnsProbeSucceeded { name, latency } => {
+                pair("host", Value::from(name.as_str()))?;
+                pair("rtt_ms", ms(latency))
+            },
+            NetworkEvent::DnsProbeFailed { name, reason, failures } => {
+                pair("host", Value::from(name.as_str()))?;
+                pair("error", Value::from_display(reason))?;
+                pair("failures", Value::from(*failures))
+            },
+            NetworkEvent::DnsDown => Ok(()),
+            NetworkEvent::DnsUp { downtime } => pair("downtime_ms", ms(downtime)),
+            NetworkEvent::LatencySummary { target, family, probe, window, stats } => {
+                pair("host", Value::from(target.as_str()))?;
+                pair("family", Value::from_display(family))?;
+                pair("probe", Value::from_display(probe))?;
+                pair("window_ms", ms(window))?;
+                pair("samples", Value::from(stats.samples))?;
+                pair("rtt_ms", ms(&stats.avg))?;
+                pair("median_ms", ms(&stats.median))?;
+                pair("p95_ms", ms(&stats.p95))?;
+                pair("p99_ms", ms(&stats.p99))
+            },
+            NetworkEvent::PathMtuChanged { target, family, previous, mtu } => {
+                pair("host", Value::from(target.as_str()))?;
+                pair("family", Value::from_display(family))?;
+                if let Some(previous) = previous {
+                    pair("previous_mtu", Value::from(*previous))?;
+                }
+                pair("mtu", Value::from(*mtu))
+            },
+            NetworkEvent::FamilyMtuChanged { family, previous, mtu } => {
+                pair("family", Value::from_display(family))?;
+                if let Some(previous) = previous {
+                    pair("previous_mtu", Value::from(*previous))?;
+                }
+                pair("mtu", Value::from(*mtu))
+            },
+            NetworkEvent::LargePacketsDropped { target, family, mtu, size } => {
+                pair("host", Value::from(target.as_str()))?;
+                pair("family", Value::from_display(family))?;
+                pair("mtu", Value::from(*mtu))?;
+                pair("size", Value::from(*size))
+            },
+            NetworkEvent::RouteTraced { target, family, route, .. } => {
+                pair("host", Value::from(target.as_str()))?;
+                pair("family", Value::from_display(family))?;
+                pair("route", Value::from_display(route))?;
+                pair("reached", Value::from(route.reached))
+            },
+        }
+    }
+}
+
+fn cause_pair<'kvs>(
+    pair: &mut impl FnMut(&'static str, Value<'kvs>) -> Result<(), kv::Error>,
+    cause: &'kvs Option<OutageCause>
+) -> Result<(), kv::Error> {
+    match cause {
+        Some(cause) => pair("cause", Value::from_display(cause)),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,620 @@
+//! link state tracking for an arbitrary number of probe paths
+
+use std::{collections::{HashMap, VecDeque}, hash::Hash, time::{Duration, Instant}};
+
+use crate::stats::RttStats;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LinkState {
+    Up,
+    /// reachable, but not working properly
+    Degraded,
+    Down,
+}
+
+/// what a single probe over a path found out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Observation {
+    Success,
+    /// the probe succeeded, but the path isn't healthy
+    Degraded,
+    Failure,
+}
+
+/// when the whole network should be considered down
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AggregateRule {
+    /// every path is down
+    #[default]
+    All,
+    /// at least this many paths are down
+    AtLeast(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition<K> {
+    Path {
+        key: K,
+        from: LinkState,
+        to: LinkState,
+        /// how long the path was in `from` for
+        duration: Duration,
+    },
+    NetworkDown,
+    NetworkUp {
+        downtime: Duration,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct PathState {
+    state: LinkState,
+    entered_at: Instant,
+    failures: u32,
+    first_failure: Option<Instant>,
+}
+
+/// tracks the state of every path and of the network as a whole
+///
+/// a path goes down after `hysteresis` failures in a row, and is considered
+/// down since the first of those failures, so reported downtimes include the
+/// time it took to notice the outage
+#[derive(Debug, Clone)]
+pub struct LinkTracker<K> {
+    hysteresis: u32,
+    rule: AggregateRule,
+    paths: HashMap<K, PathState>,
+    network_down_since: Option<Instant>,
+}
+
+impl<K: Clone + Eq + Hash> LinkTracker<K> {
+    pub fn new(hysteresis: u32, rule: AggregateRule) -> Self {
+        Self {
+            hysteresis: hysteresis.max(1),
+            rule,
+            paths: HashMap::new(),
+            network_down_since: None,
+        }
+    }
+
+    /// starts tracking `key`, which is assumed to be up until proven
+    /// otherwise, does nothing if it's already tracked
+    pub fn add_path(&mut self, key: K, now: Instant) {
+        self.paths.entry(key).or_insert(PathState {
+            state: LinkState::Up,
+            entered_at: now,
+            failures: 0,
+            first_failure: None,
+        });
+    }
+
+    /// stops tracking `key`, which may bring the network back up
+    pub fn remove_path(&mut self, key: &K, now: Instant) -> Vec<Transition<K>> {
+        let mut transitions = Vec::new();
+        if self.paths.remove(key).is_some() {
+            self.update_network(now, &mut transitions);
+        }
+        transitions
+    }
+
+    pub fn state(&self, key: &K) -> Option<LinkState> {
+        self.paths.get(key).map(|p| p.state)
+    }
+
+    /// when `key` entered its current state
+    pub fn entered_at(&self, key: &K) -> Option<Instant> {
+        self.paths.get(key).map(|p| p.entered_at)
+    }
+
+    /// how many probes in a row have failed for `key`
+    pub fn failures(&self, key: &K) -> u32 {
+        self.paths.get(key).map(|p| p.failures).unwrap_or(0)
+    }
+
+    pub fn is_network_down(&self) -> bool {
+        self.network_down_since.is_some()
+    }
+
+    /// when the network went down, if it's down
+    pub fn network_down_since(&self) -> Option<Instant> {
+        self.network_down_since
+    }
+
+    pub fn paths(&self) -> impl Iterator<Item = (&K, LinkState)> {
+        self.paths.iter().map(|(k, p)| (k, p.state))
+    }
+
+    /// records a probe result for `key`, starting to track it if needed, and
+    /// returns the resulting state changes, paths first and then the network
+    pub fn observe(&mut self, key: &K, observation: Observation, now: Instant) -> Vec<Transition<K>> {
+        if !self.paths.contains_key(key) {
+            self.add_path(key.clone(), now);
+        }
+        let hysteresis = self.hysteresis;
+        let path = self.paths.get_mut(key).expect("path was just added");
+
+        let (new_state, since) = match observation {
+            Observation::Success | Observation::Degraded => {
+                path.failures = 0;
+                path.first_failure = None;
+                let state = match observation {
+                    Observation::Success => LinkState::Up,
+                    _ => LinkState::Degraded,
+                };
+                (state, now)
+            },
+            Observation::Failure => {
+                path.failures += 1;
+                let first_failure = *path.first_failure.get_or_insert(now);
+                if path.failures >= hysteresis {
+                    (LinkState::Down, first_failure)
+                } else {
+                    (path.state, path.entered_at)
+                }
+            },
+        };
+
+        let mut transitions = Vec::new();
+        if new_state != path.state {
+            transitions.push(Transition::Path {
+                key: key.clone(),
+                from: path.state,
+                to: new_state,
+                duration: since.saturating_duration_since(path.entered_at),
+            });
+            path.state = new_state;
+            path.entered_at = since;
+            self.update_network(now, &mut transitions);
+        }
+        transitions
+    }
+
+    /// puts `key` straight into `state` since `since`, bypassing hysteresis,
+    /// for paths whose state is derived from something else
+    pub fn set_state(&mut self, key: &K, state: LinkState, since: Instant, now: Instant) -> Vec<Transition<K>> {
+        if !self.paths.contains_key(key) {
+            self.add_path(key.clone(), now);
+        }
+        let path = self.paths.get_mut(key).expect("path was just added");
+
+        let mut transitions = Vec::new();
+        if state != path.state {
+            let since = since.max(path.entered_at);
+            transitions.push(Transition::Path {
+                key: key.clone(),
+                from: path.state,
+                to: state,
+                duration: since.saturating_duration_since(path.entered_at),
+            });
+            path.state = state;
+            path.entered_at = since;
+            path.failures = 0;
+            path.first_failure = None;
+            self.update_network(now, &mut transitions);
+        }
+        transitions
+    }
+
+    /// how many paths must be down for the network to be down
+    fn quorum(&self) -> usize {
+        match self.rule {
+            AggregateRule::All => self.paths.len(),
+            AggregateRule::AtLeast(n) => n.clamp(1, self.paths.len().max(1)),
+        }
+    }
+
+    fn update_network(&mut self, now: Instant, transitions: &mut Vec<Transition<K>>) {
+        let mut down_since: Vec<Instant> = self.paths.values()
+            .filter(|p| p.state == LinkState::Down)
+            .map(|p| p.entered_at)
+            .collect();
+        let quorum = self.quorum();
+        let is_down = !self.paths.is_empty() && down_since.len() >= quorum;
+
+        match (self.network_down_since, is_down) {
+            (None, true) => {
+                // the network went down once the quorum-th path did
+                down_since.sort_unstable();
+                self.network_down_since = Some(down_since[quorum - 1]);
+                transitions.push(Transition::NetworkDown);
+            },
+            (Some(since), false) => {
+                self.network_down_since = None;
+                transitions.push(Transition::NetworkUp { downtime: now.saturating_duration_since(since) });
+            },
+            _ => {},
+        }
+    }
+}
+
+/// when a path that still responds is considered degraded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DegradedThresholds {
+    /// packet loss percentage above which a path is degraded
+    pub loss: Option<f64>,
+    /// 95th percentile round trip time above which a path is degraded
+    pub p95: Option<Duration>,
+    /// how many of the latest probes loss and latency are judged over
+    pub window: usize,
+    /// how many probes in a row must exceed a threshold for the path to
+    /// become degraded
+    pub enter: u32,
+    /// how many probes in a row must be within the thresholds for the path
+    /// to stop being degraded
+    pub leave: u32,
+}
+
+impl Default for DegradedThresholds {
+    fn default() -> Self {
+        Self { loss: None, p95: None, window: 20, enter: 3, leave: 3 }
+    }
+}
+
+/// judges whether a path is degraded from its latest probes
+#[derive(Debug, Clone)]
+pub struct QualityWindow {
+    thresholds: DegradedThresholds,
+    /// requests sent and answered by each probe, along with its round trip
+    /// time if it succeeded
+    probes: VecDeque<(u16, u16, Option<Duration>)>,
+    exceeded: u32,
+    within: u32,
+    degraded: bool,
+    reason: Option<String>,
+}
+
+impl QualityWindow {
+    pub fn new(thresholds: DegradedThresholds) -> Self {
+        Self {
+            thresholds,
+            probes: VecDeque::new(),
+            exceeded: 0,
+            within: 0,
+            degraded: false,
+            reason: None,
+        }
+    }
+
+    /// records a probe that sent `sent` requests of which `received` were
+    /// answered, returning whether the path is now degraded
+    pub fn record(&mut self, sent: u16, received: u16, rtt: Option<Duration>) -> bool {
+        if self.probes.len() >= self.thresholds.window.max(1) {
+            self.probes.pop_front();
+        }
+        self.probes.push_back((sent, received, rtt));
+
+        self.reason = self.exceeded_threshold();
+        if self.reason.is_some() {
+            self.exceeded += 1;
+            self.within = 0;
+            if self.exceeded >= self.thresholds.enter {
+                self.degraded = true;
+            }
+        } else {
+            self.within += 1;
+            self.exceeded = 0;
+            if self.within >= self.thresholds.leave {
+                self.degraded = false;
+            }
+        }
+        self.degraded
+    }
+
+    /// forgets every probe, like after the path was down
+    pub fn clear(&mut self) {
+        *self = Self::new(self.thresholds);
+    }
+
+    /// which threshold the latest probes exceeded, if any
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
+
+    fn exceeded_threshold(&self) -> Option<String> {
+        let probes = self.probes.len();
+        if let Some(max_loss) = self.thresholds.loss {
+            let sent: u32 = self.probes.iter().map(|(sent, ..)| u32::from(*sent)).sum();
+            let received: u32 = self.probes.iter().map(|(_, received, _)| u32::from(*received)).sum();
+            let loss = match sent {
+                0 => 0.0,
+                sent => f64::from(sent - received) / f64::from(sent) * 100.0,
+            };
+            if loss > max_loss {
+                return Some(format!("{loss:.1}% packet loss over the last {probes} probes"));
+            }
+        }
+        if let Some(max_p95) = self.thresholds.p95 {
+            let rtts: Vec<Duration> = self.probes.iter().filter_map(|(.., rtt)| *rtt).collect();
+            if let Some(stats) = RttStats::from_samples(&rtts).filter(|s| s.p95 > max_p95) {
+                return Some(format!(
+                    "95th percentile round trip time of {:.2}ms over the last {probes} probes",
+                    stats.p95.as_secs_f64() * 1000.0
+                ));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{AggregateRule, DegradedThresholds, LinkState, LinkTracker, Observation, QualityWindow, Transition};
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    fn tracker(paths: &[&'static str], start: Instant) -> LinkTracker<&'static str> {
+        let mut tracker = LinkTracker::new(2, AggregateRule::All);
+        for path in paths {
+            tracker.add_path(*path, start);
+        }
+        tracker
+    }
+
+    #[test]
+    fn goes_down_after_hysteresis() {
+        let t0 = Instant::now();
+        let mut tracker = tracker(&["a", "b"], t0);
+
+        assert_eq!(tracker.observe(&"a", Observation::Failure, t0 + secs(10)), vec![]);
+        assert_eq!(tracker.state(&"a"), Some(LinkState::Up));
+        assert_eq!(tracker.failures(&"a"), 1);
+
+        assert_eq!(
+            tracker.observe(&"a", Observation::Failure, t0 + secs(20)),
+            vec![Transition::Path { key: "a", from: LinkState::Up, to: LinkState::Down, duration: secs(10) }]
+        );
+        assert_eq!(tracker.state(&"a"), Some(LinkState::Down));
+        // down since the first failure
+        assert_eq!(tracker.entered_at(&"a"), Some(t0 + secs(10)));
+        assert!(!tracker.is_network_down());
+
+        // staying down doesn't do anything
+        assert_eq!(tracker.observe(&"a", Observation::Failure, t0 + secs(30)), vec![]);
+        assert_eq!(tracker.failures(&"a"), 3);
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let t0 = Instant::now();
+        let mut tracker = tracker(&["a"], t0);
+
+        tracker.observe(&"a", Observation::Failure, t0 + secs(10));
+        assert_eq!(tracker.observe(&"a", Observation::Success, t0 + secs(20)), vec![]);
+        assert_eq!(tracker.failures(&"a"), 0);
+        assert_eq!(tracker.observe(&"a", Observation::Failure, t0 + secs(30)), vec![]);
+        assert_eq!(tracker.state(&"a"), Some(LinkState::Up));
+    }
+
+    #[test]
+    fn down_to_up_reports_downtime() {
+        let t0 = Instant::now();
+        let mut tracker = tracker(&["a", "b"], t0);
+
+        tracker.observe(&"a", Observation::Failure, t0 + secs(10));
+        tracker.observe(&"a", Observation::Failure, t0 + secs(20));
+        assert_eq!(
+            tracker.observe(&"a", Observation::Success, t0 + secs(100)),
+            vec![Transition::Path { key: "a", from: LinkState::Down, to: LinkState::Up, duration: secs(90) }]
+        );
+        assert_eq!(tracker.state(&"a"), Some(LinkState::Up));
+        assert_eq!(tracker.entered_at(&"a"), Some(t0 + secs(100)));
+    }
+
+    #[test]
+    fn degraded_transitions() {
+        let t0 = Instant::now();
+        let mut tracker = tracker(&["a"], t0);
+
+        // up -> degraded
+        assert_eq!(
+            tracker.observe(&"a", Observation::Degraded, t0 + secs(10)),
+            vec![Transition::Path { key: "a", from: LinkState::Up, to: LinkState::Degraded, duration: secs(10) }]
+        );
+        // degraded -> up
+        assert_eq!(
+            tracker.observe(&"a", Observation::Success, t0 + secs(30)),
+            vec![Transition::Path { key: "a", from: LinkState::Degraded, to: LinkState::Up, duration: secs(20) }]
+        );
+
+        // degraded -> down
+        tracker.observe(&"a", Observation::Degraded, t0 + secs(40));
+        tracker.observe(&"a", Observation::Failure, t0 + secs(50));
+        assert_eq!(tracker.state(&"a"), Some(LinkState::Degraded));
+        assert_eq!(
+            tracker.observe(&"a", Observation::Failure, t0 + secs(60)),
+            vec![
+                Transition::Path { key: "a", from: LinkState::Degraded, to: LinkState::Down, duration: secs(10) },
+                Transition::NetworkDown,
+            ]
+        );
+
+        // down -> degraded
+        assert_eq!(
+            tracker.observe(&"a", Observation::Degraded, t0 + secs(80)),
+            vec![
+                Transition::Path { key: "a", from: LinkState::Down, to: LinkState::Degraded, duration: secs(30) },
+                Transition::NetworkUp { downtime: secs(30) },
+            ]
+        );
+    }
+
+    #[test]
+    fn network_down_when_all_paths_are() {
+        let t0 = Instant::now();
+        let mut tracker = tracker(&["a", "b"], t0);
+
+        tracker.observe(&"a", Observation::Failure, t0 + secs(10));
+        tracker.observe(&"a", Observation::Failure, t0 + secs(20));
+        tracker.observe(&"b", Observation::Failure, t0 + secs(25));
+        assert_eq!(
+            tracker.observe(&"b", Observation::Failure, t0 + secs(35)),
+            vec![
+                Transition::Path { key: "b", from: LinkState::Up, to: LinkState::Down, duration: secs(25) },
+                Transition::NetworkDown,
+            ]
+        );
+        assert!(tracker.is_network_down());
+
+        // the network has been down since b went down at 25s
+        assert_eq!(
+            tracker.observe(&"a", Observation::Success, t0 + secs(125)),
+            vec![
+                Transition::Path { key: "a", from: LinkState::Down, to: LinkState::Up, duration: secs(115) },
+                Transition::NetworkUp { downtime: secs(100) },
+            ]
+        );
+        assert!(!tracker.is_network_down());
+        assert_eq!(tracker.state(&"b"), Some(LinkState::Down));
+    }
+
+    #[test]
+    fn network_quorum() {
+        let t0 = Instant::now();
+        let mut tracker = LinkTracker::new(1, AggregateRule::AtLeast(2));
+        for path in ["a", "b", "c"] {
+            tracker.add_path(path, t0);
+        }
+
+        tracker.observe(&"a", Observation::Failure, t0 + secs(10));
+        assert!(!tracker.is_network_down());
+        assert_eq!(
+            tracker.observe(&"c", Observation::Failure, t0 + secs(20)),
+            vec![
+                Transition::Path { key: "c", from: LinkState::Up, to: LinkState::Down, duration: secs(20) },
+                Transition::NetworkDown,
+            ]
+        );
+        tracker.observe(&"b", Observation::Failure, t0 + secs(30));
+
+        // still two down
+        tracker.observe(&"a", Observation::Success, t0 + secs(40));
+        assert!(tracker.is_network_down());
+
+        assert_eq!(
+            tracker.observe(&"c", Observation::Success, t0 + secs(50)),
+            vec![
+                Transition::Path { key: "c", from: LinkState::Down, to: LinkState::Up, duration: secs(30) },
+                Transition::NetworkUp { downtime: secs(30) },
+            ]
+        );
+    }
+
+    #[test]
+    fn removing_path_updates_network() {
+        let t0 = Instant::now();
+        let mut tracker = LinkTracker::new(1, AggregateRule::AtLeast(2));
+        for path in ["a", "b", "c"] {
+            tracker.add_path(path, t0);
+        }
+
+        tracker.observe(&"a", Observation::Failure, t0 + secs(10));
+        tracker.observe(&"b", Observation::Failure, t0 + secs(10));
+        assert!(tracker.is_network_down());
+
+        assert_eq!(
+            tracker.remove_path(&"b", t0 + secs(20)),
+            vec![Transition::NetworkUp { downtime: secs(10) }]
+        );
+        assert_eq!(tracker.state(&"b"), None);
+        assert_eq!(tracker.remove_path(&"b", t0 + secs(20)), vec![]);
+
+        // the quorum can't be more than the paths there are
+        tracker.remove_path(&"c", t0 + secs(30));
+        assert!(tracker.is_network_down());
+    }
+
+    #[test]
+    fn set_state_bypasses_hysteresis() {
+        let t0 = Instant::now();
+        let mut tracker = tracker(&["a", "b"], t0);
+
+        assert_eq!(
+            tracker.set_state(&"a", LinkState::Down, t0 + secs(5), t0 + secs(10)),
+            vec![Transition::Path { key: "a", from: LinkState::Up, to: LinkState::Down, duration: secs(5) }]
+        );
+        assert_eq!(tracker.entered_at(&"a"), Some(t0 + secs(5)));
+        assert_eq!(tracker.set_state(&"a", LinkState::Down, t0 + secs(20), t0 + secs(20)), vec![]);
+
+        assert_eq!(
+            tracker.set_state(&"b", LinkState::Down, t0 + secs(30), t0 + secs(30)),
+            vec![
+                Transition::Path { key: "b", from: LinkState::Up, to: LinkState::Down, duration: secs(30) },
+                Transition::NetworkDown,
+            ]
+        );
+        assert_eq!(tracker.network_down_since(), Some(t0 + secs(30)));
+        assert_eq!(
+            tracker.set_state(&"b", LinkState::Up, t0 + secs(40), t0 + secs(40)),
+            vec![
+                Transition::Path { key: "b", from: LinkState::Down, to: LinkState::Up, duration: secs(10) },
+                Transition::NetworkUp { downtime: secs(10) },
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_paths_are_added_on_observation() {
+        let t0 = Instant::now();
+        let mut tracker = LinkTracker::new(1, AggregateRule::All);
+
+        assert_eq!(tracker.state(&"a"), None);
+        assert_eq!(tracker.observe(&"a", Observation::Success, t0), vec![]);
+        assert_eq!(tracker.state(&"a"), Some(LinkState::Up));
+        assert_eq!(
+            tracker.observe(&"a", Observation::Failure, t0 + secs(5)),
+            vec![
+                Transition::Path { key: "a", from: LinkState::Up, to: LinkState::Down, duration: secs(5) },
+                Transition::NetworkDown,
+            ]
+        );
+    }
+
+    #[test]
+    fn quality_window_hysteresis() {
+        let mut window = QualityWindow::new(DegradedThresholds {
+            loss: Some(20.0),
+            p95: None,
+            window: 4,
+            enter: 2,
+            leave: 3,
+        });
+
+        assert!(!window.record(1, 1, Some(secs(0))));
+        // 1 of 2 lost, over the threshold once
+        assert!(!window.record(1, 0, None));
+        assert!(window.reason().is_some());
+        // twice in a row
+        assert!(window.record(1, 1, Some(secs(0))));
+
+        // the lost probe is still in the window
+        assert!(window.record(1, 1, Some(secs(0))));
+        // diluted below the threshold, but it takes 3 good probes to leave
+        assert!(window.record(5, 5, Some(secs(0))));
+        assert_eq!(window.reason(), None);
+        assert!(window.record(5, 5, Some(secs(0))));
+        assert!(!window.record(5, 5, Some(secs(0))));
+    }
+
+    #[test]
+    fn quality_window_latency() {
+        let ms = Duration::from_millis;
+        let mut window = QualityWindow::new(DegradedThresholds {
+            p95: Some(ms(100)),
+            window: 20,
+            enter: 1,
+            leave: 1,
+            ..Default::default()
+        });
+
+        for _ in 0..19 {
+            assert!(!window.record(1, 1, Some(ms(20))));
+        }
+        // a single slow probe out of 20 doesn't move the 95th percentile
+        assert!(!window.record(1, 1, Some(ms(500))));
+        assert!(window.record(1, 1, Some(ms(500))));
+    }
+}
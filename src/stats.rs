@@ -0,0 +1,138 @@
+//! latency statistics over windows of probe results
+
+use std::{collections::HashMap, fmt::Display, time::Duration};
+
+use crate::{event::Family, probe::ProbeKind};
+
+/// summary of the round trip times measured over a window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RttStats {
+    /// how many successful probes the summary covers
+    pub samples: usize,
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+    pub median: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    /// population standard deviation
+    pub stddev: Duration,
+}
+
+impl RttStats {
+    /// summarizes `samples`, `None` if there aren't any
+    pub fn from_samples(samples: &[Duration]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        let mut sorted = samples.to_vec();
+        sorted.sort_unstable();
+
+        let secs: Vec<f64> = sorted.iter().map(Duration::as_secs_f64).collect();
+        let mean = secs.iter().sum::<f64>() / secs.len() as f64;
+        let variance = secs.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / secs.len() as f64;
+
+        Some(Self {
+            samples: sorted.len(),
+            min: sorted[0],
+            avg: Duration::from_secs_f64(mean),
+            max: sorted[sorted.len() - 1],
+            median: percentile(&sorted, 50.0),
+            p95: percentile(&sorted, 95.0),
+            p99: percentile(&sorted, 99.0),
+            stddev: Duration::from_secs_f64(variance.sqrt()),
+        })
+    }
+}
+
+impl Display for RttStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        write!(
+            f,
+            "min {:.2}ms, avg {:.2}ms, max {:.2}ms, median {:.2}ms, p95 {:.2}ms, p99 {:.2}ms, stddev {:.2}ms ({} samples)",
+            ms(self.min),
+            ms(self.avg),
+            ms(self.max),
+            ms(self.median),
+            ms(self.p95),
+            ms(self.p99),
+            ms(self.stddev),
+            self.samples
+        )
+    }
+}
+
+/// nearest-rank percentile of an already sorted, non-empty slice
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// collects round trip times per target and family until the window is over
+#[derive(Debug, Default)]
+pub(crate) struct RttWindows {
+    samples: HashMap<(usize, Family), (ProbeKind, Vec<Duration>)>,
+}
+
+impl RttWindows {
+    pub fn record(&mut self, target: usize, family: Family, probe: ProbeKind, rtt: Duration) {
+        let entry = self.samples.entry((target, family)).or_insert_with(|| (probe, Vec::new()));
+        entry.0 = probe;
+        entry.1.push(rtt);
+    }
+
+    /// summarizes and clears every window, ordered by target and family
+    pub fn flush(&mut self) -> Vec<(usize, Family, ProbeKind, RttStats)> {
+        let mut summaries: Vec<_> = self.samples.drain()
+            .filter_map(|((target, family), (probe, samples))| {
+                Some((target, family, probe, RttStats::from_samples(&samples)?))
+            })
+            .collect();
+        summaries.sort_by_key(|(target, family, ..)| (*target, *family));
+        summaries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{RttStats, RttWindows};
+    use crate::{event::Family, probe::ProbeKind};
+
+    #[test]
+    fn summarizes_samples() {
+        let ms = Duration::from_millis;
+        let samples: Vec<Duration> = (1..=100).rev().map(ms).collect();
+        let stats = RttStats::from_samples(&samples).unwrap();
+
+        assert_eq!(stats.samples, 100);
+        assert_eq!(stats.min, ms(1));
+        assert_eq!(stats.max, ms(100));
+        assert_eq!(stats.median, ms(50));
+        assert_eq!(stats.p95, ms(95));
+        assert_eq!(stats.p99, ms(99));
+        assert_eq!(stats.avg.as_micros(), 50_500);
+        // sqrt((100^2 - 1) / 12) ms
+        assert_eq!(stats.stddev.as_micros(), 28_866);
+
+        assert_eq!(RttStats::from_samples(&[]), None);
+        let single = RttStats::from_samples(&[ms(7)]).unwrap();
+        assert_eq!((single.p99, single.stddev), (ms(7), Duration::ZERO));
+    }
+
+    #[test]
+    fn windows_are_cleared_on_flush() {
+        let mut windows = RttWindows::default();
+        windows.record(1, Family::V6, ProbeKind::Icmp, Duration::from_millis(10));
+        windows.record(0, Family::V4, ProbeKind::Icmp, Duration::from_millis(20));
+        windows.record(1, Family::V6, ProbeKind::Icmp, Duration::from_millis(30));
+
+        let summaries = windows.flush();
+        assert_eq!(summaries.len(), 2);
+        assert_eq!((summaries[0].0, summaries[0].1), (0, Family::V4));
+        assert_eq!(summaries[1].3.samples, 2);
+        assert!(windows.flush().is_empty());
+    }
+}
@@ -0,0 +1,72 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr}
+};
+
+use crate::{event::Family, probe::{Marking, Probe}};
+
+/// a host to be monitored over whichever families it has addresses for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub host: String,
+    pub v4: Option<Ipv4Addr>,
+    pub v6: Option<Ipv6Addr>,
+    /// how the host is monitored, pinged by default
+    pub probe: Probe,
+    /// TTL and DSCP of the probes' packets
+    pub marking: Marking,
+}
+
+impl Target {
+    pub fn with_probe(mut self, probe: Probe) -> Self {
+        self.probe = probe;
+        self
+    }
+
+    pub fn with_marking(mut self, marking: Marking) -> Self {
+        self.marking = marking;
+        self
+    }
+
+    /// whether the target is a bare IP address rather than a hostname, in
+    /// which case there's nothing to re-resolve
+    pub fn is_literal(&self) -> bool {
+        self.host.parse::<IpAddr>().is_ok()
+    }
+
+    pub fn addr(&self, family: Family) -> Option<IpAddr> {
+        match family {
+            Family::V4 => self.v4.map(IpAddr::from),
+            Family::V6 => self.v6.map(IpAddr::from),
+        }
+    }
+
+    /// the families this target can be monitored over
+    pub fn families(&self) -> impl Iterator<Item = Family> + '_ {
+        [Family::V4, Family::V6].into_iter().filter(|f| self.addr(*f).is_some())
+    }
+}
+
+impl From<IpAddr> for Target {
+    fn from(addr: IpAddr) -> Self {
+        let (v4, v6) = match addr {
+            IpAddr::V4(v4) => (Some(v4), None),
+            IpAddr::V6(v6) => (None, Some(v6)),
+        };
+        Self { host: addr.to_string(), v4, v6, probe: Probe::default(), marking: Marking::default() }
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_literal() {
+            return write!(f, "{}", self.host);
+        }
+        match (self.v4, self.v6) {
+            (Some(v4), Some(v6)) => write!(f, "{} ({v4}, {v6})", self.host),
+            (Some(v4), None) => write!(f, "{} ({v4})", self.host),
+            (None, Some(v6)) => write!(f, "{} ({v6})", self.host),
+            (None, None) => write!(f, "{}", self.host),
+        }
+    }
+}
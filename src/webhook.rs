@@ -0,0 +1,543 @@
+//! posts notifications of outages and recoveries to webhooks, keeping them
+//! while the webhook can't be reached
+
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    time::Duration
+};
+
+use chrono::{Local, SecondsFormat};
+use log::{debug, info, warn};
+use tokio::{select, sync::mpsc, task::JoinHandle, time::Instant};
+use url::Url;
+
+use crate::{
+    event::{format_duration, Event, NetworkEvent},
+    json,
+    probe::post,
+    sink::Sink
+};
+
+/// most notifications kept while the webhook can't be reached, the oldest ones
+/// are dropped past this
+const MAX_QUEUED: usize = 1000;
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// a value that can be put in a template, as "{{name}}"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placeholder {
+    /// the kind of event, like "family_down"
+    Event,
+    /// the message that's logged for the event
+    Message,
+    /// when the event happened in local time, like "2024-01-31 12:00:00"
+    Time,
+    /// when the event happened as an RFC 3339 timestamp in UTC
+    Timestamp,
+    Family,
+    Target,
+    Cause,
+    /// how long an outage or degradation lasted as HH:MM:SS, for recoveries
+    Downtime,
+    DowntimeSeconds,
+    /// which threshold a degraded target exceeded
+    Reason,
+    /// every field of the event as a JSON object
+    Json,
+}
+
+impl Placeholder {
+    const ALL: [Placeholder; 11] = [
+        Placeholder::Event,
+        Placeholder::Message,
+        Placeholder::Time,
+        Placeholder::Timestamp,
+        Placeholder::Family,
+        Placeholder::Target,
+        Placeholder::Cause,
+        Placeholder::Downtime,
+        Placeholder::DowntimeSeconds,
+        Placeholder::Reason,
+        Placeholder::Json,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Placeholder::Event => "event",
+            Placeholder::Message => "message",
+            Placeholder::Time => "time",
+            Placeholder::Timestamp => "timestamp",
+            Placeholder::Family => "family",
+            Placeholder::Target => "target",
+            Placeholder::Cause => "cause",
+            Placeholder::Downtime => "downtime",
+            Placeholder::DowntimeSeconds => "downtime_seconds",
+            Placeholder::Reason => "reason",
+            Placeholder::Json => "json",
+        }
+    }
+
+    /// the value for `event`, empty if it doesn't have one
+    fn value(self, event: &Event) -> String {
+        let kind = &event.kind;
+        let downtime = match kind {
+            NetworkEvent::TargetUp { downtime, .. }
+                | NetworkEvent::FamilyUp { downtime, .. }
+                | NetworkEvent::NetworkUp { downtime, .. }
+                | NetworkEvent::DnsUp { downtime }
+                | NetworkEvent::TargetRecovered { duration: downtime, .. } => Some(*downtime),
+            _ => None,
+        };
+        match self {
+            Placeholder::Event => kind.name().to_string(),
+            Placeholder::Message => kind.to_string(),
+            Placeholder::Time => event.timestamp.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string(),
+            Placeholder::Timestamp => event.timestamp.to_rfc3339_opts(SecondsFormat::Secs, true),
+            Placeholder::Family => match kind {
+                NetworkEvent::TargetDown { family, .. }
+                    | NetworkEvent::TargetUp { family, .. }
+                    | NetworkEvent::TargetDegraded { family, .. }
+                    | NetworkEvent::TargetRecovered { family, .. }
+                    | NetworkEvent::FamilyDown { family, .. }
+                    | NetworkEvent::FamilyUp { family, .. } => family.to_string(),
+                _ => String::new(),
+            },
+            Placeholder::Target => match kind {
+                NetworkEvent::TargetDown { target, .. }
+                    | NetworkEvent::TargetUp { target, .. }
+                    | NetworkEvent::TargetDegraded { target, .. }
+                    | NetworkEvent::TargetRecovered { target, .. } => target.clone(),
+                _ => String::new(),
+            },
+            Placeholder::Cause => match kind {
+                NetworkEvent::TargetDown { cause: Some(cause), .. }
+                    | NetworkEvent::TargetUp { cause: Some(cause), .. }
+                    | NetworkEvent::FamilyDown { cause: Some(cause), .. }
+                    | NetworkEvent::FamilyUp { cause: Some(cause), .. }
+                    | NetworkEvent::NetworkDown { cause: Some(cause) }
+                    | NetworkEvent::NetworkUp { cause: Some(cause), .. } => cause.to_string(),
+                _ => String::new(),
+            },
+            Placeholder::Downtime => downtime.map(format_duration).unwrap_or_default(),
+            Placeholder::DowntimeSeconds => downtime.map(|d| d.as_secs().to_string()).unwrap_or_default(),
+            Placeholder::Reason => match kind {
+                NetworkEvent::TargetDegraded { reason, .. } => reason.clone(),
+                _ => String::new(),
+            },
+            Placeholder::Json => {
+                let mut object = json::Object::new();
+                object.string("event", kind.name())
+                    .string("message", &kind.to_string())
+                    .string("timestamp", &event.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true));
+                for placeholder in [Placeholder::Family, Placeholder::Target, Placeholder::Cause, Placeholder::Reason] {
+                    let value = placeholder.value(event);
+                    if !value.is_empty() {
+                        object.string(placeholder.name(), &value);
+                    }
+                }
+                if let Some(downtime) = downtime {
+                    object.number("downtime_seconds", downtime.as_secs_f64());
+                }
+                object.finish()
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Placeholder(Placeholder),
+}
+
+/// the body posted for each event, with "{{name}}" placeholders replaced by
+/// the event's values
+///
+/// templates of a JSON object or array are sent as JSON, with the values
+/// escaped to fit in JSON strings, other ones are sent as plain text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+    json: bool,
+}
+
+impl Template {
+    /// names of the built in templates, for services that expect a specific
+    /// format
+    pub const PRESETS: [&'static str; 5] = ["json", "slack", "discord", "teams", "ntfy"];
+
+    /// a built in template, "json" posts every field of the event as a JSON
+    /// object, "slack", "discord" and "teams" post the message in the format
+    /// of their incoming webhooks, and "ntfy" posts it as plain text
+    pub fn preset(name: &str) -> Option<Self> {
+        let template = match name {
+            "json" => "{{json}}",
+            "slack" | "teams" => r#"{"text":"[{{time}}] {{message}}"}"#,
+            "discord" => r#"{"content":"[{{time}}] {{message}}"}"#,
+            "ntfy" => "[{{time}}] {{message}}",
+            _ => return None,
+        };
+        Self::parse(template).ok()
+    }
+
+    pub fn parse(template: &str) -> Result<Self, &'static str> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find("{{") {
+            let end = rest[start..].find("}}").ok_or("Unclosed \"{{\" in the webhook template")? + start;
+            let name = rest[start + 2..end].trim();
+            let placeholder = Placeholder::ALL.into_iter().find(|p| p.name() == name)
+                .ok_or("Unknown placeholder in the webhook template, see --help for the ones supported")?;
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            parts.push(Part::Placeholder(placeholder));
+            rest = &rest[end + 2..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        // placeholders start with "{" too
+        let start = template.trim_start();
+        let json = (start.starts_with(['{', '[']) && !start.starts_with("{{"))
+            || parts == [Part::Placeholder(Placeholder::Json)];
+        Ok(Self { parts, json })
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self.json {
+            true => "application/json",
+            false => "text/plain; charset=utf-8",
+        }
+    }
+
+    fn render(&self, event: &Event) -> String {
+        let mut body = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => body.push_str(text),
+                Part::Placeholder(Placeholder::Json) => body.push_str(&Placeholder::Json.value(event)),
+                Part::Placeholder(placeholder) => {
+                    let value = placeholder.value(event);
+                    match self.json {
+                        true => {
+                            let quoted = json::quote(&value);
+                            body.push_str(&quoted[1..quoted.len() - 1]);
+                        },
+                        false => body.push_str(&value),
+                    }
+                },
+            }
+        }
+        body
+    }
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        Self { parts: vec![Part::Placeholder(Placeholder::Json)], json: true }
+    }
+}
+
+/// where notifications are posted to and what they look like
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Webhook {
+    pub url: Url,
+    pub template: Template,
+}
+
+impl Display for Webhook {
+    // the path is left out as it's often a secret, like with Slack and Discord
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "webhook at {}", self.url.host_str().unwrap_or_default())
+    }
+}
+
+impl Webhook {
+    /// posts `body`, returning whether to try again later if it fails
+    async fn send(&self, body: &str) -> Result<(), (String, bool)> {
+        let headers = [("Content-Type", self.template.content_type())];
+        match post(&self.url, &headers, body.as_bytes(), SEND_TIMEOUT).await {
+            Ok(status) if status < 300 => Ok(()),
+            // request timeout and too many requests
+            Ok(status @ (408 | 429)) => Err((format!("got status {status}"), true)),
+            Ok(status) if status < 500 => Err((format!("got status {status}"), false)),
+            Ok(status) => Err((format!("got status {status}"), true)),
+            Err(e) => Err((e, true)),
+        }
+    }
+}
+
+/// posts a notification to a webhook for each outage, recovery and
+/// degradation, as a [`Sink`]
+///
+/// notifications are kept in order while the webhook can't be reached, like
+/// when the network itself is down, and retried with exponential backoff, or
+/// right away once the network comes back. they're rendered as soon as the
+/// event happens, so their timestamps are the original ones
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    events: mpsc::UnboundedSender<Event>,
+}
+
+impl Notifier {
+    /// starts notifying `webhook`, must be called from within a tokio runtime
+    ///
+    /// the returned task finishes once the notifier was dropped and what was
+    /// kept got one last chance at being sent, so it should be awaited before
+    /// exiting
+    pub fn start(webhook: Webhook) -> (Self, JoinHandle<()>) {
+        let (events, rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(run(webhook, rx));
+        (Self { events }, task)
+    }
+}
+
+impl Sink for Notifier {
+    fn handle(&mut self, event: &Event) {
+        if event.kind.is_transition() {
+            // the notifier's task only stops once this is dropped
+            let _ = self.events.send(event.clone());
+        }
+    }
+}
+
+/// notifications waiting to be posted, along with when to try again
+#[derive(Debug)]
+struct Queue {
+    bodies: VecDeque<String>,
+    backoff: Duration,
+    retry_at: Instant,
+    /// whether the last attempt failed and should be retried
+    failing: bool,
+}
+
+impl Queue {
+    fn new() -> Self {
+        Self { bodies: VecDeque::new(), backoff: MIN_BACKOFF, retry_at: Instant::now(), failing: false }
+    }
+
+    /// adds a notification, returning whether the oldest one had to be
+    /// dropped to make room for it
+    fn push(&mut self, body: String) -> bool {
+        self.bodies.push_back(body);
+        if self.bodies.len() > MAX_QUEUED {
+            self.bodies.pop_front();
+            return true;
+        }
+        false
+    }
+
+    /// tries again right away, without waiting for the backoff
+    fn retry_now(&mut self) {
+        self.retry_at = Instant::now();
+        self.backoff = MIN_BACKOFF;
+    }
+
+    /// when to try again, if the last attempt failed
+    fn next_retry(&self) -> Option<Instant> {
+        self.failing.then_some(self.retry_at)
+    }
+
+    /// posts the notifications in order until one can't be, which is kept
+    /// along with the ones after it
+    async fn deliver(&mut self, webhook: &Webhook) {
+        while let Some(body) = self.bodies.front() {
+            match webhook.send(body).await {
+                Ok(()) => {
+                    self.bodies.pop_front();
+                    if self.failing {
+                        info!("the {webhook} can be reached again, sending the notifications kept meanwhile");
+                        self.failing = false;
+                    }
+                    self.backoff = MIN_BACKOFF;
+                },
+                Err((e, false)) => {
+                    warn!("the {webhook} rejected a notification, dropping it: {e}");
+                    self.bodies.pop_front();
+                },
+                Err((e, true)) => {
+                    match self.failing {
+                        true => debug!("could not notify the {webhook}, retrying in {:?}: {e}", self.backoff),
+                        false => warn!("could not notify the {webhook}, keeping notifications until it can be reached: {e}"),
+                    }
+                    self.failing = true;
+                    self.retry_at = Instant::now() + self.backoff;
+                    self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                    return;
+                },
+            }
+        }
+        // nothing is left to retry, even if the last ones were rejected
+        self.failing = false;
+        self.backoff = MIN_BACKOFF;
+    }
+}
+
+async fn run(webhook: Webhook, mut events: mpsc::UnboundedReceiver<Event>) {
+    let mut queue = Queue::new();
+
+    loop {
+        let next_retry = queue.next_retry();
+        let closed = select! {
+            event = events.recv() => match event {
+                Some(event) => {
+                    // the webhook is likely reachable again
+                    if matches!(event.kind, NetworkEvent::NetworkUp { .. } | NetworkEvent::FamilyUp { .. }) {
+                        queue.retry_now();
+                    }
+                    if queue.push(webhook.template.render(&event)) {
+                        warn!("dropped the oldest notification for the {webhook}, as too many were kept");
+                    }
+                    false
+                },
+                None => true,
+            },
+            _ = tokio::time::sleep_until(next_retry.unwrap_or_else(Instant::now)), if next_retry.is_some() => false,
+        };
+
+        if queue.next_retry().is_none_or(|at| Instant::now() >= at) || closed {
+            queue.deliver(&webhook).await;
+        }
+        if closed {
+            if !queue.bodies.is_empty() {
+                warn!("could not send {} notifications to the {webhook} before stopping", queue.bodies.len());
+            }
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use std::net::SocketAddr;
+
+    use chrono::{TimeZone, Utc};
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+    use super::{Queue, Template, Webhook, MIN_BACKOFF};
+    use crate::event::{Event, Family, NetworkEvent, OutageCause};
+
+    fn family_up() -> Event {
+        Event {
+            timestamp: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+            kind: NetworkEvent::FamilyUp {
+                family: Family::V6,
+                downtime: Duration::from_secs(301),
+                cause: Some(OutageCause::Isp),
+            },
+        }
+    }
+
+    #[test]
+    fn renders_templates() {
+        assert_eq!(
+            Template::default().render(&family_up()),
+            r#"{"event":"family_up","message":"IPv6 is back online, and was down for 00:05:01 (outage at the ISP)","timestamp":"2023-11-14T22:13:20.000Z","family":"IPv6","cause":"ISP","downtime_seconds":301}"#
+        );
+
+        let template = Template::parse(r#"{"text": "{{ family }} \"{{cause}}\" {{downtime}} {{target}}"}"#).unwrap();
+        assert_eq!(template.content_type(), "application/json");
+        assert_eq!(template.render(&family_up()), r#"{"text": "IPv6 \"ISP\" 00:05:01 "}"#);
+
+        let template = Template::parse("{{event}} at {{timestamp}}: {{message}}").unwrap();
+        assert_eq!(template.content_type(), "text/plain; charset=utf-8");
+        assert_eq!(
+            template.render(&family_up()),
+            "family_up at 2023-11-14T22:13:20Z: IPv6 is back online, and was down for 00:05:01 (outage at the ISP)"
+        );
+
+        assert!(Template::parse("{{rtt}}").is_err());
+        assert!(Template::parse("{{message").is_err());
+        for preset in Template::PRESETS {
+            assert!(Template::preset(preset).is_some());
+        }
+    }
+
+    fn webhook(addr: SocketAddr) -> Webhook {
+        Webhook { url: format!("http://{addr}/hook").parse().unwrap(), template: Template::default() }
+    }
+
+    /// a webhook nothing listens on
+    fn unreachable() -> Webhook {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        webhook(listener.local_addr().unwrap())
+    }
+
+    /// answers `requests` requests with `status`, returning their bodies
+    async fn serve(listener: TcpListener, status: u16, requests: usize) -> Vec<String> {
+        let mut bodies = Vec::new();
+        for _ in 0..requests {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut chunk = [0; 4096];
+            loop {
+                let n = stream.read(&mut chunk).await.unwrap();
+                request.extend_from_slice(&chunk[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length: usize = head.lines()
+                        .find_map(|line| line.strip_prefix("Content-Length: "))
+                        .unwrap()
+                        .parse()
+                        .unwrap();
+                    if body.len() >= length {
+                        bodies.push(body.to_string());
+                        break;
+                    }
+                }
+                assert!(n > 0, "connection closed before the whole request was sent");
+            }
+            let response = format!("HTTP/1.1 {status} Whatever\r\nContent-Length: 0\r\n\r\n");
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+        bodies
+    }
+
+    #[tokio::test]
+    async fn keeps_notifications_until_delivered() {
+        let mut queue = Queue::new();
+        queue.push("first".to_string());
+        queue.push("second".to_string());
+
+        let down = unreachable();
+        queue.deliver(&down).await;
+        assert_eq!(queue.bodies.len(), 2);
+        assert!(queue.next_retry().is_some());
+        queue.deliver(&down).await;
+        assert_eq!(queue.backoff, MIN_BACKOFF * 4);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let up = webhook(listener.local_addr().unwrap());
+        let (_, bodies) = tokio::join!(queue.deliver(&up), serve(listener, 204, 2));
+        assert_eq!(bodies, ["first", "second"]);
+        assert!(queue.bodies.is_empty());
+        assert_eq!(queue.next_retry(), None);
+        assert_eq!(queue.backoff, MIN_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn stops_retrying_once_rejected() {
+        let mut queue = Queue::new();
+        queue.push("malformed".to_string());
+        queue.deliver(&unreachable()).await;
+        assert!(queue.next_retry().is_some());
+
+        // the webhook comes back but rejects what was kept
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let rejecting = webhook(listener.local_addr().unwrap());
+        let (_, bodies) = tokio::join!(queue.deliver(&rejecting), serve(listener, 400, 1));
+        assert_eq!(bodies, ["malformed"]);
+        assert!(queue.bodies.is_empty());
+        // nothing is left to retry, so there's no retry to wait for either
+        assert_eq!(queue.next_retry(), None);
+        assert_eq!(queue.backoff, MIN_BACKOFF);
+    }
+}